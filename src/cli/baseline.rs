@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The version of the baseline file format
+const BASELINE_VERSION: u32 = 1;
+
+/// The contents of the baseline file as stored on disk
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BaselineFile {
+    /// The version of the baseline file format
+    version: u32,
+    /// The fingerprint of the contents of each unformatted file, keyed by its path relative to the baseline file.
+    /// Sorted, so that the file is stable when committed to a repository.
+    files: BTreeMap<String, String>,
+}
+
+/// A record of files which were known to be unformatted, so that `--check` only fails on newly unformatted code.
+/// A file stays in the baseline until its contents change, at which point it must be formatted to pass.
+#[derive(Debug)]
+pub struct Baseline {
+    location: PathBuf,
+    /// The directory paths in the baseline are relative to
+    directory: PathBuf,
+    /// The directory relative paths are resolved against
+    current_directory: PathBuf,
+    entries: BTreeMap<String, String>,
+    /// The outcome of checking each file during this run, used when updating the baseline.
+    /// Unformatted files are given with their fingerprint, whilst formatted files have none.
+    checked: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Baseline {
+    /// Loads the baseline from the given location, resolved against the current directory.
+    /// A missing baseline is only allowed if we are about to create it.
+    pub fn load(location: &Path, current_directory: PathBuf, allow_missing: bool) -> Result<Self> {
+        let location = current_directory.join(location);
+        let directory = location
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| current_directory.clone());
+
+        let entries = match fs::read_to_string(&location) {
+            Ok(contents) => {
+                let baseline_file = serde_json::from_str::<BaselineFile>(&contents)
+                    .with_context(|| format!("could not parse baseline {}", location.display()))?;
+                debug!("baseline: loaded baseline from {}", location.display());
+                baseline_file.files
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound && allow_missing => {
+                debug!("baseline: no baseline found at {}", location.display());
+                BTreeMap::new()
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("could not read baseline {}", location.display()))
+            }
+        };
+
+        Ok(Self {
+            location,
+            directory,
+            current_directory,
+            entries,
+            checked: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Creates a fingerprint identifying the contents of a file.
+    /// The baseline is committed alongside the code, so this must be stable across platforms and Rust versions,
+    /// and ignores line endings which may be changed on checkout.
+    pub fn fingerprint(contents: &str) -> String {
+        // 64-bit FNV-1a
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in contents.bytes().filter(|&byte| byte != b'\r') {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        format!("{hash:016x}")
+    }
+
+    /// Returns the key of the file at the path, relative to the baseline using `/` separators
+    fn key(&self, path: &Path) -> String {
+        let path = self.current_directory.join(path);
+        match path.strip_prefix(&self.directory) {
+            Ok(relative) => relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    /// Whether the file at the path is in the baseline, and its contents are unchanged
+    pub fn contains(&self, path: &Path, contents: &str) -> bool {
+        self.entries.get(&self.key(path)) == Some(&Self::fingerprint(contents))
+    }
+
+    /// Records whether the file at the path was found to be formatted, for when the baseline is updated
+    pub fn record(&self, path: &Path, unformatted_contents: Option<&str>) {
+        self.checked
+            .lock()
+            .unwrap()
+            .insert(self.key(path), unformatted_contents.map(Self::fingerprint));
+    }
+
+    /// Writes the unformatted files found during this run back to disk.
+    /// Entries for files which were not checked are kept, as long as the file still exists.
+    pub fn save(&self) -> Result<()> {
+        let checked = self.checked.lock().unwrap();
+
+        let mut files = self
+            .entries
+            .iter()
+            .filter(|(key, _)| !checked.contains_key(*key) && self.directory.join(key).is_file())
+            .map(|(key, fingerprint)| (key.clone(), fingerprint.clone()))
+            .collect::<BTreeMap<_, _>>();
+        files.extend(checked.iter().filter_map(|(key, fingerprint)| {
+            fingerprint
+                .as_ref()
+                .map(|fingerprint| (key.clone(), fingerprint.clone()))
+        }));
+
+        let baseline_file = BaselineFile {
+            version: BASELINE_VERSION,
+            files,
+        };
+        let mut output = serde_json::to_string_pretty(&baseline_file)?;
+        output.push('\n');
+
+        fs::write(&self.location, output)
+            .with_context(|| format!("could not write baseline to {}", self.location.display()))?;
+        debug!("baseline: saved baseline to {}", self.location.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_ignores_line_endings() {
+        assert_eq!(
+            Baseline::fingerprint("local x = 1\n"),
+            Baseline::fingerprint("local x = 1\r\n")
+        );
+        assert_ne!(
+            Baseline::fingerprint("local x = 1\n"),
+            Baseline::fingerprint("local x = 2\n")
+        );
+        // The fingerprint is written to disk, so must never change
+        assert_eq!(Baseline::fingerprint(""), "cbf29ce484222325");
+    }
+
+    #[test]
+    fn test_baseline_roundtrip() {
+        let cwd = assert_fs::TempDir::new().unwrap();
+        let location = Path::new("nested/baseline.json");
+        fs::create_dir_all(cwd.path().join("nested/src")).unwrap();
+        fs::write(cwd.path().join("nested/src/kept.lua"), "local  x = 1").unwrap();
+
+        assert!(Baseline::load(location, cwd.to_path_buf(), false).is_err());
+
+        let baseline = Baseline::load(location, cwd.to_path_buf(), true).unwrap();
+        baseline.record(Path::new("./nested/src/foo.lua"), Some("local  y = 2"));
+        baseline.record(Path::new("nested/src/kept.lua"), Some("local  x = 1"));
+        baseline.record(Path::new("nested/src/formatted.lua"), None);
+        baseline.save().unwrap();
+
+        let baseline = Baseline::load(location, cwd.to_path_buf(), false).unwrap();
+        assert_eq!(
+            baseline.entries.keys().collect::<Vec<_>>(),
+            ["src/foo.lua", "src/kept.lua"]
+        );
+        assert!(baseline.contains(Path::new("nested/src/foo.lua"), "local  y = 2"));
+        assert!(!baseline.contains(Path::new("nested/src/foo.lua"), "local   y = 2"));
+        assert!(!baseline.contains(Path::new("nested/src/formatted.lua"), "local y = 2"));
+
+        // `kept.lua` is now formatted, and `foo.lua` is dropped as it does not exist
+        baseline.record(Path::new("nested/src/kept.lua"), None);
+        baseline.save().unwrap();
+        let baseline = Baseline::load(location, cwd.to_path_buf(), false).unwrap();
+        assert!(baseline.entries.is_empty());
+
+        cwd.close().unwrap();
+    }
+}
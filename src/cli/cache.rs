@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use stylua_lib::Config;
+
+/// The default location of the cache file, relative to the current working directory
+pub const DEFAULT_CACHE_LOCATION: &str = ".stylua-cache";
+
+/// The contents of the cache file as stored on disk
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    /// The version of StyLua which wrote the cache
+    version: String,
+    /// The fingerprint of each file which was known to be formatted, keyed by its absolute path
+    entries: HashMap<PathBuf, u64>,
+}
+
+/// The syntax features this binary was compiled with, which change how code is parsed
+const SYNTAX_FEATURES: &[(&str, bool)] = &[
+    ("lua52", cfg!(feature = "lua52")),
+    ("lua53", cfg!(feature = "lua53")),
+    ("lua54", cfg!(feature = "lua54")),
+    ("luau", cfg!(feature = "luau")),
+    ("luajit", cfg!(feature = "luajit")),
+    ("cfxlua", cfg!(feature = "cfxlua")),
+];
+
+/// A 64-bit FNV-1a hasher.
+/// The fingerprints are persisted to disk, so unlike `DefaultHasher` the hash must not change across Rust releases.
+struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writes a stable serialisation of the configuration to the hasher, with every field given by name.
+/// Enums are written as their discriminant, which is fixed for a given StyLua version and set of syntax features,
+/// both of which are also part of the fingerprint.
+fn hash_config(config: &Config, hasher: &mut Fnv1aHasher) {
+    #[allow(deprecated)]
+    let fields = [
+        ("syntax", config.syntax as u64),
+        ("column_width", config.column_width as u64),
+        ("line_endings", config.line_endings as u64),
+        ("indent_type", config.indent_type as u64),
+        ("indent_width", config.indent_width as u64),
+        ("quote_style", config.quote_style as u64),
+        ("no_call_parentheses", config.no_call_parentheses as u64),
+        ("call_parentheses", config.call_parentheses as u64),
+        (
+            "collapse_simple_statement",
+            config.collapse_simple_statement as u64,
+        ),
+        ("block_newline_gaps", config.block_newline_gaps as u64),
+        ("sort_requires", config.sort_requires.enabled as u64),
+        (
+            "space_after_function_names",
+            config.space_after_function_names as u64,
+        ),
+    ];
+
+    for (name, value) in fields {
+        hasher.write(name.as_bytes());
+        hasher.write(b"=");
+        hasher.write(&value.to_le_bytes());
+    }
+}
+
+/// A cache of files which are already known to be formatted, persisted across runs.
+/// Files are identified by a fingerprint of their contents, the configuration used to format them, and the StyLua version,
+/// so an entry is invalidated whenever any of these change.
+#[derive(Debug)]
+pub struct FormatCache {
+    location: PathBuf,
+    /// The directory relative paths are resolved against, so that entries are keyed by absolute path
+    current_directory: PathBuf,
+    entries: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl FormatCache {
+    /// Loads the cache from the given location.
+    /// If the cache does not exist, is unreadable, or was written by a different version of StyLua, we start with an empty cache.
+    pub fn load(location: PathBuf, current_directory: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&location) {
+            Ok(contents) => match serde_json::from_str::<CacheFile>(&contents) {
+                Ok(cache_file) if cache_file.version == env!("CARGO_PKG_VERSION") => {
+                    debug!("cache: loaded cache from {}", location.display());
+                    cache_file.entries
+                }
+                Ok(_) => {
+                    debug!("cache: discarding cache written by a different version of stylua");
+                    HashMap::new()
+                }
+                Err(error) => {
+                    debug!("cache: discarding unreadable cache: {}", error);
+                    HashMap::new()
+                }
+            },
+            Err(_) => {
+                debug!("cache: no cache found at {}", location.display());
+                HashMap::new()
+            }
+        };
+
+        Self {
+            location,
+            current_directory,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Creates a fingerprint identifying the contents of a file formatted with the given configuration,
+    /// by the current version of StyLua compiled with the current syntax features
+    pub fn fingerprint(contents: &str, config: &Config) -> u64 {
+        let mut hasher = Fnv1aHasher::default();
+        hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.write(b"\0");
+        for (feature, enabled) in SYNTAX_FEATURES {
+            if *enabled {
+                hasher.write(feature.as_bytes());
+                hasher.write(b",");
+            }
+        }
+        hasher.write(b"\0");
+        hash_config(config, &mut hasher);
+        hasher.write(b"\0");
+        hasher.write(contents.as_bytes());
+        hasher.finish()
+    }
+
+    /// Whether the file at the path was previously found to be formatted with the given fingerprint
+    pub fn is_formatted(&self, path: &Path, fingerprint: u64) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&self.current_directory.join(path))
+            == Some(&fingerprint)
+    }
+
+    /// Records that the file at the path is formatted with the given fingerprint
+    pub fn mark_formatted(&self, path: &Path, fingerprint: u64) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(self.current_directory.join(path), fingerprint);
+    }
+
+    /// Removes any record of the file at the path, as it is not formatted
+    pub fn mark_unformatted(&self, path: &Path) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&self.current_directory.join(path));
+    }
+
+    /// Writes the cache back to disk
+    pub fn save(&self) -> Result<()> {
+        let cache_file = CacheFile {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            entries: self.entries.lock().unwrap().clone(),
+        };
+
+        if let Some(parent) = self.location.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("could not create cache directory {}", parent.display())
+                })?;
+            }
+        }
+
+        fs::write(&self.location, serde_json::to_vec(&cache_file)?)
+            .with_context(|| format!("could not write cache to {}", self.location.display()))?;
+        debug!("cache: saved cache to {}", self.location.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stylua_lib::QuoteStyle;
+
+    #[test]
+    fn test_fingerprint_depends_on_contents_and_config() {
+        let config = Config::default();
+        let other_config = Config {
+            quote_style: QuoteStyle::ForceSingle,
+            ..Config::default()
+        };
+
+        let fingerprint = FormatCache::fingerprint("local x = 1\n", &config);
+        assert_eq!(
+            fingerprint,
+            FormatCache::fingerprint("local x = 1\n", &config)
+        );
+        assert_ne!(
+            fingerprint,
+            FormatCache::fingerprint("local x = 2\n", &config)
+        );
+        assert_ne!(
+            fingerprint,
+            FormatCache::fingerprint("local x = 1\n", &other_config)
+        );
+    }
+
+    #[test]
+    fn test_fnv1a_hasher_is_stable() {
+        // The fingerprints are written to disk, so the hash must never change
+        let mut hasher = Fnv1aHasher::default();
+        assert_eq!(hasher.finish(), 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let cwd = assert_fs::TempDir::new().unwrap();
+        let location = cwd.path().join("nested").join(DEFAULT_CACHE_LOCATION);
+        let path = cwd.path().join("foo.lua");
+
+        let cache = FormatCache::load(location.clone(), cwd.to_path_buf());
+        assert!(!cache.is_formatted(&path, 1));
+        cache.mark_formatted(&path, 1);
+        cache.save().unwrap();
+
+        let cache = FormatCache::load(location.clone(), cwd.to_path_buf());
+        assert!(cache.is_formatted(Path::new("foo.lua"), 1));
+        assert!(cache.is_formatted(&path, 1));
+        assert!(!cache.is_formatted(&path, 2));
+        cache.mark_unformatted(&path);
+        assert!(!cache.is_formatted(&path, 1));
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_cache_discarded_for_different_version() {
+        let cwd = assert_fs::TempDir::new().unwrap();
+        let location = cwd.path().join(DEFAULT_CACHE_LOCATION);
+        let path = cwd.path().join("foo.lua");
+
+        let mut entries = HashMap::new();
+        entries.insert(path.clone(), 1);
+        let cache_file = CacheFile {
+            version: String::from("0.0.0"),
+            entries,
+        };
+        fs::write(&location, serde_json::to_vec(&cache_file).unwrap()).unwrap();
+
+        let cache = FormatCache::load(location, cwd.to_path_buf());
+        assert!(!cache.is_formatted(&path, 1));
+
+        cwd.close().unwrap();
+    }
+}
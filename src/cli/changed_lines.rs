@@ -0,0 +1,437 @@
+use anyhow::{bail, Context, Result};
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use stylua_lib::Range;
+
+/// Which revision to compare the working tree against when looking for changed lines
+#[derive(Debug, Clone)]
+pub enum ChangedSince {
+    /// Compare the working tree (including staged changes) against the given revision
+    Revision(String),
+    /// Compare the index against `HEAD`, i.e. only consider staged changes.
+    /// Line numbers are given for the file in the index, so are mapped through any unstaged changes when
+    /// formatting the working tree.
+    Staged,
+}
+
+/// A hunk of a unified diff, given as the 1-indexed start line and number of lines on each side.
+/// When no lines are on a side, the start line is the line before the hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Hunk {
+    old_start: usize,
+    old_count: usize,
+    new_start: usize,
+    new_count: usize,
+}
+
+/// The lines changed in each file of a git repository, used to only format code which has been modified
+#[derive(Debug, Default)]
+pub struct ChangedLines {
+    /// The root of the git repository
+    repository_root: PathBuf,
+    /// The changed lines of each file, keyed by absolute path.
+    /// Lines are given as 1-indexed inclusive `(start, end)` pairs, sorted in ascending order.
+    /// When only considering staged changes, these are lines of the file in the index.
+    files: HashMap<PathBuf, Vec<(usize, usize)>>,
+    /// Files which are not tracked by git, so every line is considered changed
+    untracked_files: HashSet<PathBuf>,
+    /// When only considering staged changes, the unstaged changes to each file, used to find where the staged
+    /// lines are in the working tree. Hunks are sorted in ascending order.
+    unstaged_hunks: HashMap<PathBuf, Vec<Hunk>>,
+    /// Whether only staged changes are considered
+    staged: bool,
+}
+
+fn run_git(directory: &Path, args: &[&str]) -> Result<String> {
+    debug!("changed lines: running git {}", args.join(" "));
+    let output = Command::new("git")
+        .current_dir(directory)
+        // Otherwise paths containing non-ASCII characters are escaped in diffs
+        .args(["-c", "core.quotePath=false"])
+        .args(args)
+        .output()
+        .context("could not run git")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).context("git output was not valid utf-8")
+}
+
+/// Parses a range within a hunk header (e.g. `13,2`) into its start line and number of lines
+fn parse_hunk_range(range: &str) -> Option<(usize, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let start = parts.next()?.parse::<usize>().ok()?;
+    let count = match parts.next() {
+        Some(count) => count.parse::<usize>().ok()?,
+        None => 1,
+    };
+    Some((start, count))
+}
+
+/// Parses the hunk header of a unified diff (e.g. `@@ -12,0 +13,2 @@`)
+fn parse_hunk(line: &str) -> Option<Hunk> {
+    let mut parts = line.split(' ');
+    let (old_start, old_count) = parse_hunk_range(parts.nth(1)?.strip_prefix('-')?)?;
+    let (new_start, new_count) = parse_hunk_range(parts.next()?.strip_prefix('+')?)?;
+    Some(Hunk {
+        old_start,
+        old_count,
+        new_start,
+        new_count,
+    })
+}
+
+/// Unquotes a path given in a diff header. Git surrounds paths containing special characters (such as quotes,
+/// backslashes or control characters) with quotes, escaping those characters as in C.
+fn unquote_path(path: &str) -> String {
+    let Some(quoted) = path
+        .strip_prefix('"')
+        .and_then(|path| path.strip_suffix('"'))
+    else {
+        return path.to_string();
+    };
+
+    let mut bytes = Vec::with_capacity(quoted.len());
+    let mut chars = quoted.bytes().peekable();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+
+        match chars.next() {
+            Some(b'a') => bytes.push(0x07),
+            Some(b'b') => bytes.push(0x08),
+            Some(b't') => bytes.push(b'\t'),
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b'v') => bytes.push(0x0b),
+            Some(b'f') => bytes.push(0x0c),
+            Some(b'r') => bytes.push(b'\r'),
+            // Any other byte is escaped as three octal digits
+            Some(digit @ b'0'..=b'7') => {
+                let mut value = u32::from(digit - b'0');
+                for _ in 0..2 {
+                    if let Some(digit @ b'0'..=b'7') = chars.peek().copied() {
+                        value = value * 8 + u32::from(digit - b'0');
+                        chars.next();
+                    }
+                }
+                bytes.push(value as u8);
+            }
+            Some(escaped) => bytes.push(escaped),
+            None => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Parses the output of `git diff --unified=0` into the hunks of each file, relative to the repository root
+fn parse_diff_hunks(diff: &str) -> HashMap<PathBuf, Vec<Hunk>> {
+    let mut files: HashMap<PathBuf, Vec<Hunk>> = HashMap::new();
+    let mut current_file = None;
+
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            let path = unquote_path(path.trim_end_matches('\t'));
+            current_file = path.strip_prefix("b/").map(PathBuf::from);
+        } else if line.starts_with("@@ ") {
+            if let (Some(file), Some(hunk)) = (&current_file, parse_hunk(line)) {
+                files.entry(file.clone()).or_default().push(hunk);
+            }
+        }
+    }
+
+    for hunks in files.values_mut() {
+        hunks.sort_unstable();
+    }
+
+    files
+}
+
+/// Parses the output of `git diff --unified=0` into the changed lines of each file, relative to the repository root
+fn parse_diff(diff: &str) -> HashMap<PathBuf, Vec<(usize, usize)>> {
+    parse_diff_hunks(diff)
+        .into_iter()
+        .filter_map(|(file, hunks)| {
+            let lines = hunks
+                .into_iter()
+                .filter(|hunk| hunk.new_count > 0)
+                .map(|hunk| (hunk.new_start, hunk.new_start + hunk.new_count - 1))
+                .collect::<Vec<_>>();
+            (!lines.is_empty()).then_some((file, lines))
+        })
+        .collect()
+}
+
+/// Maps a line of the old side of a diff to the new side, given the hunks of the diff in ascending order.
+/// Lines changed by a hunk are mapped to the start of the new lines, or their end if `is_end` is set.
+/// If the hunk removed the lines entirely, the start is mapped after the end, so the range becomes empty.
+fn map_line(line: usize, hunks: &[Hunk], is_end: bool) -> usize {
+    let mut delta = 0isize;
+
+    for hunk in hunks {
+        // The first line of the hunk, and the first line after it, on each side
+        let (old_first, old_after) = match hunk.old_count {
+            0 => (hunk.old_start + 1, hunk.old_start + 1),
+            count => (hunk.old_start, hunk.old_start + count),
+        };
+
+        if line < old_first {
+            break;
+        } else if line >= old_after {
+            delta += hunk.new_count as isize - hunk.old_count as isize;
+        } else {
+            return match (hunk.new_count, is_end) {
+                (0, false) => hunk.new_start + 1,
+                (0, true) => hunk.new_start,
+                (_, false) => hunk.new_start,
+                (count, true) => hunk.new_start + count - 1,
+            };
+        }
+    }
+
+    (line as isize + delta) as usize
+}
+
+impl ChangedLines {
+    /// Loads the changed lines of every file in the git repository containing `directory`
+    pub fn load(directory: &Path, changed_since: &ChangedSince) -> Result<Self> {
+        let repository_root = PathBuf::from(
+            run_git(directory, &["rev-parse", "--show-toplevel"])
+                .context("could not find git repository")?
+                .trim(),
+        );
+
+        let diff_args = [
+            "diff",
+            "--unified=0",
+            "--no-color",
+            "--no-ext-diff",
+            "--src-prefix=a/",
+            "--dst-prefix=b/",
+        ];
+        let diff = match changed_since {
+            ChangedSince::Revision(revision) => run_git(
+                &repository_root,
+                &[&diff_args[..], &[revision.as_str(), "--"]].concat(),
+            )?,
+            ChangedSince::Staged => {
+                run_git(&repository_root, &[&diff_args[..], &["--cached"]].concat())?
+            }
+        };
+
+        let files = parse_diff(&diff)
+            .into_iter()
+            .map(|(path, lines)| (repository_root.join(path), lines))
+            .collect();
+
+        // Untracked files are completely new, but do not show up in a diff.
+        // They can never be staged, so are only included when comparing against a revision.
+        let untracked_files = match changed_since {
+            ChangedSince::Revision(_) => run_git(
+                &repository_root,
+                &["ls-files", "--others", "--exclude-standard", "-z"],
+            )?
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(|path| repository_root.join(path))
+            .collect(),
+            ChangedSince::Staged => HashSet::new(),
+        };
+
+        // Staged lines are numbered as in the index, so we need the unstaged changes to find them in the working tree
+        let unstaged_hunks = match changed_since {
+            ChangedSince::Revision(_) => HashMap::new(),
+            ChangedSince::Staged => parse_diff_hunks(&run_git(&repository_root, &diff_args)?)
+                .into_iter()
+                .map(|(path, hunks)| (repository_root.join(path), hunks))
+                .collect(),
+        };
+
+        Ok(Self {
+            repository_root,
+            files,
+            untracked_files,
+            unstaged_hunks,
+            staged: matches!(changed_since, ChangedSince::Staged),
+        })
+    }
+
+    /// When only considering staged changes, returns the contents of the file at the path in the index,
+    /// which may differ from the working tree. Returns `None` if the file has no staged changes.
+    pub fn staged_contents(&self, path: &Path) -> Result<Option<String>> {
+        if !self.staged {
+            return Ok(None);
+        }
+
+        let path = match path.canonicalize() {
+            Ok(path) if self.files.contains_key(&path) => path,
+            _ => return Ok(None),
+        };
+        let relative_path = path
+            .strip_prefix(&self.repository_root)
+            .unwrap_or(&path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        run_git(
+            &self.repository_root,
+            &["cat-file", "blob", &format!(":{relative_path}")],
+        )
+        .map(Some)
+    }
+
+    /// Returns the byte ranges of the changed lines within the file at the path, given its contents in the working tree.
+    /// Every range covers complete lines, and the ranges are sorted in ascending order.
+    /// No ranges means the whole file has changed.
+    /// Returns `None` if no lines in the file have changed.
+    pub fn ranges(&self, path: &Path, contents: &str) -> Option<Vec<Range>> {
+        let path = path.canonicalize().ok()?;
+
+        if self.untracked_files.contains(&path) {
+            return Some(Vec::new());
+        }
+
+        let lines = self.files.get(&path)?;
+        let lines = match self.unstaged_hunks.get(&path) {
+            Some(hunks) => lines
+                .iter()
+                .map(|&(start, end)| (map_line(start, hunks, false), map_line(end, hunks, true)))
+                .filter(|(start, end)| start <= end)
+                .collect(),
+            None => lines.clone(),
+        };
+
+        if lines.is_empty() {
+            return None;
+        }
+
+        Some(Self::line_ranges(&lines, contents))
+    }
+
+    /// Returns the byte ranges of the changed lines within the file at the path, given its contents from
+    /// [`ChangedLines::staged_contents`].
+    /// Returns `None` if no lines in the file have changed.
+    pub fn staged_ranges(&self, path: &Path, contents: &str) -> Option<Vec<Range>> {
+        let lines = self.files.get(&path.canonicalize().ok()?)?;
+        Some(Self::line_ranges(lines, contents))
+    }
+
+    /// Converts 1-indexed inclusive `(start, end)` lines into byte ranges covering those lines within the contents
+    fn line_ranges(lines: &[(usize, usize)], contents: &str) -> Vec<Range> {
+        // Byte offset of the start of each line, with a final entry for the end of the file
+        let line_starts = std::iter::once(0)
+            .chain(contents.match_indices('\n').map(|(index, _)| index + 1))
+            .chain(std::iter::once(contents.len()))
+            .collect::<Vec<_>>();
+        let line_start = |line: usize| line_starts[(line - 1).min(line_starts.len() - 1)];
+
+        lines
+            .iter()
+            .map(|&(start, end)| {
+                Range::from_values(Some(line_start(start)), Some(line_start(end + 1)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(old_start: usize, old_count: usize, new_start: usize, new_count: usize) -> Hunk {
+        Hunk {
+            old_start,
+            old_count,
+            new_start,
+            new_count,
+        }
+    }
+
+    #[test]
+    fn test_parse_hunk() {
+        assert_eq!(parse_hunk("@@ -12,0 +13,2 @@"), Some(hunk(12, 0, 13, 2)));
+        assert_eq!(
+            parse_hunk("@@ -1 +1 @@ local x = 1"),
+            Some(hunk(1, 1, 1, 1))
+        );
+        assert_eq!(parse_hunk("@@ -5,2 +4,0 @@"), Some(hunk(5, 2, 4, 0)));
+    }
+
+    #[test]
+    fn test_map_line() {
+        // Two lines inserted after line 1, and line 5 replaced by three lines
+        let hunks = [hunk(1, 0, 2, 2), hunk(5, 1, 7, 3)];
+        assert_eq!(map_line(1, &hunks, false), 1);
+        assert_eq!(map_line(2, &hunks, false), 4);
+        assert_eq!(map_line(5, &hunks, false), 7);
+        assert_eq!(map_line(5, &hunks, true), 9);
+        assert_eq!(map_line(6, &hunks, false), 10);
+
+        // Line 3 removed
+        let hunks = [hunk(3, 1, 2, 0)];
+        assert_eq!(map_line(2, &hunks, false), 2);
+        assert!(map_line(3, &hunks, false) > map_line(3, &hunks, true));
+        assert_eq!(map_line(4, &hunks, false), 3);
+    }
+
+    #[test]
+    fn test_parse_diff() {
+        let diff = "\
+diff --git a/foo.lua b/foo.lua
+index 1234567..89abcde 100644
+--- a/foo.lua
++++ b/foo.lua
+@@ -3,0 +4,2 @@ local x = 1
++local   y = 2
++local   z = 3
+@@ -1 +1 @@
+-local a=1
++local  a=1
+diff --git a/removed.lua b/removed.lua
+deleted file mode 100644
+--- a/removed.lua
++++ /dev/null
+@@ -1 +0,0 @@
+-local x = 1
+";
+
+        let files = parse_diff(diff);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[Path::new("foo.lua")], vec![(1, 1), (4, 5)]);
+    }
+
+    #[test]
+    fn test_parse_diff_quoted_paths() {
+        let diff = r#"
+diff --git "a/caf\303\251.lua" "b/caf\303\251.lua"
+--- "a/caf\303\251.lua"
++++ "b/caf\303\251.lua"
+@@ -1 +1 @@
+-local a=1
++local  a=1
+diff --git "a/tab\there.lua" "b/tab\there.lua"
+--- "a/tab\there.lua"
++++ "b/tab\there.lua"
+@@ -2 +2 @@
+-local b=1
++local  b=1
+"#;
+
+        let files = parse_diff(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[Path::new("café.lua")], vec![(1, 1)]);
+        assert_eq!(files[Path::new("tab\there.lua")], vec![(2, 2)]);
+    }
+}
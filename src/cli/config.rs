@@ -1,10 +1,13 @@
 use crate::opt::Opt;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use log::*;
-use std::collections::HashMap;
+#[cfg(feature = "lsp")]
+use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use stylua_lib::Config;
 use stylua_lib::SortRequiresConfig;
 
@@ -13,46 +16,349 @@ use stylua_lib::editorconfig;
 
 static CONFIG_FILE_NAME: [&str; 2] = ["stylua.toml", ".stylua.toml"];
 
-fn read_config_file(path: &Path) -> Result<Config> {
-    let contents = fs::read_to_string(path).context("Failed to read config file")?;
-    let config = toml::from_str(&contents).context("Config file not in correct format")?;
+/// Where each configuration option was set, keyed by the option name.
+/// Nested options are separated with a `.`, e.g. `sort_requires.enabled`.
+/// Any option which is not present was left as its default value.
+pub type ConfigSources = BTreeMap<String, String>;
 
-    Ok(config)
+/// A set of configuration values which only apply to files matching the provided globs
+#[derive(Clone, Debug)]
+struct ConfigOverride {
+    /// The globs to match against, relative to the directory containing the configuration file
+    files: GlobSet,
+    /// The configuration values to apply on top of the base configuration
+    values: toml::Table,
 }
 
-fn read_and_apply_overrides(path: &Path, opt: &Opt) -> Result<Config> {
-    read_config_file(path).map(|config| load_overrides(config, opt))
+/// A parsed configuration file, which may contain glob-scoped overrides in an `[[overrides]]` array
+#[derive(Clone, Debug)]
+struct ConfigFile {
+    /// The path to the configuration file
+    path: PathBuf,
+    /// Whether this configuration file is the root, and should not inherit from any parent configuration
+    root: bool,
+    /// An explicit configuration file to inherit from, instead of searching parent directories
+    extends: Option<PathBuf>,
+    /// The top-level configuration values
+    values: toml::Table,
+    /// The overrides declared in the configuration file, in the order they should be applied
+    overrides: Vec<ConfigOverride>,
+}
+
+impl ConfigFile {
+    /// The directory containing the configuration file, which override globs are relative to
+    fn directory(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// Merges the values of this configuration file into `values`.
+    /// Every override with a glob matching the path is then merged on top, in order.
+    /// If no path is provided (e.g. formatting from stdin), then no overrides are applied.
+    fn apply(&self, values: &mut toml::Table, path: Option<&Path>, sources: &mut ConfigSources) {
+        merge_tables(values, &self.values);
+        record_sources(sources, "", &self.values, &self.path.display().to_string());
+
+        if let Some(path) = path {
+            let relative_path = path.strip_prefix(self.directory()).unwrap_or(path);
+            for (index, config_override) in self.overrides.iter().enumerate() {
+                if config_override.files.is_match(relative_path) {
+                    merge_tables(values, &config_override.values);
+                    record_sources(
+                        sources,
+                        "",
+                        &config_override.values,
+                        &format!("{} (overrides[{}])", self.path.display(), index),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The configuration files that apply to a directory, ordered from lowest to highest precedence.
+/// A configuration file inherits from the nearest configuration in a parent directory (or the one it `extends`),
+/// until a configuration file with `root = true` is reached.
+#[derive(Clone, Debug, Default)]
+struct ConfigStack {
+    files: Vec<ConfigFile>,
+}
+
+impl ConfigStack {
+    /// Resolves the configuration to use for the provided file path, by merging every configuration file in order
+    fn resolve(&self, path: Option<&Path>, sources: &mut ConfigSources) -> Result<Config> {
+        let mut values = toml::Table::new();
+        for config_file in &self.files {
+            config_file.apply(&mut values, path, sources);
+        }
+
+        toml::Value::Table(values)
+            .try_into()
+            .context("Config file not in correct format")
+    }
+
+    /// Whether the configuration file at the path is part of this stack
+    #[cfg(feature = "lsp")]
+    fn contains(&self, path: &Path) -> bool {
+        self.files
+            .iter()
+            .any(|config_file| config_file.path == path)
+    }
+}
+
+/// Merges the values of `other` into `base`. Nested tables (e.g. `sort_requires`) are merged key by key
+fn merge_tables(base: &mut toml::Table, other: &toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(other_table)) => {
+                merge_tables(base_table, other_table)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Records `source` as the origin of every value in `values`
+fn record_sources(sources: &mut ConfigSources, prefix: &str, values: &toml::Table, source: &str) {
+    for (key, value) in values {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        match value {
+            toml::Value::Table(table) => record_sources(sources, &key, table, source),
+            _ => {
+                sources.insert(key, source.to_string());
+            }
+        }
+    }
+}
+
+/// Flattens a configuration into a map of option names to values.
+/// Nested options are separated with a `.`, in the same way as [`ConfigSources`]
+#[cfg(feature = "serialize")]
+pub fn flatten_config(config: &Config) -> Result<BTreeMap<String, toml::Value>> {
+    fn flatten(flattened: &mut BTreeMap<String, toml::Value>, prefix: &str, values: toml::Table) {
+        for (key, value) in values {
+            let key = if prefix.is_empty() {
+                key
+            } else {
+                format!("{prefix}.{key}")
+            };
+
+            match value {
+                toml::Value::Table(table) => flatten(flattened, &key, table),
+                value => {
+                    flattened.insert(key, value);
+                }
+            }
+        }
+    }
+
+    let mut flattened = BTreeMap::new();
+    flatten(&mut flattened, "", toml::Table::try_from(config)?);
+    Ok(flattened)
+}
+
+/// Builds a glob set from the `files` list of an override.
+/// Globs without a path separator are matched against files in any directory, similar to `.gitignore`
+fn build_override_globs(files: &toml::Value) -> Result<GlobSet> {
+    let files = match files {
+        toml::Value::Array(files) => files,
+        _ => bail!("`files` in an override must be an array of globs"),
+    };
+
+    let mut builder = GlobSetBuilder::new();
+    for file in files {
+        let pattern = file
+            .as_str()
+            .context("`files` in an override must be an array of globs")?;
+        let pattern = if pattern.contains('/') {
+            pattern.trim_start_matches('/').to_string()
+        } else {
+            format!("**/{pattern}")
+        };
+
+        builder.add(
+            GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .with_context(|| format!("invalid glob `{pattern}` in override"))?,
+        );
+    }
+
+    builder.build().context("failed to build override globs")
+}
+
+fn parse_config_file(contents: &str, path: &Path) -> Result<ConfigFile> {
+    let mut values: toml::Table = toml::from_str(contents)?;
+
+    let root = match values.remove("root") {
+        Some(toml::Value::Boolean(root)) => root,
+        Some(_) => bail!("`root` must be a boolean"),
+        None => false,
+    };
+
+    let extends = match values.remove("extends") {
+        Some(toml::Value::String(extends)) => Some(normalize_path(
+            &path.parent().unwrap_or_else(|| Path::new("")).join(extends),
+        )),
+        Some(_) => bail!("`extends` must be a path to a configuration file"),
+        None => None,
+    };
+
+    let overrides = match values.remove("overrides") {
+        Some(toml::Value::Array(overrides)) => overrides
+            .into_iter()
+            .map(|config_override| {
+                let mut values = match config_override {
+                    toml::Value::Table(values) => values,
+                    _ => bail!("`overrides` must be an array of tables"),
+                };
+                let files = values
+                    .remove("files")
+                    .context("an override is missing the `files` key")?;
+
+                Ok(ConfigOverride {
+                    files: build_override_globs(&files)?,
+                    values,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(_) => bail!("`overrides` must be an array of tables"),
+        None => Vec::new(),
+    };
+
+    let config_file = ConfigFile {
+        path: path.to_path_buf(),
+        root,
+        extends,
+        values,
+        overrides,
+    };
+
+    // Validate the base configuration, as well as every override applied on top of it, upfront
+    toml::Value::Table(config_file.values.clone()).try_into::<Config>()?;
+    for config_override in &config_file.overrides {
+        let mut values = config_file.values.clone();
+        merge_tables(&mut values, &config_override.values);
+        toml::Value::Table(values).try_into::<Config>()?;
+    }
+
+    Ok(config_file)
+}
+
+/// Resolves any `.` and `..` components without touching the filesystem,
+/// so that a file which is extended is always referred to by the same path
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Whether the path points to a configuration toml file (i.e. `stylua.toml` or `.stylua.toml`)
+#[cfg(feature = "lsp")]
+pub fn is_config_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .is_some_and(|file_name| CONFIG_FILE_NAME.contains(&file_name))
 }
 
 pub struct ConfigResolver<'a> {
-    config_cache: HashMap<PathBuf, Option<Config>>,
-    forced_configuration: Option<Config>,
+    config_cache: HashMap<PathBuf, Option<ConfigStack>>,
+    forced_configuration: Option<ConfigStack>,
+    /// The contents of configuration files which have been modified but not yet saved (e.g. open in an editor).
+    /// These take precedence over the contents on disk.
+    unsaved_config_files: HashMap<PathBuf, String>,
     current_directory: PathBuf,
     default_configuration: Config,
+    search_parent_directories: bool,
     opt: &'a Opt,
 }
 
 impl ConfigResolver<'_> {
     pub fn new(opt: &Opt) -> Result<ConfigResolver<'_>> {
-        let forced_configuration = opt
-            .config_path
-            .as_ref()
-            .map(|config_path| {
-                debug!(
-                    "config: explicit config path provided at {}",
-                    config_path.display()
-                );
-                read_and_apply_overrides(config_path, opt)
-            })
-            .transpose()?;
-
-        Ok(ConfigResolver {
+        let mut config_resolver = ConfigResolver {
             config_cache: HashMap::new(),
-            forced_configuration,
+            forced_configuration: None,
+            unsaved_config_files: HashMap::new(),
             current_directory: env::current_dir().context("Could not find current directory")?,
             default_configuration: load_overrides(Config::default(), opt),
+            search_parent_directories: opt.search_parent_directories,
             opt,
-        })
+        };
+
+        if let Some(config_path) = &opt.config_path {
+            config_resolver.load_forced_configuration(config_path)?;
+        }
+
+        Ok(config_resolver)
+    }
+
+    /// Loads the explicitly provided configuration file, which is used for every file instead of searching for configuration
+    fn load_forced_configuration(&mut self, config_path: &Path) -> Result<()> {
+        debug!(
+            "config: explicit config path provided at {}",
+            config_path.display()
+        );
+        let config_file = self.read_config_file(&self.current_directory.join(config_path))?;
+        self.forced_configuration =
+            Some(self.build_config_stack(config_file, None, false, &mut Vec::new())?);
+        Ok(())
+    }
+
+    /// Overrides `--config-path` with a different configuration file. Passing `None` reverts to `--config-path`.
+    #[cfg(feature = "lsp")]
+    pub fn set_config_path(&mut self, config_path: Option<&Path>) -> Result<()> {
+        self.forced_configuration = None;
+        match config_path.or(self.opt.config_path.as_deref()) {
+            Some(config_path) => self.load_forced_configuration(config_path),
+            None => Ok(()),
+        }
+    }
+
+    /// Overrides `--search-parent-directories`. Passing `None` reverts to `--search-parent-directories`.
+    #[cfg(feature = "lsp")]
+    pub fn set_search_parent_directories(&mut self, search_parent_directories: Option<bool>) {
+        let search_parent_directories =
+            search_parent_directories.unwrap_or(self.opt.search_parent_directories);
+        if search_parent_directories != self.search_parent_directories {
+            // Where the search for configuration stops has changed, so anything cached may be wrong
+            self.config_cache.clear();
+            self.search_parent_directories = search_parent_directories;
+        }
+    }
+
+    /// Sets the configuration used when no configuration file is found, which any command line overrides still apply to.
+    /// Passing `None` reverts to the default configuration.
+    #[cfg(feature = "lsp")]
+    pub fn set_default_configuration(&mut self, configuration: Option<Config>) {
+        self.default_configuration = load_overrides(configuration.unwrap_or_default(), self.opt);
+    }
+
+    /// Resolves the configuration for the given file path from a stack of config files, then applies any command line overrides
+    fn resolve_config_stack(
+        &self,
+        config_stack: &ConfigStack,
+        path: Option<&Path>,
+        sources: &mut ConfigSources,
+    ) -> Result<Config> {
+        let config = config_stack.resolve(path, sources)?;
+        let config = load_overrides(config, self.opt);
+        record_override_sources(self.opt, sources);
+        debug!("config: {:#?}", config);
+        Ok(config)
     }
 
     /// Returns the root used when searching for configuration
@@ -62,7 +368,7 @@ impl ConfigResolver<'_> {
         &self,
         search_root_override: Option<PathBuf>,
     ) -> Option<PathBuf> {
-        match self.opt.search_parent_directories {
+        match self.search_parent_directories {
             true => None,
             false => {
                 Some(search_root_override.unwrap_or_else(|| self.current_directory.to_path_buf()))
@@ -75,102 +381,287 @@ impl ConfigResolver<'_> {
         path: &Path,
         search_root_override: Option<PathBuf>,
     ) -> Result<Config> {
-        if let Some(configuration) = self.forced_configuration {
-            return Ok(configuration);
+        self.resolve_configuration(Some(path), search_root_override, &mut ConfigSources::new())
+    }
+
+    pub fn load_configuration(&mut self, path: &Path) -> Result<Config> {
+        self.load_configuration_with_search_root(path, None)
+    }
+
+    pub fn load_configuration_for_stdin(&mut self) -> Result<Config> {
+        let stdin_filepath = self.opt.stdin_filepath.clone();
+        self.resolve_configuration(stdin_filepath.as_deref(), None, &mut ConfigSources::new())
+    }
+
+    /// Loads the configuration for the given path, along with where each configuration option was set.
+    /// If no path is provided, loads the configuration used when formatting from stdin.
+    #[cfg(feature = "serialize")]
+    pub fn load_configuration_with_sources(
+        &mut self,
+        path: Option<&Path>,
+    ) -> Result<(Config, ConfigSources)> {
+        let path = path.or(self.opt.stdin_filepath.as_deref());
+        let mut sources = ConfigSources::new();
+        let config = self.resolve_configuration(path, None, &mut sources)?;
+        Ok((config, sources))
+    }
+
+    /// Resolves the configuration for the given path.
+    /// If no path is provided (i.e. formatting from stdin without `--stdin-filepath`), the search starts at the current directory.
+    fn resolve_configuration(
+        &mut self,
+        path: Option<&Path>,
+        search_root_override: Option<PathBuf>,
+        sources: &mut ConfigSources,
+    ) -> Result<Config> {
+        let absolute_path = path.map(|path| self.current_directory.join(path));
+
+        if let Some(configuration) = &self.forced_configuration {
+            return self.resolve_config_stack(configuration, absolute_path.as_deref(), sources);
         }
 
         let root = self.get_configuration_search_root(search_root_override);
 
-        let absolute_path = self.current_directory.join(path);
-        let parent_path = &absolute_path
-            .parent()
-            .with_context(|| format!("no parent directory found for {}", path.display()))?;
+        let search_directory = match (path, &absolute_path) {
+            (Some(path), Some(absolute_path)) => absolute_path
+                .parent()
+                .with_context(|| format!("no parent directory found for {}", path.display()))?
+                .to_path_buf(),
+            _ => self.current_directory.to_path_buf(),
+        };
 
-        match self.find_config_file(parent_path, root)? {
-            Some(config) => Ok(config),
+        match self.find_config_file(&search_directory, root)? {
+            Some(config_stack) => {
+                self.resolve_config_stack(&config_stack, absolute_path.as_deref(), sources)
+            }
             None => {
+                record_override_sources(self.opt, sources);
+
                 #[cfg(feature = "editorconfig")]
-                if self.opt.no_editorconfig {
-                    Ok(self.default_configuration)
-                } else {
-                    editorconfig::parse(self.default_configuration, path)
-                        .context("could not parse editorconfig")
+                if !self.opt.no_editorconfig {
+                    let editorconfig_path = path.unwrap_or_else(|| Path::new("*.lua"));
+                    let config = editorconfig::parse(self.default_configuration, editorconfig_path)
+                        .context("could not parse editorconfig")?;
+
+                    #[cfg(feature = "serialize")]
+                    {
+                        let default_configuration = flatten_config(&self.default_configuration)?;
+                        for (key, value) in flatten_config(&config)? {
+                            if default_configuration.get(&key) != Some(&value) {
+                                sources.insert(key, String::from(".editorconfig"));
+                            }
+                        }
+                    }
+
+                    return Ok(config);
                 }
-                #[cfg(not(feature = "editorconfig"))]
+
                 Ok(self.default_configuration)
             }
         }
     }
 
-    pub fn load_configuration(&mut self, path: &Path) -> Result<Config> {
-        self.load_configuration_with_search_root(path, None)
-    }
-
-    pub fn load_configuration_for_stdin(&mut self) -> Result<Config> {
-        if let Some(configuration) = self.forced_configuration {
-            return Ok(configuration);
-        }
+    fn read_config_file(&self, path: &Path) -> Result<ConfigFile> {
+        let contents = match self.unsaved_config_files.get(path) {
+            Some(contents) => {
+                debug!("config: using unsaved contents of {}", path.display());
+                contents.clone()
+            }
+            None => fs::read_to_string(path).context("Failed to read config file")?,
+        };
+        let config_file =
+            parse_config_file(&contents, path).context("Config file not in correct format")?;
 
-        let root = self.get_configuration_search_root(None);
-        let my_current_directory = self.current_directory.to_owned();
-
-        match &self.opt.stdin_filepath {
-            Some(filepath) => self.load_configuration(filepath),
-            None => match self.find_config_file(&my_current_directory, root)? {
-                Some(config) => Ok(config),
-                None => {
-                    #[cfg(feature = "editorconfig")]
-                    if self.opt.no_editorconfig {
-                        Ok(self.default_configuration)
-                    } else {
-                        editorconfig::parse(self.default_configuration, &PathBuf::from("*.lua"))
-                            .context("could not parse editorconfig")
-                    }
-                    #[cfg(not(feature = "editorconfig"))]
-                    Ok(self.default_configuration)
-                }
-            },
-        }
+        Ok(config_file)
     }
 
-    fn lookup_config_file_in_directory(&self, directory: &Path) -> Result<Option<Config>> {
+    fn lookup_config_file_in_directory(&self, directory: &Path) -> Result<Option<ConfigFile>> {
         debug!("config: looking for config in {}", directory.display());
-        let config_file = find_toml_file(directory);
+        let config_file = find_toml_file(directory).or_else(|| {
+            CONFIG_FILE_NAME
+                .iter()
+                .map(|name| directory.join(name))
+                .find(|file_path| self.unsaved_config_files.contains_key(file_path))
+        });
         match config_file {
             Some(file_path) => {
                 debug!("config: found config at {}", file_path.display());
-                let config = read_and_apply_overrides(&file_path, self.opt)?;
-                debug!("config: {:#?}", config);
-                Ok(Some(config))
+                let config_file = self.read_config_file(&file_path)?;
+                Ok(Some(config_file))
             }
             None => Ok(None),
         }
     }
 
+    /// Sets the unsaved contents of a configuration file, which are used instead of the contents on disk.
+    /// Passing `None` discards the unsaved contents, e.g. when the file is closed.
+    #[cfg(feature = "lsp")]
+    pub fn set_unsaved_config_file(&mut self, path: &Path, contents: Option<String>) -> Result<()> {
+        match contents {
+            Some(contents) => self
+                .unsaved_config_files
+                .insert(path.to_path_buf(), contents),
+            None => self.unsaved_config_files.remove(path),
+        };
+
+        self.invalidate_config_file(path)
+    }
+
+    /// The contents of the configuration files which have been modified but not yet saved
+    #[cfg(feature = "lsp")]
+    pub fn unsaved_config_files(&self) -> &HashMap<PathBuf, String> {
+        &self.unsaved_config_files
+    }
+
+    /// Evicts any cached configuration affected by a change to the configuration file at the path.
+    /// This is any configuration resolved for a directory containing the file, or which inherits from the file.
+    /// If the explicitly provided configuration is affected, it is reloaded.
+    #[cfg(feature = "lsp")]
+    pub fn invalidate_config_file(&mut self, path: &Path) -> Result<()> {
+        debug!("config: invalidating configuration for {}", path.display());
+        let config_directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+        if global_config_directories()
+            .iter()
+            .any(|directory| directory == config_directory)
+        {
+            // Any directory without its own root configuration may fall back to the global configuration
+            self.config_cache.clear();
+        } else {
+            self.config_cache.retain(|directory, config_stack| {
+                !directory.starts_with(config_directory)
+                    && !config_stack
+                        .as_ref()
+                        .is_some_and(|config_stack| config_stack.contains(path))
+            });
+        }
+
+        let forced_configuration_path = match &self.forced_configuration {
+            Some(config_stack) if config_stack.contains(path) => config_stack
+                .files
+                .last()
+                .map(|config_file| config_file.path.clone()),
+            _ => None,
+        };
+        if let Some(forced_configuration_path) = forced_configuration_path {
+            let config_file = self.read_config_file(&forced_configuration_path)?;
+            self.forced_configuration =
+                Some(self.build_config_stack(config_file, None, false, &mut Vec::new())?);
+        }
+
+        Ok(())
+    }
+
+    /// The configuration files which any cached configuration was loaded from, including the files they `extends`.
+    /// If `--search-parent-directories` is enabled, this also includes everywhere a global configuration file may be found.
+    /// A change to any of these files may change the configuration.
+    #[cfg(feature = "lsp")]
+    pub fn config_files(&self) -> HashSet<PathBuf> {
+        let mut config_files = self
+            .config_cache
+            .values()
+            .flatten()
+            .chain(&self.forced_configuration)
+            .flat_map(|config_stack| &config_stack.files)
+            .map(|config_file| config_file.path.clone())
+            .collect::<HashSet<_>>();
+
+        if self.search_parent_directories {
+            for directory in global_config_directories() {
+                config_files.extend(CONFIG_FILE_NAME.iter().map(|name| directory.join(name)));
+            }
+        }
+
+        config_files
+    }
+
+    /// Evicts any cached configuration resolved for the directory or a directory within it.
+    /// This is needed when the root where the search for configuration stops changes, e.g. a workspace folder is added.
+    #[cfg(feature = "lsp")]
+    pub fn invalidate_directory(&mut self, directory: &Path) {
+        debug!(
+            "config: invalidating configuration within {}",
+            directory.display()
+        );
+        self.config_cache
+            .retain(|cached_directory, _| !cached_directory.starts_with(directory));
+    }
+
+    /// Builds the stack of configuration files that `config_file` inherits from.
+    /// If the file has `root = true`, it does not inherit from anything.
+    /// If the file `extends` another configuration file, it inherits from that file (and whatever that file extends).
+    /// Otherwise, if `inherit_from_parents` is set, it inherits from the nearest configuration found in a parent directory.
+    fn build_config_stack(
+        &mut self,
+        config_file: ConfigFile,
+        root: Option<PathBuf>,
+        inherit_from_parents: bool,
+        visited: &mut Vec<PathBuf>,
+    ) -> Result<ConfigStack> {
+        if visited.contains(&config_file.path) {
+            bail!(
+                "config file at {} extends itself through a cycle",
+                config_file.path.display()
+            );
+        }
+        visited.push(config_file.path.clone());
+
+        let mut config_stack = if config_file.root {
+            ConfigStack::default()
+        } else if let Some(extends) = &config_file.extends {
+            debug!(
+                "config: {} extends {}",
+                config_file.path.display(),
+                extends.display()
+            );
+            let extended_config_file = self.read_config_file(extends).with_context(|| {
+                format!(
+                    "could not load config file at {} extended by {}",
+                    extends.display(),
+                    config_file.path.display()
+                )
+            })?;
+            self.build_config_stack(extended_config_file, root, false, visited)?
+        } else if inherit_from_parents {
+            match config_file.directory().parent() {
+                Some(parent_directory) if Some(config_file.directory()) != root.as_deref() => self
+                    .find_config_file(parent_directory, root)?
+                    .unwrap_or_default(),
+                _ => self.search_config_locations_as_stack()?.unwrap_or_default(),
+            }
+        } else {
+            ConfigStack::default()
+        };
+
+        config_stack.files.push(config_file);
+        Ok(config_stack)
+    }
+
     /// Looks for a configuration file in the directory provided
     /// Keep searching recursively upwards until we hit the root (if provided), then stop
     /// When `--search-parent-directories` is enabled, root = None, else root = Some(cwd)
+    /// Any configuration file found inherits from configuration files further up, unless it is marked as `root = true`
     fn find_config_file(
         &mut self,
         directory: &Path,
         root: Option<PathBuf>,
-    ) -> Result<Option<Config>> {
-        if let Some(config) = self.config_cache.get(directory) {
-            return Ok(*config);
+    ) -> Result<Option<ConfigStack>> {
+        if let Some(config_stack) = self.config_cache.get(directory) {
+            return Ok(config_stack.clone());
         }
 
         let resolved_configuration = match self.lookup_config_file_in_directory(directory)? {
-            Some(config) => Some(config),
+            Some(config_file) => {
+                Some(self.build_config_stack(config_file, root, true, &mut Vec::new())?)
+            }
             None => {
                 let parent_directory = directory.parent();
                 let should_stop = Some(directory) == root.as_deref() || parent_directory.is_none();
 
                 if should_stop {
                     debug!("config: no configuration file found");
-                    if self.opt.search_parent_directories {
-                        if let Some(config) = self.search_config_locations()? {
-                            return Ok(Some(config));
-                        }
+                    if let Some(config_stack) = self.search_config_locations_as_stack()? {
+                        return Ok(Some(config_stack));
                     }
 
                     debug!("config: falling back to default config");
@@ -182,50 +673,37 @@ impl ConfigResolver<'_> {
         };
 
         self.config_cache
-            .insert(directory.to_path_buf(), resolved_configuration);
+            .insert(directory.to_path_buf(), resolved_configuration.clone());
         Ok(resolved_configuration)
     }
 
-    /// Looks for a configuration file at either `$XDG_CONFIG_HOME`, `$XDG_CONFIG_HOME/stylua`, `$HOME/.config` or `$HOME/.config/stylua`
-    fn search_config_locations(&self) -> Result<Option<Config>> {
-        // Look in `$XDG_CONFIG_HOME`
-        if let Ok(xdg_config) = std::env::var("XDG_CONFIG_HOME") {
-            let xdg_config_path = Path::new(&xdg_config);
-            if xdg_config_path.exists() {
-                debug!("config: looking in $XDG_CONFIG_HOME");
-
-                if let Some(config) = self.lookup_config_file_in_directory(xdg_config_path)? {
-                    return Ok(Some(config));
-                }
-
-                debug!("config: looking in $XDG_CONFIG_HOME/stylua");
-                let xdg_config_path = xdg_config_path.join("stylua");
-                if xdg_config_path.exists() {
-                    if let Some(config) = self.lookup_config_file_in_directory(&xdg_config_path)? {
-                        return Ok(Some(config));
-                    }
-                }
-            }
+    /// Looks for a global configuration file if `--search-parent-directories` is enabled.
+    /// A global configuration file does not inherit from any parent directories, but may still `extends` another file.
+    fn search_config_locations_as_stack(&mut self) -> Result<Option<ConfigStack>> {
+        if !self.search_parent_directories {
+            return Ok(None);
         }
 
-        // Look in `$HOME/.config`
-        if let Ok(home) = std::env::var("HOME") {
-            let home_config_path = Path::new(&home).join(".config");
+        match self.search_config_locations()? {
+            Some(config_file) => Ok(Some(self.build_config_stack(
+                config_file,
+                None,
+                false,
+                &mut Vec::new(),
+            )?)),
+            None => Ok(None),
+        }
+    }
 
-            if home_config_path.exists() {
-                debug!("config: looking in $HOME/.config");
+    /// Looks for a configuration file at either `$XDG_CONFIG_HOME`, `$XDG_CONFIG_HOME/stylua`, `$HOME/.config` or `$HOME/.config/stylua`
+    fn search_config_locations(&self) -> Result<Option<ConfigFile>> {
+        for directory in global_config_directories() {
+            if directory.exists() {
+                debug!("config: looking in {}", directory.display());
 
-                if let Some(config) = self.lookup_config_file_in_directory(&home_config_path)? {
+                if let Some(config) = self.lookup_config_file_in_directory(&directory)? {
                     return Ok(Some(config));
                 }
-
-                debug!("config: looking in $HOME/.config/stylua");
-                let home_config_path = home_config_path.join("stylua");
-                if home_config_path.exists() {
-                    if let Some(config) = self.lookup_config_file_in_directory(&home_config_path)? {
-                        return Ok(Some(config));
-                    }
-                }
             }
         }
 
@@ -233,6 +711,26 @@ impl ConfigResolver<'_> {
     }
 }
 
+/// The directories searched for a global configuration file, in order of precedence:
+/// `$XDG_CONFIG_HOME`, `$XDG_CONFIG_HOME/stylua`, `$HOME/.config` and `$HOME/.config/stylua`
+fn global_config_directories() -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+
+    if let Ok(xdg_config) = std::env::var("XDG_CONFIG_HOME") {
+        let xdg_config_path = PathBuf::from(xdg_config);
+        directories.push(xdg_config_path.clone());
+        directories.push(xdg_config_path.join("stylua"));
+    }
+
+    if let Ok(home) = std::env::var("HOME") {
+        let home_config_path = Path::new(&home).join(".config");
+        directories.push(home_config_path.clone());
+        directories.push(home_config_path.join("stylua"));
+    }
+
+    directories
+}
+
 /// Searches the directory for the configuration toml file (i.e. `stylua.toml` or `.stylua.toml`)
 fn find_toml_file(directory: &Path) -> Option<PathBuf> {
     for name in &CONFIG_FILE_NAME {
@@ -258,6 +756,70 @@ pub fn find_ignore_file_path(mut directory: PathBuf, recursive: bool) -> Option<
     }
 }
 
+/// Records the command line options which override configuration options
+fn record_override_sources(opt: &Opt, sources: &mut ConfigSources) {
+    let format_opts = &opt.format_opts;
+    let overrides = [
+        ("syntax", "--syntax", format_opts.syntax.is_some()),
+        (
+            "column_width",
+            "--column-width",
+            format_opts.column_width.is_some(),
+        ),
+        (
+            "line_endings",
+            "--line-endings",
+            format_opts.line_endings.is_some(),
+        ),
+        (
+            "indent_type",
+            "--indent-type",
+            format_opts.indent_type.is_some(),
+        ),
+        (
+            "indent_width",
+            "--indent-width",
+            format_opts.indent_width.is_some(),
+        ),
+        (
+            "quote_style",
+            "--quote-style",
+            format_opts.quote_style.is_some(),
+        ),
+        (
+            "call_parentheses",
+            "--call-parentheses",
+            format_opts.call_parentheses.is_some(),
+        ),
+        (
+            "space_after_function_names",
+            "--space-after-function-names",
+            format_opts.space_after_function_names.is_some(),
+        ),
+        (
+            "collapse_simple_statement",
+            "--collapse-simple-statement",
+            format_opts.collapse_simple_statement.is_some(),
+        ),
+        (
+            "block_newline_gaps",
+            "--preserve-block-newline-gaps",
+            format_opts.preserve_block_newline_gaps.is_some(),
+        ),
+        (
+            "sort_requires.enabled",
+            "--sort-requires",
+            format_opts.sort_requires,
+        ),
+    ];
+
+    for (key, flag, is_set) in overrides {
+        if is_set {
+            sources.insert(key.to_string(), flag.to_string());
+        }
+    }
+}
+
 /// Handles any overrides provided by command line options
 fn load_overrides(config: Config, opt: &Opt) -> Config {
     let mut new_config = config;
@@ -329,6 +891,14 @@ mod tests {
         assert_eq!(config.line_endings, LineEndings::Windows);
     }
 
+    #[test]
+    fn test_override_line_endings_auto() {
+        let override_opt = Opt::parse_from(vec!["BINARY_NAME", "--line-endings", "Auto"]);
+        let default_config = Config::new();
+        let config = load_overrides(default_config, &override_opt);
+        assert_eq!(config.line_endings, LineEndings::Auto);
+    }
+
     #[test]
     fn test_override_indent_type() {
         let override_opt = Opt::parse_from(vec!["BINARY_NAME", "--indent-type", "Spaces"]);
@@ -360,4 +930,309 @@ mod tests {
         let config = load_overrides(default_config, &override_opt);
         assert_eq!(config.call_parentheses, CallParenType::None);
     }
+
+    #[cfg(feature = "lsp")]
+    #[test]
+    fn test_invalidate_config_file() {
+        use assert_fs::prelude::*;
+
+        let cwd = assert_fs::TempDir::new().unwrap();
+        cwd.child("stylua.toml")
+            .write_str("column_width = 100")
+            .unwrap();
+        let config_path = cwd.child("stylua.toml").to_path_buf();
+        let path = cwd.child("src/foo.lua").to_path_buf();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+        let load_column_width = |config_resolver: &mut ConfigResolver| {
+            config_resolver
+                .load_configuration_with_search_root(&path, Some(cwd.to_path_buf()))
+                .unwrap()
+                .column_width
+        };
+        assert_eq!(load_column_width(&mut config_resolver), 100);
+
+        // The cached configuration is used until the file is invalidated
+        cwd.child("stylua.toml")
+            .write_str("column_width = 80")
+            .unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 100);
+        config_resolver
+            .invalidate_config_file(&config_path)
+            .unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 80);
+
+        // Unsaved contents take precedence over the file on disk
+        config_resolver
+            .set_unsaved_config_file(&config_path, Some(String::from("column_width = 60")))
+            .unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 60);
+        config_resolver
+            .set_unsaved_config_file(&config_path, None)
+            .unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 80);
+
+        cwd.close().unwrap();
+    }
+
+    #[cfg(feature = "lsp")]
+    #[test]
+    fn test_invalidate_extended_config_file() {
+        use assert_fs::prelude::*;
+
+        let cwd = assert_fs::TempDir::new().unwrap();
+        cwd.child("shared/base.toml")
+            .write_str("column_width = 100")
+            .unwrap();
+        cwd.child("packages/foo/stylua.toml")
+            .write_str("extends = '../../shared/base.toml'")
+            .unwrap();
+        let base_path = cwd.child("shared/base.toml").to_path_buf();
+        let path = cwd.child("packages/foo/init.lua").to_path_buf();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+        let load_column_width = |config_resolver: &mut ConfigResolver| {
+            config_resolver
+                .load_configuration_with_search_root(&path, Some(cwd.to_path_buf()))
+                .unwrap()
+                .column_width
+        };
+        assert_eq!(load_column_width(&mut config_resolver), 100);
+
+        // The extended file is reported by the path it is found at, so it can be watched and invalidated
+        assert!(config_resolver.config_files().contains(&base_path));
+        assert!(config_resolver
+            .config_files()
+            .contains(&cwd.child("packages/foo/stylua.toml").to_path_buf()));
+
+        cwd.child("shared/base.toml")
+            .write_str("column_width = 80")
+            .unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 100);
+        config_resolver.invalidate_config_file(&base_path).unwrap();
+        assert_eq!(load_column_width(&mut config_resolver), 80);
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_overrides_applied_to_matching_files() {
+        let config_file = parse_config_file(
+            r#"
+            column_width = 100
+
+            [[overrides]]
+            files = ["*.luau"]
+            syntax = "All"
+            quote_style = "ForceSingle"
+
+            [[overrides]]
+            files = ["spec/**/*.lua"]
+            column_width = 80
+            "#,
+            Path::new("/project/stylua.toml"),
+        )
+        .unwrap();
+        let config_stack = ConfigStack {
+            files: vec![config_file],
+        };
+
+        let config = config_stack
+            .resolve(
+                Some(Path::new("/project/src/foo.luau")),
+                &mut ConfigSources::new(),
+            )
+            .unwrap();
+        assert_eq!(config.column_width, 100);
+        assert_eq!(config.quote_style, QuoteStyle::ForceSingle);
+
+        let config = config_stack
+            .resolve(
+                Some(Path::new("/project/spec/nested/foo.lua")),
+                &mut ConfigSources::new(),
+            )
+            .unwrap();
+        assert_eq!(config.column_width, 80);
+        assert_eq!(config.quote_style, QuoteStyle::AutoPreferDouble);
+
+        let config = config_stack
+            .resolve(
+                Some(Path::new("/project/src/spec/foo.lua")),
+                &mut ConfigSources::new(),
+            )
+            .unwrap();
+        assert_eq!(config.column_width, 100);
+    }
+
+    #[test]
+    fn test_overrides_merged_in_order() {
+        let config_file = parse_config_file(
+            r#"
+            [[overrides]]
+            files = ["*.lua"]
+            column_width = 80
+            indent_type = "Spaces"
+
+            [[overrides]]
+            files = ["generated/*.lua"]
+            column_width = 200
+            "#,
+            Path::new("/project/stylua.toml"),
+        )
+        .unwrap();
+        let config_stack = ConfigStack {
+            files: vec![config_file],
+        };
+
+        let config = config_stack
+            .resolve(
+                Some(Path::new("/project/generated/foo.lua")),
+                &mut ConfigSources::new(),
+            )
+            .unwrap();
+        assert_eq!(config.column_width, 200);
+        assert_eq!(config.indent_type, IndentType::Spaces);
+    }
+
+    #[test]
+    fn test_overrides_merge_nested_tables() {
+        let config_file = parse_config_file(
+            r#"
+            [sort_requires]
+            enabled = true
+
+            [[overrides]]
+            files = ["*.lua"]
+            sort_requires = {}
+            "#,
+            Path::new("/project/stylua.toml"),
+        )
+        .unwrap();
+        let config_stack = ConfigStack {
+            files: vec![config_file],
+        };
+
+        let config = config_stack
+            .resolve(
+                Some(Path::new("/project/foo.lua")),
+                &mut ConfigSources::new(),
+            )
+            .unwrap();
+        assert!(config.sort_requires.enabled);
+    }
+
+    #[test]
+    fn test_overrides_not_applied_without_path() {
+        let config_file = parse_config_file(
+            r#"
+            [[overrides]]
+            files = ["*.lua"]
+            column_width = 80
+            "#,
+            Path::new("/project/stylua.toml"),
+        )
+        .unwrap();
+        let config_stack = ConfigStack {
+            files: vec![config_file],
+        };
+
+        let config = config_stack
+            .resolve(None, &mut ConfigSources::new())
+            .unwrap();
+        assert_eq!(config.column_width, 120);
+    }
+
+    #[test]
+    fn test_override_missing_files_is_error() {
+        let result = parse_config_file(
+            r#"
+            [[overrides]]
+            column_width = 80
+            "#,
+            Path::new("/project/stylua.toml"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_override_with_unknown_field_is_error() {
+        let result = parse_config_file(
+            r#"
+            [[overrides]]
+            files = ["*.lua"]
+            unknown_option = true
+            "#,
+            Path::new("/project/stylua.toml"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_sources_record_config_files_and_overrides() {
+        let config_stack = ConfigStack {
+            files: vec![
+                parse_config_file(
+                    "indent_width = 2\ncolumn_width = 100",
+                    Path::new("/project/stylua.toml"),
+                )
+                .unwrap(),
+                parse_config_file(
+                    r#"
+                    column_width = 80
+
+                    [[overrides]]
+                    files = ["*.luau"]
+                    quote_style = "ForceSingle"
+                    "#,
+                    Path::new("/project/src/stylua.toml"),
+                )
+                .unwrap(),
+            ],
+        };
+
+        let mut sources = ConfigSources::new();
+        config_stack
+            .resolve(Some(Path::new("/project/src/foo.luau")), &mut sources)
+            .unwrap();
+
+        assert_eq!(
+            sources.get("indent_width"),
+            Some(&Path::new("/project/stylua.toml").display().to_string())
+        );
+        assert_eq!(
+            sources.get("column_width"),
+            Some(&Path::new("/project/src/stylua.toml").display().to_string())
+        );
+        assert_eq!(
+            sources.get("quote_style"),
+            Some(&format!(
+                "{} (overrides[0])",
+                Path::new("/project/src/stylua.toml").display()
+            ))
+        );
+        assert_eq!(sources.get("syntax"), None);
+    }
+
+    #[test]
+    fn test_sources_record_command_line_overrides() {
+        let override_opt = Opt::parse_from(vec![
+            "BINARY_NAME",
+            "--column-width",
+            "80",
+            "--sort-requires",
+        ]);
+        let mut sources = ConfigSources::new();
+        record_override_sources(&override_opt, &mut sources);
+        assert_eq!(
+            sources.get("column_width").map(String::as_str),
+            Some("--column-width")
+        );
+        assert_eq!(
+            sources.get("sort_requires.enabled").map(String::as_str),
+            Some("--sort-requires")
+        );
+        assert_eq!(sources.get("indent_width"), None);
+    }
 }
@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::io::{stdout, Write};
+use stylua_lib::Config;
+
+/// Generates a JSON Schema describing `stylua.toml`.
+/// The schema for the formatting options is derived from [`Config`], and is then extended with the
+/// keys that are only understood by the CLI (`root`, `extends` and `overrides`).
+pub fn generate_config_schema() -> Result<Value> {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Config))
+        .context("could not serialize configuration schema")?;
+
+    let properties = schema["properties"].clone();
+
+    let mut override_properties = properties.clone();
+    override_properties["files"] = json!({
+        "description": "Globs matching the files this override applies to, relative to the directory containing the configuration file. Globs without a `/` match files in any directory.",
+        "type": "array",
+        "items": { "type": "string" },
+    });
+
+    schema["title"] = json!("StyLua configuration");
+    schema["properties"]["root"] = json!({
+        "description": "Whether this configuration file is the root, and should not inherit from any configuration in parent directories.",
+        "type": "boolean",
+        "default": false,
+    });
+    schema["properties"]["extends"] = json!({
+        "description": "A path to a configuration file to inherit from, relative to this configuration file. When set, configuration in parent directories is not inherited from.",
+        "type": "string",
+    });
+    schema["properties"]["overrides"] = json!({
+        "description": "Configuration options which only apply to files matching a list of globs. Overrides are applied in order.",
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": override_properties,
+            "required": ["files"],
+            "additionalProperties": false,
+        },
+    });
+
+    Ok(schema)
+}
+
+/// Prints the JSON Schema for `stylua.toml`
+pub fn print_config_schema() -> Result<i32> {
+    let mut output = serde_json::to_string_pretty(&generate_config_schema()?)?;
+    output.push('\n');
+
+    stdout()
+        .lock()
+        .write_all(output.as_bytes())
+        .context("could not output to stdout")?;
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::flatten_config;
+
+    #[test]
+    fn test_schema_contains_every_option() {
+        let schema = generate_config_schema().unwrap();
+        for key in flatten_config(&Config::default()).unwrap().keys() {
+            let key = key.split('.').next().unwrap();
+            assert!(
+                schema["properties"].get(key).is_some(),
+                "schema is missing option `{}`",
+                key
+            );
+            assert!(
+                schema["properties"]["overrides"]["items"]["properties"]
+                    .get(key)
+                    .is_some(),
+                "override schema is missing option `{}`",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_schema_contains_descriptions_and_variants() {
+        let schema = generate_config_schema().unwrap();
+        let schema_string = schema.to_string();
+
+        assert!(schema["properties"]["column_width"]["description"]
+            .as_str()
+            .unwrap()
+            .starts_with("The approximate line length to use when printing the code."));
+        for variant in [
+            "AutoPreferDouble",
+            "AutoPreferSingle",
+            "ForceDouble",
+            "ForceSingle",
+        ] {
+            assert!(schema_string.contains(&format!("\"{variant}\"")));
+        }
+        assert!(schema["definitions"]["SortRequiresConfig"].is_object());
+    }
+}
@@ -0,0 +1,382 @@
+use full_moon::{
+    tokenizer::{Lexer, LexerResult, StringLiteralQuoteType, TokenType},
+    LuaVersion,
+};
+use serde::Serialize;
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// The kind of change made to a hunk of code by formatting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffCategory {
+    /// Only whitespace within lines changed, such as indentation or spacing around operators
+    Whitespace,
+    /// Code was split across, or joined onto, a different number of lines
+    LineBreaking,
+    /// Strings were changed to use a different quote style
+    QuoteStyle,
+    /// Parentheses were added or removed around call arguments
+    CallParentheses,
+    /// Trailing commas or semicolons were added or removed
+    TrailingSeparators,
+    /// Requires were reordered
+    RequireOrder,
+    /// Any other change
+    Other,
+}
+
+impl fmt::Display for DiffCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DiffCategory::Whitespace => "whitespace",
+            DiffCategory::LineBreaking => "line breaking",
+            DiffCategory::QuoteStyle => "quote style",
+            DiffCategory::CallParentheses => "call parentheses",
+            DiffCategory::TrailingSeparators => "trailing separators",
+            DiffCategory::RequireOrder => "require order",
+            DiffCategory::Other => "other",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A token which is not whitespace
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SignificantToken {
+    text: String,
+    /// The value of a quoted string, which stays the same when only its quotes change
+    quoted_value: Option<String>,
+    is_identifier: bool,
+}
+
+/// The value of a quoted string, ignoring any escaping of quotes (which changes alongside the quotes themselves)
+fn unescape_quotes(literal: &str) -> String {
+    let mut value = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            value.push(char);
+            continue;
+        }
+
+        match chars.next() {
+            Some(quote @ ('\'' | '"')) => value.push(quote),
+            Some(escaped) => {
+                value.push(char);
+                value.push(escaped);
+            }
+            None => value.push(char),
+        }
+    }
+    value
+}
+
+/// Tokenizes the code using the given syntax, keeping only the tokens which are not whitespace.
+/// Returns `None` if the code could not be tokenized, which can happen as a hunk may start or end within a string or comment.
+fn significant_tokens(code: &str, lua_version: LuaVersion) -> Option<Vec<SignificantToken>> {
+    let tokens = match Lexer::new(code, lua_version).collect() {
+        LexerResult::Ok(tokens) => tokens,
+        LexerResult::Recovered(..) | LexerResult::Fatal(_) => return None,
+    };
+
+    Some(
+        tokens
+            .iter()
+            .filter(|token| {
+                !matches!(
+                    token.token_type(),
+                    TokenType::Whitespace { .. } | TokenType::Eof
+                )
+            })
+            .map(|token| SignificantToken {
+                text: token.to_string(),
+                quoted_value: match token.token_type() {
+                    TokenType::StringLiteral {
+                        literal,
+                        quote_type: StringLiteralQuoteType::Single | StringLiteralQuoteType::Double,
+                        ..
+                    } => Some(unescape_quotes(literal)),
+                    _ => None,
+                },
+                is_identifier: matches!(token.token_type(), TokenType::Identifier { .. }),
+            })
+            .collect(),
+    )
+}
+
+/// Whether the separator at the index ends a list or a statement, rather than separating two items.
+/// A separator at the end of the hunk is followed by unchanged code, which must close the list or start a new
+/// statement, as otherwise adding or removing the separator would change how the code parses.
+fn is_trailing_separator(tokens: &[SignificantToken], index: usize) -> bool {
+    match tokens.get(index + 1) {
+        None => true,
+        Some(next) if matches!(next.text.as_str(), ")" | "}" | "]") => true,
+        // A semicolon outside of any brackets ends a statement
+        Some(_) => {
+            tokens[index].text == ";"
+                && tokens[..index]
+                    .iter()
+                    .fold(0isize, |depth, token| match token.text.as_str() {
+                        "(" | "{" | "[" => depth + 1,
+                        ")" | "}" | "]" => depth - 1,
+                        _ => depth,
+                    })
+                    <= 0
+        }
+    }
+}
+
+/// Removes the changed tokens which can be explained by a category, recording that category.
+/// Returns the remaining tokens.
+fn remove_explained_tokens<'a>(
+    tokens: &'a [SignificantToken],
+    indices: &[usize],
+    skip_parentheses: bool,
+    categories: &mut BTreeSet<DiffCategory>,
+) -> Vec<&'a SignificantToken> {
+    indices
+        .iter()
+        .filter(|&&index| match tokens[index].text.as_str() {
+            "(" | ")" if skip_parentheses => {
+                categories.insert(DiffCategory::CallParentheses);
+                false
+            }
+            "," | ";" if is_trailing_separator(tokens, index) => {
+                categories.insert(DiffCategory::TrailingSeparators);
+                false
+            }
+            _ => true,
+        })
+        .map(|&index| &tokens[index])
+        .collect()
+}
+
+/// Classifies a change made by formatting, given the original and expected code of the hunk, and the syntax it is written in.
+/// A hunk may contain multiple kinds of change, so every category which applies is returned, in order.
+pub fn classify_diff(original: &str, expected: &str, lua_version: LuaVersion) -> Vec<DiffCategory> {
+    let (original_tokens, expected_tokens) = match (
+        significant_tokens(original, lua_version),
+        significant_tokens(expected, lua_version),
+    ) {
+        (Some(original_tokens), Some(expected_tokens)) => (original_tokens, expected_tokens),
+        _ => return vec![DiffCategory::Other],
+    };
+
+    let mut categories = BTreeSet::new();
+
+    if original.lines().count() != expected.lines().count() {
+        categories.insert(DiffCategory::LineBreaking);
+    }
+
+    if original_tokens == expected_tokens {
+        if categories.is_empty() {
+            categories.insert(DiffCategory::Whitespace);
+        }
+        return categories.into_iter().collect();
+    }
+
+    // The same tokens in a different order, which only happens when sorting requires
+    let is_reordered = {
+        let mut original_sorted = original_tokens.iter().collect::<Vec<_>>();
+        let mut expected_sorted = expected_tokens.iter().collect::<Vec<_>>();
+        original_sorted.sort_unstable();
+        expected_sorted.sort_unstable();
+        original_sorted == expected_sorted
+    };
+    if is_reordered && original_tokens.iter().any(|token| token.text == "require") {
+        categories.insert(DiffCategory::RequireOrder);
+        return categories.into_iter().collect();
+    }
+
+    // Collect the indices of every token which was removed or added
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for operation in capture_diff_slices(Algorithm::Myers, &original_tokens, &expected_tokens) {
+        if !matches!(operation, DiffOp::Equal { .. }) {
+            removed.extend(operation.old_range());
+            added.extend(operation.new_range());
+        }
+    }
+
+    // Parentheses only belong to calls if every opening parenthesis follows something which can be called,
+    // rather than wrapping an expression
+    let parentheses_are_calls = |tokens: &[SignificantToken], indices: &[usize]| {
+        let count = |symbol| {
+            indices
+                .iter()
+                .filter(|&&index| tokens[index].text == symbol)
+                .count()
+        };
+        count("(") == count(")")
+            && indices.iter().all(|&index| {
+                tokens[index].text != "("
+                    || index
+                        .checked_sub(1)
+                        .map(|previous| &tokens[previous])
+                        .is_some_and(|previous| {
+                            previous.is_identifier || previous.text == ")" || previous.text == "]"
+                        })
+            })
+    };
+    let skip_parentheses = parentheses_are_calls(&original_tokens, &removed)
+        && parentheses_are_calls(&expected_tokens, &added);
+
+    let removed = remove_explained_tokens(
+        &original_tokens,
+        &removed,
+        skip_parentheses,
+        &mut categories,
+    );
+    let added =
+        remove_explained_tokens(&expected_tokens, &added, skip_parentheses, &mut categories);
+
+    // The remaining tokens must only differ in the quotes used for strings with the same value
+    if removed.len() == added.len() {
+        for (removed, added) in removed.iter().zip(&added) {
+            match (&removed.quoted_value, &added.quoted_value) {
+                (Some(removed_value), Some(added_value)) if removed_value == added_value => {
+                    categories.insert(DiffCategory::QuoteStyle);
+                }
+                _ if removed != added => {
+                    categories.insert(DiffCategory::Other);
+                }
+                _ => (),
+            }
+        }
+    } else {
+        categories.insert(DiffCategory::Other);
+    }
+
+    categories.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(original: &str, expected: &str) -> Vec<DiffCategory> {
+        classify_diff(original, expected, LuaVersion::new())
+    }
+
+    #[test]
+    fn test_classify_whitespace() {
+        assert_eq!(
+            classify("local   x =  1\n", "local x = 1\n"),
+            [DiffCategory::Whitespace]
+        );
+        assert_eq!(
+            classify("    foo()\n", "\tfoo()\n"),
+            [DiffCategory::Whitespace]
+        );
+    }
+
+    #[test]
+    fn test_classify_line_breaking() {
+        assert_eq!(
+            classify("local x = {\n\t1,\n\t2\n}\n", "local x = { 1, 2 }\n"),
+            [DiffCategory::LineBreaking]
+        );
+        assert_eq!(
+            classify(
+                "foo(aaaaaaaa, bbbbbbbb)\n",
+                "foo(\n\taaaaaaaa,\n\tbbbbbbbb,\n)\n"
+            ),
+            [DiffCategory::LineBreaking, DiffCategory::TrailingSeparators]
+        );
+    }
+
+    #[test]
+    fn test_classify_trailing_separators() {
+        assert_eq!(
+            classify("local x = { 1, 2 }\n", "local x = { 1, 2, }\n"),
+            [DiffCategory::TrailingSeparators]
+        );
+        assert_eq!(
+            classify("\t2\n", "\t2,\n"),
+            [DiffCategory::TrailingSeparators]
+        );
+        assert_eq!(
+            classify("local x = 1;\n", "local x = 1\n"),
+            [DiffCategory::TrailingSeparators]
+        );
+        // Separators between items are not trailing
+        assert_eq!(
+            classify("local x = { 1; 2 }\n", "local x = { 1, 2 }\n"),
+            [DiffCategory::Other]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "luau")]
+    fn test_classify_uses_syntax() {
+        let original = "local x = `hello {name}`   + 1\n";
+        let expected = "local x = `hello {name}` + 1\n";
+        assert_eq!(
+            classify_diff(original, expected, LuaVersion::luau()),
+            [DiffCategory::Whitespace]
+        );
+    }
+
+    #[test]
+    fn test_classify_quote_style() {
+        assert_eq!(
+            classify("local x = 'hello'\n", "local x = \"hello\"\n"),
+            [DiffCategory::QuoteStyle]
+        );
+        assert_eq!(
+            classify("local x = 'it\\'s'\n", "local x = \"it's\"\n"),
+            [DiffCategory::QuoteStyle]
+        );
+        // Strings with different values are not just a change of quotes
+        assert_eq!(
+            classify("local x = 'hello'\n", "local x = \"world\"\n"),
+            [DiffCategory::Other]
+        );
+        assert_eq!(
+            classify("local x = [[hello]]\n", "local x = \"hello\"\n"),
+            [DiffCategory::Other]
+        );
+    }
+
+    #[test]
+    fn test_classify_call_parentheses() {
+        assert_eq!(
+            classify("require 'foo'\n", "require(\"foo\")\n"),
+            [DiffCategory::QuoteStyle, DiffCategory::CallParentheses]
+        );
+        assert_eq!(
+            classify("foo({ x = 1 })\n", "foo { x = 1 }\n"),
+            [DiffCategory::CallParentheses]
+        );
+    }
+
+    #[test]
+    fn test_classify_require_order() {
+        assert_eq!(
+            classify(
+                "local b = require(\"b\")\nlocal a = require(\"a\")\n",
+                "local a = require(\"a\")\nlocal b = require(\"b\")\n"
+            ),
+            [DiffCategory::RequireOrder]
+        );
+    }
+
+    #[test]
+    fn test_classify_other() {
+        assert_eq!(
+            classify("local x = (1)\n", "local x = 1\n"),
+            [DiffCategory::Other]
+        );
+        assert_eq!(
+            classify("local x = 1 + 2\n", "local x = 3\n"),
+            [DiffCategory::Other]
+        );
+        // Hunks which cannot be tokenized cannot be classified
+        assert_eq!(
+            classify("local x = [[\n", "local x = [[ \n"),
+            [DiffCategory::Other]
+        );
+    }
+}
@@ -1,48 +1,312 @@
-use std::{convert::TryInto, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
 
-use lsp_server::{Connection, ErrorCode, Message, Response};
+use crossbeam_channel::Sender;
+use full_moon::{
+    ast::{LastStmt, Stmt},
+    node::Node,
+    tokenizer::{Lexer, LexerResult, Symbol, TokenType},
+    visitors::Visitor,
+    LuaVersion,
+};
+use log::*;
+use lsp_server::{Connection, ErrorCode, Message, RequestId, Response};
 use lsp_textdocument::{FullTextDocument, TextDocuments};
 use lsp_types::{
-    request::{Formatting, RangeFormatting, Request},
-    DocumentFormattingParams, DocumentRangeFormattingParams, FormattingOptions, InitializeParams,
-    InitializeResult, OneOf, Range, ServerCapabilities, ServerInfo, TextDocumentSyncCapability,
-    TextDocumentSyncKind, TextEdit, Uri, WorkspaceFolder,
+    notification::{
+        Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
+        DidChangeWorkspaceFolders, DidCloseTextDocument, DidOpenTextDocument, LogMessage,
+        Notification, PublishDiagnostics, ShowMessage,
+    },
+    request::{
+        ApplyWorkspaceEdit, CodeActionRequest, CodeActionResolveRequest, DocumentDiagnosticRequest,
+        ExecuteCommand, Formatting, OnTypeFormatting, RangeFormatting, RegisterCapability, Request,
+        Shutdown, WorkspaceConfiguration,
+    },
+    ApplyWorkspaceEditParams, ApplyWorkspaceEditResponse, CancelParams, CodeAction, CodeActionKind,
+    CodeActionOptions, CodeActionOrCommand, CodeActionParams, CodeActionProviderCapability,
+    ConfigurationItem, ConfigurationParams, Diagnostic, DiagnosticOptions,
+    DiagnosticServerCapabilities, DiagnosticSeverity, DidChangeConfigurationParams,
+    DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, DidChangeWorkspaceFoldersParams,
+    DocumentDiagnosticParams, DocumentDiagnosticReport, DocumentDiagnosticReportResult,
+    DocumentFormattingParams, DocumentOnTypeFormattingOptions, DocumentOnTypeFormattingParams,
+    DocumentRangeFormattingParams, ExecuteCommandOptions, ExecuteCommandParams, FileSystemWatcher,
+    FormattingOptions, FullDocumentDiagnosticReport, GlobPattern, InitializeParams,
+    InitializeResult, LogMessageParams, MessageType, NumberOrString, OneOf, Position,
+    PositionEncodingKind, PublishDiagnosticsParams, Range, Registration, RegistrationParams,
+    RelatedFullDocumentDiagnosticReport, RelativePattern, ServerCapabilities, ServerInfo,
+    ShowMessageParams, TextDocumentIdentifier, TextDocumentSyncCapability, TextDocumentSyncKind,
+    TextEdit, Uri, WorkDoneProgressParams, WorkspaceEdit, WorkspaceFolder,
+    WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities,
 };
 use serde::{Deserialize, Serialize};
-use similar::{DiffOp, TextDiff};
-use stylua_lib::{format_code, IndentType, OutputVerification};
+use similar::{DiffOp, DiffTag, TextDiff};
+use stylua_lib::{
+    format_code, format_code_with_ranges, Config, IndentType, OutputVerification,
+    SortRequiresConfig,
+};
+use threadpool::ThreadPool;
+
+use crate::{
+    config::{self, ConfigResolver},
+    opt,
+    stylua_ignore::{self, create_walker_builder, matches_default_glob},
+};
+
+/// The `textDocument/rangesFormatting` request, introduced in LSP 3.18.
+/// This is not yet provided by `lsp_types`.
+enum RangesFormatting {}
+
+impl Request for RangesFormatting {
+    type Params = DocumentRangesFormattingParams;
+    type Result = Option<Vec<TextEdit>>;
+    const METHOD: &'static str = "textDocument/rangesFormatting";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentRangesFormattingParams {
+    text_document: TextDocumentIdentifier,
+    ranges: Vec<Range>,
+    options: FormattingOptions,
+    #[serde(flatten)]
+    work_done_progress_params: WorkDoneProgressParams,
+}
+
+/// The encoding of the `character` offsets within positions exchanged with the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PositionEncoding {
+    Utf8,
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// Returns the byte offset of the position within the document.
+    /// Positions past the end of a line are clamped to the end of the line.
+    fn offset_at(self, document: &FullTextDocument, position: Position) -> usize {
+        match self {
+            PositionEncoding::Utf16 => document
+                .offset_at(position)
+                .try_into()
+                .expect("u32 fits into usize"),
+            PositionEncoding::Utf8 => {
+                let contents = document.get_content(None);
+                let line_start: usize = document
+                    .offset_at(Position::new(position.line, 0))
+                    .try_into()
+                    .expect("u32 fits into usize");
+                let line = contents[line_start..]
+                    .split(['\r', '\n'])
+                    .next()
+                    .unwrap_or_default();
+                let character: usize = position.character.try_into().expect("u32 fits into usize");
+
+                // Round down to the nearest character, in case the position is in the middle of one
+                let mut offset = line_start + character.min(line.len());
+                while !contents.is_char_boundary(offset) {
+                    offset -= 1;
+                }
+                offset
+            }
+        }
+    }
+
+    /// Returns the position of the byte offset within the document
+    fn position_at(self, document: &FullTextDocument, offset: usize) -> Position {
+        let position = document.position_at(offset.try_into().expect("usize fits into u32"));
+        match self {
+            PositionEncoding::Utf16 => position,
+            PositionEncoding::Utf8 => {
+                let line_start = document.offset_at(Position::new(position.line, 0));
+                let offset: u32 = offset
+                    .min(document.get_content(None).len())
+                    .try_into()
+                    .expect("usize fits into u32");
+                Position::new(position.line, offset - line_start)
+            }
+        }
+    }
+}
+
+/// How documents which could not be formatted are reported to the user
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FailureReporting {
+    /// Nothing is reported, and the formatting request is just answered with no edits
+    Off,
+    /// Every failure is written to the client's log through `window/logMessage`
+    #[default]
+    Log,
+    /// Errors, such as parse errors, are shown to the user through `window/showMessage`.
+    /// Documents which are deliberately left unformatted, such as ignored files, are only logged.
+    Show,
+}
+
+impl FailureReporting {
+    /// Creates the message reporting that a document could not be formatted, if it should be reported
+    fn message(self, message_type: MessageType, message: String) -> Option<Message> {
+        let notification = match self {
+            FailureReporting::Off => return None,
+            FailureReporting::Show if message_type == MessageType::ERROR => {
+                lsp_server::Notification::new(
+                    ShowMessage::METHOD.to_string(),
+                    ShowMessageParams {
+                        typ: message_type,
+                        message,
+                    },
+                )
+            }
+            FailureReporting::Log | FailureReporting::Show => lsp_server::Notification::new(
+                LogMessage::METHOD.to_string(),
+                LogMessageParams {
+                    typ: message_type,
+                    message,
+                },
+            ),
+        };
+
+        Some(Message::Notification(notification))
+    }
+}
+
+/// Options negotiated with the client when initializing
+#[derive(Debug, Clone, Copy)]
+struct ClientOptions {
+    respect_editor_formatting_options: bool,
+    /// Whether we should push diagnostics to the client whenever a document changes,
+    /// rather than waiting for the client to pull them
+    publish_diagnostics: bool,
+    position_encoding: PositionEncoding,
+    /// Whether edits should replace whole lines, rather than being as small as possible
+    line_granular_edits: bool,
+    /// Whether the client supports us pulling settings through `workspace/configuration`
+    workspace_configuration: bool,
+    /// Whether the client supports us registering which files to watch through `workspace/didChangeWatchedFiles`
+    watched_files_registration: bool,
+    /// Whether watched files can be given as a pattern relative to a directory, rather than an absolute path
+    relative_watch_patterns: bool,
+    /// Whether the client can resolve the edits of code actions through `codeAction/resolve`,
+    /// so that expensive edits only need to be computed once an action is chosen
+    code_action_resolve: bool,
+    failure_reporting: FailureReporting,
+}
+
+impl ClientOptions {
+    fn new(initialize_params: &InitializeParams) -> Self {
+        let initialization_options = initialize_params
+            .initialization_options
+            .clone()
+            .and_then(|opt| serde_json::from_value::<InitializationOptions>(opt).ok())
+            .unwrap_or_default();
+
+        // Clients which support pulling diagnostics will request them when needed, otherwise we push them on every change
+        let publish_diagnostics = initialize_params
+            .capabilities
+            .text_document
+            .as_ref()
+            .map(|text_document| {
+                text_document.publish_diagnostics.is_some() && text_document.diagnostic.is_none()
+            })
+            .unwrap_or_default();
+
+        // UTF-8 matches how we store documents, so we prefer it whenever the client supports it
+        let supports_utf8 = initialize_params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_ref())
+            .is_some_and(|encodings| encodings.contains(&PositionEncodingKind::UTF8));
+
+        let code_action_resolve = initialize_params
+            .capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.code_action.as_ref())
+            .and_then(|code_action| code_action.resolve_support.as_ref())
+            .is_some_and(|resolve_support| {
+                resolve_support
+                    .properties
+                    .iter()
+                    .any(|property| property == "edit")
+            });
+
+        let did_change_watched_files = initialize_params
+            .capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files);
+
+        Self {
+            respect_editor_formatting_options: initialization_options
+                .respect_editor_formatting_options
+                .unwrap_or_default(),
+            publish_diagnostics,
+            position_encoding: if supports_utf8 {
+                PositionEncoding::Utf8
+            } else {
+                PositionEncoding::Utf16
+            },
+            line_granular_edits: initialization_options
+                .line_granular_edits
+                .unwrap_or_default(),
+            workspace_configuration: initialize_params
+                .capabilities
+                .workspace
+                .as_ref()
+                .and_then(|workspace| workspace.configuration)
+                .unwrap_or_default(),
+            watched_files_registration: did_change_watched_files
+                .and_then(|did_change_watched_files| did_change_watched_files.dynamic_registration)
+                .unwrap_or_default(),
+            relative_watch_patterns: did_change_watched_files
+                .and_then(|did_change_watched_files| {
+                    did_change_watched_files.relative_pattern_support
+                })
+                .unwrap_or_default(),
+            code_action_resolve,
+            failure_reporting: initialization_options
+                .report_formatting_failures
+                .unwrap_or_default(),
+        }
+    }
+}
 
-use crate::{config::ConfigResolver, opt, stylua_ignore};
+/// Byte offsets of the start of each slice, with a final entry for the end of the last slice
+fn slice_offsets(slices: &[&str]) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(slices.iter().scan(0, |offset, slice| {
+            *offset += slice.len();
+            Some(*offset)
+        }))
+        .collect()
+}
 
 fn diffop_to_textedit(
     op: DiffOp,
     document: &FullTextDocument,
-    original_contents: &str,
+    position_encoding: PositionEncoding,
+    original_offsets: &[usize],
     formatted_contents: &str,
+    formatted_offsets: &[usize],
 ) -> Option<TextEdit> {
-    let range = |start: usize, len: usize| {
-        let byte_start = original_contents
-            .char_indices()
-            .nth(start)
-            .map(|(i, _)| i)
-            .unwrap_or(original_contents.len());
-        let byte_end = original_contents
-            .char_indices()
-            .nth(start + len)
-            .map(|(i, _)| i)
-            .unwrap_or(original_contents.len());
-        Range {
-            start: document.position_at(byte_start.try_into().expect("usize fits into u32")),
-            end: document.position_at(byte_end.try_into().expect("usize fits into u32")),
-        }
+    let range = |start: usize, len: usize| Range {
+        start: position_encoding.position_at(document, original_offsets[start]),
+        end: position_encoding.position_at(document, original_offsets[start + len]),
     };
 
     let lookup = |start: usize, len: usize| {
-        formatted_contents
-            .chars()
-            .skip(start)
-            .take(len)
-            .collect::<String>()
+        formatted_contents[formatted_offsets[start]..formatted_offsets[start + len]].to_string()
     };
 
     match op {
@@ -79,6 +343,230 @@ fn diffop_to_textedit(
     }
 }
 
+/// Computes the edits required to turn the contents of the document into the new contents
+fn compute_text_edits(
+    document: &FullTextDocument,
+    client_options: ClientOptions,
+    original_contents: &str,
+    new_contents: &str,
+) -> Vec<TextEdit> {
+    let diff = if client_options.line_granular_edits {
+        TextDiff::from_lines(original_contents, new_contents)
+    } else {
+        TextDiff::from_chars(original_contents, new_contents)
+    };
+    let original_offsets = slice_offsets(diff.old_slices());
+    let new_offsets = slice_offsets(diff.new_slices());
+
+    diff.grouped_ops(0)
+        .into_iter()
+        .flat_map(|operations| {
+            operations.into_iter().filter_map(|op| {
+                diffop_to_textedit(
+                    op,
+                    document,
+                    client_options.position_encoding,
+                    &original_offsets,
+                    new_contents,
+                    &new_offsets,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Formats the document within the given ranges, returning the edits to apply.
+fn format_document(
+    document: &FullTextDocument,
+    client_options: ClientOptions,
+    config: Config,
+    ranges: &[Range],
+    verification: OutputVerification,
+) -> Result<Vec<TextEdit>, stylua_lib::Error> {
+    let contents = document.get_content(None);
+    let position_encoding = client_options.position_encoding;
+
+    let ranges = ranges
+        .iter()
+        .map(|lsp_range| {
+            stylua_lib::Range::from_values(
+                Some(position_encoding.offset_at(document, lsp_range.start)),
+                Some(position_encoding.offset_at(document, lsp_range.end)),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let formatted_contents = format_code_with_ranges(contents, config, &ranges, verification)?;
+
+    Ok(compute_text_edits(
+        document,
+        client_options,
+        contents,
+        &formatted_contents,
+    ))
+}
+
+/// Finds the byte range of the innermost statement containing the given byte offset
+#[derive(Default)]
+struct StatementFinder {
+    offset: usize,
+    statement: Option<(usize, usize)>,
+}
+
+impl StatementFinder {
+    fn visit_node(&mut self, node: &impl Node) {
+        if let (Some(start), Some(end)) = (node.start_position(), node.end_position()) {
+            // Statements are visited before the statements nested within them, so the last match is the innermost
+            if start.bytes() <= self.offset && self.offset <= end.bytes() {
+                self.statement = Some((start.bytes(), end.bytes()));
+            }
+        }
+    }
+}
+
+impl Visitor for StatementFinder {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        self.visit_node(stmt);
+    }
+
+    fn visit_last_stmt(&mut self, last_stmt: &LastStmt) {
+        self.visit_node(last_stmt);
+    }
+}
+
+/// Returns the byte range of the innermost statement containing the offset.
+/// Returns `None` if the code cannot be parsed, or the offset is not within a statement.
+fn find_statement(
+    contents: &str,
+    lua_version: LuaVersion,
+    offset: usize,
+) -> Option<(usize, usize)> {
+    let ast = full_moon::parse_fallible(contents, lua_version)
+        .into_result()
+        .ok()?;
+
+    let mut finder = StatementFinder {
+        offset,
+        ..Default::default()
+    };
+    finder.visit_ast(&ast);
+    finder.statement
+}
+
+/// Returns the byte range of the statement to format after a character is typed, ending at the given offset.
+/// After a newline, this is the statement ending the previous line.
+/// After `end`, `)` or `}`, this is the innermost statement enclosing the closing token.
+fn find_on_type_formatting_statement(
+    contents: &str,
+    lua_version: LuaVersion,
+    offset: usize,
+    character: &str,
+) -> Option<(usize, usize)> {
+    // Skip past the newline, and any indentation the editor has inserted
+    let end = contents[..offset].trim_end().len();
+    if end == 0 {
+        return None;
+    }
+
+    if character == "\n" {
+        return find_statement(contents, lua_version, end - 1)
+            .filter(|&(_, statement_end)| statement_end == end);
+    }
+
+    // `d` is typed often, so only lex the document when the text typed could be an `end`
+    let before = &contents[..end];
+    let could_close_node = match character {
+        "d" => before.strip_suffix("end").is_some_and(|before| {
+            !before.ends_with(|char: char| char.is_alphanumeric() || char == '_')
+        }),
+        _ => before.ends_with(character),
+    };
+    if !could_close_node {
+        return None;
+    }
+
+    // Make sure the character closes a block, call or table, rather than being part of a string, comment or name
+    let tokens = match Lexer::new(contents, lua_version).collect() {
+        LexerResult::Ok(tokens) => tokens,
+        LexerResult::Recovered(..) | LexerResult::Fatal(_) => return None,
+    };
+    let closes_node = tokens.iter().any(|token| {
+        token.end_position().bytes() == end
+            && matches!(
+                token.token_type(),
+                TokenType::Symbol {
+                    symbol: Symbol::End | Symbol::RightParen | Symbol::RightBrace
+                }
+            )
+    });
+
+    if closes_node {
+        find_statement(contents, lua_version, end - 1)
+    } else {
+        None
+    }
+}
+
+/// Returns the byte offset of the start of the line containing the offset
+fn line_start(contents: &str, offset: usize) -> usize {
+    contents[..offset].rfind('\n').map_or(0, |index| index + 1)
+}
+
+/// Returns the whitespace indenting the line starting at the offset
+fn line_indentation(contents: &str, line_start: usize) -> &str {
+    let line = &contents[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Adds a `-- stylua: ignore` comment before the innermost statement containing the offset.
+/// Returns `None` if the code cannot be parsed, or the offset is not within a statement.
+fn ignore_statement(contents: &str, lua_version: LuaVersion, offset: usize) -> Option<String> {
+    let (statement_start, _) = find_statement(contents, lua_version, offset)?;
+
+    let prefix = &contents[line_start(contents, statement_start)..statement_start];
+    let comment = if prefix.trim().is_empty() {
+        format!("-- stylua: ignore\n{prefix}")
+    } else {
+        // The statement does not start its line, so the comment needs a line of its own
+        String::from("\n-- stylua: ignore\n")
+    };
+
+    Some(format!(
+        "{}{}{}",
+        &contents[..statement_start],
+        comment,
+        &contents[statement_start..]
+    ))
+}
+
+/// Surrounds the lines covered by the byte range with `-- stylua: ignore start` and `-- stylua: ignore end` comments
+fn ignore_lines(contents: &str, start: usize, end: usize) -> String {
+    let start = line_start(contents, start);
+    // A selection ending at the start of a line does not include that line
+    let end = if end > start && contents[..end].ends_with('\n') {
+        end - 1
+    } else {
+        end
+    };
+    let end = contents[end..]
+        .find('\n')
+        .map_or(contents.len(), |index| end + index + 1);
+
+    let indentation = line_indentation(contents, start);
+    let line_ending = if contents[..end].ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+
+    format!(
+        "{}{indentation}-- stylua: ignore start\n{}{line_ending}{indentation}-- stylua: ignore end\n{}",
+        &contents[..start],
+        &contents[start..end],
+        &contents[end..],
+    )
+}
+
 trait ToFilePath {
     fn to_file_path(&self) -> PathBuf;
 }
@@ -89,7 +577,8 @@ impl ToFilePath for Uri {
         //
         // Made some modification because `lsp-types` currently use 0.1.4 instead of
         // 0.4.1 from the source.
-        let path = self.path().as_str();
+        let path = self.path().as_estr().decode().into_string_lossy();
+        let path = path.as_ref();
 
         #[cfg(windows)]
         {
@@ -116,887 +605,3499 @@ impl ToFilePath for Uri {
     }
 }
 
-struct LanguageServer<'a> {
-    documents: TextDocuments,
-    workspace_folders: Vec<WorkspaceFolder>,
-    root_uri: Option<Uri>,
-    search_parent_directories: bool,
-    respect_editor_formatting_options: bool,
-    config_resolver: &'a mut ConfigResolver<'a>,
-}
+/// Converts an absolute file path into a `file://` URI, percent-encoding any characters not allowed in a URI path
+fn file_path_to_uri(path: &Path) -> Option<Uri> {
+    let path = path.to_str()?.replace('\\', "/");
+    // Windows paths start with a drive letter rather than a slash
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        format!("/{path}")
+    };
 
-enum FormattingError {
-    StyLuaError,
-    NotLuaDocument,
-    DocumentNotFound,
-    FileIsIgnored,
+    let encoded_path = path
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+
+    Uri::from_str(&format!("file://{encoded_path}")).ok()
 }
 
-impl LanguageServer<'_> {
-    fn new<'a>(
-        workspace_folders: Vec<WorkspaceFolder>,
-        root_uri: Option<Uri>,
-        search_parent_directories: bool,
-        respect_editor_formatting_options: bool,
-        config_resolver: &'a mut ConfigResolver<'a>,
-    ) -> LanguageServer<'a> {
-        LanguageServer {
-            documents: TextDocuments::new(),
-            workspace_folders,
-            root_uri,
-            search_parent_directories,
-            respect_editor_formatting_options,
-            config_resolver,
-        }
+/// Computes the diagnostics for the document: any parse errors, and a hint for every region which would be reformatted
+fn compute_diagnostics(
+    document: &FullTextDocument,
+    config: Config,
+    position_encoding: PositionEncoding,
+) -> Vec<Diagnostic> {
+    let contents = document.get_content(None);
+    let position_at = |offset: usize| position_encoding.position_at(document, offset);
+
+    match format_code(contents, config, None, OutputVerification::None) {
+        Ok(formatted_contents) => TextDiff::from_lines(contents, &formatted_contents)
+            .grouped_ops(0)
+            .into_iter()
+            .flatten()
+            .filter(|op| op.tag() != DiffTag::Equal)
+            .map(|op| {
+                let old_range = op.old_range();
+                let line_offset = |line: usize| {
+                    contents
+                        .split_inclusive('\n')
+                        .take(line)
+                        .map(str::len)
+                        .sum::<usize>()
+                };
+
+                Diagnostic {
+                    range: Range::new(
+                        position_at(line_offset(old_range.start)),
+                        position_at(line_offset(old_range.end)),
+                    ),
+                    severity: Some(DiagnosticSeverity::HINT),
+                    source: Some(String::from("stylua")),
+                    message: String::from("code is not formatted"),
+                    ..Default::default()
+                }
+            })
+            .collect(),
+        Err(stylua_lib::Error::ParseError(errors)) => errors
+            .iter()
+            .map(|error| {
+                let (start, end) = error.range();
+                Diagnostic {
+                    range: Range::new(position_at(start.bytes()), position_at(end.bytes())),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some(String::from("stylua")),
+                    message: error.error_message().to_string(),
+                    ..Default::default()
+                }
+            })
+            .collect(),
+        Err(_) => Vec::new(),
     }
+}
 
-    fn find_config_root(&self, uri: &Uri) -> PathBuf {
-        let mut best_workspace = None;
-        let mut best_len = 0;
-        let check_str = uri.as_str();
-        for workspace in &self.workspace_folders {
-            if *uri == workspace.uri {
-                return workspace.uri.to_file_path();
-            }
+/// A report of every diagnostic for a document, to respond to a request for diagnostics with
+fn diagnostic_report(diagnostics: Vec<Diagnostic>) -> DocumentDiagnosticReportResult {
+    DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(
+        RelatedFullDocumentDiagnosticReport {
+            related_documents: None,
+            full_document_diagnostic_report: FullDocumentDiagnosticReport {
+                result_id: None,
+                items: diagnostics,
+            },
+        },
+    ))
+}
 
-            let prefix_str = workspace.uri.as_str();
-            if prefix_str.len() < best_len {
-                continue;
-            }
+/// Finds the range of the statement to format after the character was typed at the position
+fn find_on_type_formatting_range(
+    document: &FullTextDocument,
+    config: Config,
+    position_encoding: PositionEncoding,
+    position: Position,
+    ch: &str,
+) -> Option<Range> {
+    let contents = document.get_content(None);
+    let offset = position_encoding.offset_at(document, position);
+    let (start, end) =
+        find_on_type_formatting_statement(contents, config.syntax.into(), offset, ch)?;
+
+    Some(Range::new(
+        position_encoding.position_at(document, start),
+        position_encoding.position_at(document, end),
+    ))
+}
 
-            if check_str.starts_with(prefix_str) {
-                best_workspace = Some(&workspace.uri);
-                best_len = prefix_str.len()
-            }
-        }
+/// A code action which needs the document to be formatted, so its edit is only computed once it is resolved,
+/// if the client supports resolving code actions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FormattingCodeAction {
+    FormatSelection,
+    SortRequires,
+}
 
-        match best_workspace {
-            Some(workspace) => workspace.to_file_path(),
-            None => match &self.root_uri {
-                Some(root_uri) => root_uri.to_file_path(),
-                None => std::env::current_dir().expect("Could not find current directory"),
-            },
+impl FormattingCodeAction {
+    fn title(self) -> &'static str {
+        match self {
+            FormattingCodeAction::FormatSelection => "Format selection",
+            FormattingCodeAction::SortRequires => "Sort requires in this file",
         }
     }
 
-    fn handle_formatting(
-        &mut self,
-        uri: &Uri,
-        range: Option<Range>,
-        formatting_options: Option<&FormattingOptions>,
-    ) -> Result<Vec<TextEdit>, FormattingError> {
-        let Some(document) = self.documents.get_document(uri) else {
-            return Err(FormattingError::DocumentNotFound);
-        };
+    fn kind(self) -> CodeActionKind {
+        match self {
+            FormattingCodeAction::FormatSelection => CodeActionKind::REFACTOR_REWRITE,
+            FormattingCodeAction::SortRequires => CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+        }
+    }
 
-        let range = range.map(|lsp_range| {
-            stylua_lib::Range::from_values(
-                Some(document.offset_at(lsp_range.start).try_into().unwrap()),
-                Some(document.offset_at(lsp_range.end).try_into().unwrap()),
+    /// Formats the document for the action, returning the new contents
+    fn apply(
+        self,
+        contents: &str,
+        config: Config,
+        start: usize,
+        end: usize,
+        verification: OutputVerification,
+    ) -> Option<String> {
+        match self {
+            FormattingCodeAction::FormatSelection => format_code(
+                contents,
+                config,
+                Some(stylua_lib::Range::from_values(Some(start), Some(end))),
+                verification,
             )
-        });
+            .ok(),
+            FormattingCodeAction::SortRequires => format_code(
+                contents,
+                Config {
+                    sort_requires: SortRequiresConfig { enabled: true },
+                    ..config
+                },
+                None,
+                verification,
+            )
+            .ok(),
+        }
+    }
+}
 
-        if document.language_id() != "lua" && document.language_id() != "luau" {
-            return Err(FormattingError::NotLuaDocument);
+/// Stored in the `data` of a code action whose edit is computed once it is resolved
+#[derive(Serialize, Deserialize)]
+struct CodeActionData {
+    uri: Uri,
+    /// The version of the document the action was offered for. The range is out of date for any other version.
+    version: i32,
+    range: Range,
+    action: FormattingCodeAction,
+}
+
+/// Creates a code action which edits the document to have the new contents, if it changes anything
+fn code_action_with_edit(
+    title: &str,
+    kind: CodeActionKind,
+    uri: &Uri,
+    document: &FullTextDocument,
+    client_options: ClientOptions,
+    new_contents: &str,
+) -> Option<CodeAction> {
+    let edits = compute_text_edits(
+        document,
+        client_options,
+        document.get_content(None),
+        new_contents,
+    );
+    if edits.is_empty() {
+        return None;
+    }
+
+    Some(CodeAction {
+        title: title.to_string(),
+        kind: Some(kind),
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri.clone(), edits)])),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Computes the code actions available for the selection within the document.
+/// Only the kinds of action requested by the client are computed. If the client supports resolving code actions,
+/// actions which need the document to be formatted are returned without their edit, which is computed once resolved.
+fn compute_code_actions(
+    params: &CodeActionParams,
+    document: &FullTextDocument,
+    config: Config,
+    client_options: ClientOptions,
+    verification: OutputVerification,
+) -> Vec<CodeActionOrCommand> {
+    let uri = &params.text_document.uri;
+    let contents = document.get_content(None);
+
+    let position_encoding = client_options.position_encoding;
+    let start = position_encoding.offset_at(document, params.range.start);
+    let end = position_encoding.offset_at(document, params.range.end);
+
+    let is_requested = |kind: &CodeActionKind| match &params.context.only {
+        Some(only) => only
+            .iter()
+            .any(|requested| kind.as_str().starts_with(requested.as_str())),
+        None => true,
+    };
+
+    let mut actions = Vec::new();
+
+    let mut formatting_actions = Vec::new();
+    if start < end {
+        formatting_actions.push(FormattingCodeAction::FormatSelection);
+    }
+    formatting_actions.push(FormattingCodeAction::SortRequires);
+
+    for action in formatting_actions {
+        if !is_requested(&action.kind()) {
+            continue;
         }
 
-        let search_root = Some(self.find_config_root(uri));
-        let path = &uri.to_file_path();
+        if client_options.code_action_resolve {
+            // Whether sorting changes anything is only known once formatted, so it is offered whenever there are requires
+            if action == FormattingCodeAction::SortRequires && !contents.contains("require") {
+                continue;
+            }
+
+            actions.push(CodeAction {
+                title: action.title().to_string(),
+                kind: Some(action.kind()),
+                data: Some(
+                    serde_json::to_value(CodeActionData {
+                        uri: uri.clone(),
+                        version: document.version(),
+                        range: params.range,
+                        action,
+                    })
+                    .unwrap(),
+                ),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let Some(new_contents) = action.apply(contents, config, start, end, verification) else {
+            continue;
+        };
 
-        if stylua_ignore::path_is_stylua_ignored(
-            path,
-            self.search_parent_directories,
-            search_root.clone(),
-        )
-        .unwrap_or(false)
+        // Sorting requires also formats the file, so only offer it if it changes more than formatting would
+        if action == FormattingCodeAction::SortRequires
+            && format_code(contents, config, None, verification)
+                .is_ok_and(|formatted_contents| formatted_contents == new_contents)
         {
-            return Err(FormattingError::FileIsIgnored);
+            continue;
         }
 
-        let contents = document.get_content(None);
+        actions.extend(code_action_with_edit(
+            action.title(),
+            action.kind(),
+            uri,
+            document,
+            client_options,
+            &new_contents,
+        ));
+    }
 
-        let mut config = self
-            .config_resolver
-            .load_configuration_with_search_root(path, search_root)
-            .unwrap_or_default();
+    if is_requested(&CodeActionKind::REFACTOR_REWRITE) {
+        if let Some(ignored_contents) = ignore_statement(contents, config.syntax.into(), start) {
+            actions.extend(code_action_with_edit(
+                "Wrap statement in `-- stylua: ignore`",
+                CodeActionKind::REFACTOR_REWRITE,
+                uri,
+                document,
+                client_options,
+                &ignored_contents,
+            ));
+        }
 
-        if let Some(formatting_options) = formatting_options {
-            config.indent_width = formatting_options
-                .tab_size
-                .try_into()
-                .expect("u32 fits into usize");
-            config.indent_type = if formatting_options.insert_spaces {
-                IndentType::Spaces
-            } else {
-                IndentType::Tabs
-            };
+        if start < end {
+            actions.extend(code_action_with_edit(
+                "Add `-- stylua: ignore start/end` around selection",
+                CodeActionKind::REFACTOR_REWRITE,
+                uri,
+                document,
+                client_options,
+                &ignore_lines(contents, start, end),
+            ));
         }
+    }
 
-        let Ok(formatted_contents) = format_code(contents, config, range, OutputVerification::None)
-        else {
-            return Err(FormattingError::StyLuaError);
-        };
+    actions
+        .into_iter()
+        .map(CodeActionOrCommand::CodeAction)
+        .collect()
+}
+
+/// Computes the edit of a code action returned without one
+fn resolve_code_action(
+    mut action: CodeAction,
+    data: CodeActionData,
+    document: &FullTextDocument,
+    config: Config,
+    client_options: ClientOptions,
+    verification: OutputVerification,
+) -> CodeAction {
+    let contents = document.get_content(None);
+    let position_encoding = client_options.position_encoding;
+    let start = position_encoding.offset_at(document, data.range.start);
+    let end = position_encoding.offset_at(document, data.range.end);
+
+    let edits = data
+        .action
+        .apply(contents, config, start, end, verification)
+        .map(|new_contents| compute_text_edits(document, client_options, contents, &new_contents))
+        .unwrap_or_default();
+    action.edit = Some(WorkspaceEdit {
+        changes: Some(HashMap::from([(data.uri, edits)])),
+        ..Default::default()
+    });
+    action
+}
 
-        let operations = TextDiff::from_chars(contents, &formatted_contents).grouped_ops(0);
+/// Which parts of a document to format
+enum FormattingRanges {
+    /// Format within the given ranges, or the whole document if there are none
+    Ranges(Vec<Range>),
+    /// Format the statement completed by typing the character at the position, if there is one
+    OnType { position: Position, ch: String },
+}
 
-        let edits = operations
-            .into_iter()
-            .flat_map(|operations| {
-                operations.into_iter().filter_map(|op| {
-                    diffop_to_textedit(op, document, contents, &formatted_contents)
-                })
-            })
-            .collect();
-        Ok(edits)
+/// The requests which have not yet been responded to, and the document they are for (if any).
+/// Whoever removes a request from here is responsible for responding to it, so each request is responded to exactly once.
+#[derive(Default)]
+struct PendingRequests {
+    requests: Mutex<HashMap<RequestId, Option<Uri>>>,
+    /// Notified whenever a request is removed
+    removed: Condvar,
+}
+
+impl PendingRequests {
+    fn insert(&self, id: RequestId, uri: Option<Uri>) {
+        self.requests.lock().unwrap().insert(id, uri);
     }
 
-    fn handle_request(&mut self, request: lsp_server::Request) -> Response {
-        match request.method.as_str() {
-            Formatting::METHOD => {
-                match serde_json::from_value::<DocumentFormattingParams>(request.params) {
-                    Ok(params) => {
-                        match self.handle_formatting(
-                            &params.text_document.uri,
-                            None,
-                            self.respect_editor_formatting_options
-                                .then_some(&params.options),
-                        ) {
-                            Ok(edits) => Response::new_ok(request.id, edits),
-                            Err(FormattingError::StyLuaError)
-                            | Err(FormattingError::NotLuaDocument)
-                            | Err(FormattingError::FileIsIgnored) => {
-                                Response::new_ok(request.id, serde_json::Value::Null)
-                            }
-                            Err(FormattingError::DocumentNotFound) => Response::new_err(
-                                request.id,
-                                ErrorCode::RequestFailed as i32,
-                                format!(
-                                    "no document found for '{}'",
-                                    params.text_document.uri.as_str()
-                                ),
-                            ),
-                        }
-                    }
-                    Err(err) => Response::new_err(
-                        request.id,
-                        lsp_server::ErrorCode::RequestFailed as i32,
-                        err.to_string(),
-                    ),
-                }
-            }
-            RangeFormatting::METHOD => {
-                match serde_json::from_value::<DocumentRangeFormattingParams>(request.params) {
-                    Ok(params) => {
-                        match self.handle_formatting(
-                            &params.text_document.uri,
-                            Some(params.range),
-                            self.respect_editor_formatting_options
-                                .then_some(&params.options),
-                        ) {
-                            Ok(edits) => Response::new_ok(request.id, edits),
-                            Err(FormattingError::StyLuaError)
-                            | Err(FormattingError::NotLuaDocument)
-                            | Err(FormattingError::FileIsIgnored) => {
-                                Response::new_ok(request.id, serde_json::Value::Null)
-                            }
-                            Err(FormattingError::DocumentNotFound) => Response::new_err(
-                                request.id,
-                                ErrorCode::RequestFailed as i32,
-                                format!(
-                                    "no document found for '{}'",
-                                    params.text_document.uri.as_str()
-                                ),
-                            ),
-                        }
-                    }
-                    Err(err) => Response::new_err(
-                        request.id,
-                        lsp_server::ErrorCode::RequestFailed as i32,
-                        err.to_string(),
-                    ),
-                }
+    fn contains(&self, id: &RequestId) -> bool {
+        self.requests.lock().unwrap().contains_key(id)
+    }
+
+    /// Removes the request, sending the messages responding to it if it had not already been responded to.
+    /// The messages are sent whilst the request is still locked, so they are sent before anyone waiting on the removal continues.
+    fn respond(&self, id: &RequestId, sender: &Sender<Message>, messages: Vec<Message>) {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.remove(id).is_none() {
+            return;
+        }
+
+        for message in messages {
+            if sender.send(message).is_err() {
+                error!("could not send response, the connection has closed");
+                break;
             }
-            _ => Response::new_err(
-                request.id,
-                lsp_server::ErrorCode::MethodNotFound as i32,
-                format!("server does not support method '{}'", request.method),
-            ),
         }
+        self.removed.notify_all();
     }
 
-    fn handle_notification(&mut self, notification: lsp_server::Notification) {
-        self.documents
-            .listen(notification.method.as_str(), &notification.params);
+    /// Removes the request, returning whether it had not already been responded to
+    fn remove(&self, id: &RequestId) -> bool {
+        let removed = self.requests.lock().unwrap().remove(id).is_some();
+        self.removed.notify_all();
+        removed
     }
-}
 
-#[derive(Serialize, Deserialize, Default)]
-#[serde(default)]
-struct InitializationOptions {
-    respect_editor_formatting_options: Option<bool>,
+    /// The requests for the document
+    fn for_document(&self, uri: &Uri) -> Vec<RequestId> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, request_uri)| request_uri.as_ref() == Some(uri))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Waits until every request has been responded to
+    fn wait_until_empty(&self) {
+        let requests = self.requests.lock().unwrap();
+        let _requests = self
+            .removed
+            .wait_while(requests, |requests| !requests.is_empty())
+            .unwrap();
+    }
 }
 
-fn main_loop<'a>(
-    connection: Connection,
-    search_parent_directories: bool,
-    config_resolver: &'a mut ConfigResolver<'a>,
-) -> anyhow::Result<()> {
-    let initialize_result = InitializeResult {
-        capabilities: ServerCapabilities {
-            document_range_formatting_provider: Some(OneOf::Left(true)),
-            document_formatting_provider: Some(OneOf::Left(true)),
-            text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                TextDocumentSyncKind::INCREMENTAL,
-            )),
-            ..Default::default()
-        },
-        server_info: Some(ServerInfo {
-            name: env!("CARGO_PKG_NAME").to_string(),
-            version: Some(env!("CARGO_PKG_VERSION").to_string()),
-        }),
-    };
+/// Runs slow work, such as formatting documents or computing diagnostics, on a pool of worker threads,
+/// so that it does not block other messages. The workers send their results to the client directly once they finish.
+struct FormattingWorkers {
+    pool: ThreadPool,
+    sender: Sender<Message>,
+    client_options: ClientOptions,
+    pending_requests: Arc<PendingRequests>,
+    /// The version of each document which diagnostics are being published for.
+    /// Diagnostics computed for any other version are out of date, so are never published.
+    diagnostics_versions: Arc<Mutex<HashMap<Uri, i32>>>,
+}
 
-    let (id, initialize_params) = connection.initialize_start()?;
+impl FormattingWorkers {
+    fn new(num_threads: usize, sender: Sender<Message>, client_options: ClientOptions) -> Self {
+        Self {
+            pool: ThreadPool::new(num_threads),
+            sender,
+            client_options,
+            pending_requests: Arc::new(PendingRequests::default()),
+            diagnostics_versions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
 
-    let initialize_params = serde_json::from_value::<InitializeParams>(initialize_params)?;
-    let respect_editor_formatting_options = initialize_params
-        .initialization_options
-        .and_then(|opt| serde_json::from_value::<InitializationOptions>(opt).ok())
-        .and_then(|opt| opt.respect_editor_formatting_options)
-        .unwrap_or_default();
+    /// Queues work for the request, sending the messages it returns (which include the response) once it finishes.
+    /// If the request is for a document, it is cancelled whenever the document changes, as the result would be out of date.
+    /// If the work panics, the request is answered with an error, so that neither the client nor shutdown waits on it forever.
+    fn respond(
+        &self,
+        id: RequestId,
+        uri: Option<&Uri>,
+        work: impl FnOnce(RequestId) -> Vec<Message> + Send + 'static,
+    ) {
+        self.pending_requests.insert(id.clone(), uri.cloned());
+        let pending_requests = Arc::clone(&self.pending_requests);
+        let sender = self.sender.clone();
+
+        self.pool.execute(move || {
+            // Skip the work if the request was cancelled whilst it was queued
+            if !pending_requests.contains(&id) {
+                return;
+            }
 
-    connection.initialize_finish(id, serde_json::to_value(initialize_result)?)?;
+            let messages = match panic::catch_unwind(AssertUnwindSafe(|| work(id.clone()))) {
+                Ok(messages) => messages,
+                Err(payload) => {
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .copied()
+                        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("unknown error");
+                    error!("request {} panicked: {}", id, message);
+                    vec![Message::Response(Response::new_err(
+                        id.clone(),
+                        ErrorCode::InternalError as i32,
+                        format!("stylua panicked whilst handling the request: {message}"),
+                    ))]
+                }
+            };
+            pending_requests.respond(&id, &sender, messages);
+        });
+    }
 
-    let mut language_server = LanguageServer::new(
-        initialize_params.workspace_folders.unwrap_or_default(),
-        #[allow(deprecated)]
-        initialize_params.root_uri,
-        search_parent_directories,
-        respect_editor_formatting_options,
-        config_resolver,
-    );
+    /// Queues the document to be formatted, responding to the request with the edits to apply
+    fn format(
+        &self,
+        id: RequestId,
+        uri: &Uri,
+        document: FullTextDocument,
+        config: Config,
+        ranges: FormattingRanges,
+        verification: OutputVerification,
+    ) {
+        let client_options = self.client_options;
+        let document_uri = uri.clone();
+
+        self.respond(id, Some(uri), move |id| {
+            let ranges = match ranges {
+                FormattingRanges::Ranges(ranges) => ranges,
+                FormattingRanges::OnType { position, ch } => match find_on_type_formatting_range(
+                    &document,
+                    config,
+                    client_options.position_encoding,
+                    position,
+                    &ch,
+                ) {
+                    Some(range) => vec![range],
+                    None => {
+                        return vec![Message::Response(Response::new_ok(
+                            id,
+                            serde_json::Value::Null,
+                        ))]
+                    }
+                },
+            };
 
-    for msg in &connection.receiver {
-        match msg {
-            Message::Request(req) => {
-                if connection.handle_shutdown(&req)? {
-                    break;
-                }
+            match format_document(&document, client_options, config, &ranges, verification) {
+                Ok(edits) => vec![Message::Response(Response::new_ok(id, edits))],
+                Err(error) => client_options
+                    .failure_reporting
+                    .message(
+                        MessageType::ERROR,
+                        format!("could not format {}: {}", document_uri.as_str(), error),
+                    )
+                    .into_iter()
+                    .chain(std::iter::once(Message::Response(Response::new_ok(
+                        id,
+                        serde_json::Value::Null,
+                    ))))
+                    .collect(),
+            }
+        });
+    }
 
-                let response = language_server.handle_request(req);
-                connection.sender.send(Message::Response(response))?
+    /// Queues the diagnostics for the document to be computed, publishing them once they are ready.
+    /// They are not published if the document changes in the meantime, as newer diagnostics will replace them.
+    fn publish_diagnostics(&self, uri: Uri, document: FullTextDocument, config: Config) {
+        let version = document.version();
+        self.diagnostics_versions
+            .lock()
+            .unwrap()
+            .insert(uri.clone(), version);
+        let diagnostics_versions = Arc::clone(&self.diagnostics_versions);
+        let sender = self.sender.clone();
+        let position_encoding = self.client_options.position_encoding;
+
+        self.pool.execute(move || {
+            let is_latest =
+                |versions: &HashMap<Uri, i32>| versions.get(&uri).copied() == Some(version);
+            if !is_latest(&diagnostics_versions.lock().unwrap()) {
+                return;
             }
-            Message::Response(_) => {}
-            Message::Notification(notification) => {
-                language_server.handle_notification(notification)
+
+            let diagnostics = compute_diagnostics(&document, config, position_encoding);
+
+            // Hold the lock whilst publishing, so that the diagnostics cannot become out of date before they are sent
+            let versions = diagnostics_versions.lock().unwrap();
+            if is_latest(&versions) {
+                let notification = lsp_server::Notification::new(
+                    PublishDiagnostics::METHOD.to_string(),
+                    PublishDiagnosticsParams::new(uri, diagnostics, Some(version)),
+                );
+                if sender.send(Message::Notification(notification)).is_err() {
+                    error!("could not publish diagnostics, the connection has closed");
+                }
             }
-        }
+        });
     }
-    Ok(())
-}
 
-pub fn run(opt: opt::Opt) -> anyhow::Result<()> {
-    // Load the configuration
-    let opt_for_config_resolver = opt.clone();
-    let mut config_resolver = ConfigResolver::new(&opt_for_config_resolver)?;
+    /// Stops any diagnostics still being computed for the document from being published
+    fn cancel_diagnostics(&self, uri: &Uri) {
+        self.diagnostics_versions.lock().unwrap().remove(uri);
+    }
 
-    let (connection, io_threads) = Connection::stdio();
+    /// Cancels the pending request, creating an error response for it.
+    /// Returns `None` if the request has already been responded to.
+    fn cancel(&self, id: &RequestId, code: ErrorCode, message: &str) -> Option<Message> {
+        if !self.pending_requests.remove(id) {
+            return None;
+        }
 
-    main_loop(
-        connection,
-        opt.search_parent_directories,
-        &mut config_resolver,
-    )?;
+        Some(Message::Response(Response::new_err(
+            id.clone(),
+            code as i32,
+            message.to_string(),
+        )))
+    }
 
-    io_threads.join()?;
+    /// Cancels every pending request for the document, as their results would no longer apply
+    fn cancel_document(&self, uri: &Uri) -> Vec<Message> {
+        self.pending_requests
+            .for_document(uri)
+            .iter()
+            .filter_map(|id| {
+                self.cancel(
+                    id,
+                    ErrorCode::ContentModified,
+                    "document was modified before the request finished",
+                )
+            })
+            .collect()
+    }
 
-    Ok(())
+    /// Waits for every pending request to be responded to.
+    /// Diagnostics which have not been published yet are dropped, as there is no need to wait for them.
+    fn join(&self) {
+        self.diagnostics_versions.lock().unwrap().clear();
+        self.pending_requests.wait_until_empty();
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::cmp::Ordering;
-    use std::convert::TryInto;
-    use std::path::{Path, PathBuf};
-    use std::str::FromStr;
-
-    use clap::Parser;
-    use crossbeam_channel::Receiver;
-
-    use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
-    use lsp_types::{
-        notification::{DidOpenTextDocument, Exit, Initialized, Notification as NotificationType},
-        request::{Formatting, Initialize, RangeFormatting, Request as RequestType, Shutdown},
-        DidOpenTextDocumentParams, DocumentFormattingParams, DocumentRangeFormattingParams,
-        FormattingOptions, InitializeParams, OneOf, Position, Range, ServerCapabilities,
-        ServerInfo, TextDocumentIdentifier, TextDocumentItem, TextDocumentSyncCapability,
-        TextDocumentSyncKind, TextEdit, Uri, WorkDoneProgressParams,
-    };
-    use serde::de::DeserializeOwned;
-    use serde_json::to_value;
-
-    use crate::{
-        config::ConfigResolver,
-        lsp::{main_loop, InitializationOptions},
-        opt::Opt,
-    };
-
-    use assert_fs::prelude::*;
-
-    macro_rules! construct_tree {
-        ({ $($file_name:literal:$file_contents:literal,)* }) => {{
-            let cwd = assert_fs::TempDir::new().unwrap();
-
-            $(
-                cwd.child($file_name).write_str($file_contents).unwrap();
-            )*
+struct LanguageServer<'a> {
+    documents: TextDocuments,
+    workspace_folders: Vec<WorkspaceFolder>,
+    root_uri: Option<Uri>,
+    opt: &'a opt::Opt,
+    client_options: ClientOptions,
+    settings: Settings,
+    config_resolver: &'a mut ConfigResolver<'a>,
+    workers: FormattingWorkers,
+    sender: Sender<Message>,
+    outgoing_requests: Arc<OutgoingRequests>,
+    /// Only the response to the latest request for settings is applied, as earlier ones may be out of date
+    latest_settings_request: Option<RequestId>,
+    /// The configuration files outside of the workspace globs which the client has been asked to watch
+    watched_config_files: HashSet<PathBuf>,
+}
 
-            cwd
-        }};
-        ({ $($file_name:literal:$file_contents:expr,)* }) => {{
-            let cwd = assert_fs::TempDir::new().unwrap();
+/// A request we sent to the client, which we are waiting on a response for
+enum OutgoingRequest {
+    Settings,
+    ApplyEdit,
+    RegisterWatchedFiles,
+}
 
-            $(
-                cwd.child($file_name).write_str($file_contents).unwrap();
-            )*
+/// The requests we sent to the client which have not been responded to yet.
+/// These are shared with the worker pool, which sends requests to apply the edits when formatting the workspace.
+#[derive(Default)]
+struct OutgoingRequests {
+    /// The id to give the next request
+    next_id: AtomicI32,
+    requests: Mutex<HashMap<RequestId, OutgoingRequest>>,
+}
 
-            cwd
-        }};
+impl OutgoingRequests {
+    fn next_id(&self) -> RequestId {
+        RequestId::from(self.next_id.fetch_add(1, Ordering::Relaxed))
     }
 
-    macro_rules! lsp_test {
-        ([$( $arguments:expr ),*], [$( $messages:expr ),*], [$( $tests:expr ),*]) => {
-            let opt = Opt::parse_from(vec!["BINARY_NAME", "--lsp", $($arguments)*]);
-            let mut config_resolver = ConfigResolver::new(&opt).unwrap();
-
-            let (server, client) = Connection::memory();
-            $(
-                client.sender.send($messages).unwrap();
-            )*
-
-            main_loop(server, false, &mut config_resolver).unwrap();
-
-            $(
-                $tests(&client.receiver);
-            )*
-        };
+    /// Creates a request to send to the client with the given id, tracking it until the client responds
+    fn create_with_id(
+        &self,
+        id: RequestId,
+        kind: OutgoingRequest,
+        method: &str,
+        params: impl Serialize,
+    ) -> lsp_server::Request {
+        self.requests.lock().unwrap().insert(id.clone(), kind);
+        lsp_server::Request::new(id, method.to_string(), params)
     }
 
-    fn initialize(id: i32, root_path: Option<&Path>) -> Message {
-        Message::Request(Request {
-            id: RequestId::from(id),
-            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
-            params: to_value(InitializeParams {
-                #[allow(deprecated)]
-                root_uri: root_path.map(|path| Uri::from_str(path.to_str().unwrap()).unwrap()),
-                ..Default::default()
-            })
-            .unwrap(),
-        })
+    /// Creates a request to send to the client, tracking it until the client responds
+    fn create(
+        &self,
+        kind: OutgoingRequest,
+        method: &str,
+        params: impl Serialize,
+    ) -> lsp_server::Request {
+        self.create_with_id(self.next_id(), kind, method, params)
     }
 
-    fn initialize_with_options(id: i32, options: InitializationOptions) -> Message {
-        Message::Request(Request {
-            id: RequestId::from(id),
-            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
-            params: to_value(InitializeParams {
-                initialization_options: Some(to_value(options).unwrap()),
-                ..Default::default()
-            })
-            .unwrap(),
-        })
+    /// Stops tracking the request once the client responds, returning what the request was for
+    fn remove(&self, id: &RequestId) -> Option<OutgoingRequest> {
+        self.requests.lock().unwrap().remove(id)
     }
+}
 
-    fn initialized() -> Message {
-        Message::Notification(Notification {
-            method: Initialized::METHOD.to_string(),
-            params: serde_json::Value::Null,
-        })
+enum FormattingError {
+    NotLuaDocument,
+    DocumentNotFound,
+    FileIsIgnored,
+    /// The configuration for the document could not be loaded, such as when a `stylua.toml` is invalid
+    Config(anyhow::Error),
+}
+
+impl LanguageServer<'_> {
+    fn new<'a>(
+        workspace_folders: Vec<WorkspaceFolder>,
+        root_uri: Option<Uri>,
+        opt: &'a opt::Opt,
+        client_options: ClientOptions,
+        config_resolver: &'a mut ConfigResolver<'a>,
+        workers: FormattingWorkers,
+        sender: Sender<Message>,
+    ) -> LanguageServer<'a> {
+        LanguageServer {
+            documents: TextDocuments::new(),
+            workspace_folders,
+            root_uri,
+            opt,
+            client_options,
+            settings: Settings::default(),
+            config_resolver,
+            workers,
+            sender,
+            outgoing_requests: Arc::new(OutgoingRequests::default()),
+            latest_settings_request: None,
+            watched_config_files: HashSet::new(),
+        }
     }
 
-    fn open_text_document(uri: Uri, text: String) -> Message {
-        Message::Notification(Notification {
-            method: DidOpenTextDocument::METHOD.to_string(),
-            params: to_value(DidOpenTextDocumentParams {
-                text_document: TextDocumentItem {
-                    uri,
-                    language_id: "lua".to_string(),
-                    version: 0,
-                    text,
-                },
-            })
-            .unwrap(),
-        })
+    /// Applies the `stylua` section of the client's settings.
+    /// `null` is sent when the section is not set, so the settings are reset.
+    fn apply_settings(&mut self, settings: serde_json::Value) {
+        let settings = if settings.is_null() {
+            Settings::default()
+        } else {
+            match serde_json::from_value::<Settings>(settings) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    error!("invalid settings: {}", err);
+                    return;
+                }
+            }
+        };
+
+        settings.apply_to(self.config_resolver, self.root_uri.as_ref());
+        self.settings = settings;
     }
 
-    fn format_document(id: i32, uri: Uri, options: FormattingOptions) -> Message {
-        Message::Request(Request {
-            id: RequestId::from(id),
-            method: Formatting::METHOD.to_string(),
-            params: to_value(DocumentFormattingParams {
-                text_document: TextDocumentIdentifier { uri },
-                options,
-                work_done_progress_params: WorkDoneProgressParams::default(),
-            })
-            .unwrap(),
-        })
+    fn search_parent_directories(&self) -> bool {
+        self.settings
+            .search_parent_directories
+            .unwrap_or(self.opt.search_parent_directories)
     }
 
-    fn shutdown(id: i32) -> Message {
-        Message::Request(Request {
-            id: RequestId::from(id),
-            method: Shutdown::METHOD.to_string(),
-            params: serde_json::Value::Null,
-        })
+    fn verification(&self) -> OutputVerification {
+        if self.settings.verify.unwrap_or_default() {
+            OutputVerification::Full
+        } else {
+            OutputVerification::None
+        }
     }
 
-    fn exit() -> Message {
-        Message::Notification(Notification {
-            method: Exit::METHOD.to_string(),
-            params: serde_json::Value::Null,
-        })
-    }
+    fn find_config_root(&self, uri: &Uri) -> PathBuf {
+        let mut best_workspace = None;
+        let mut best_len = 0;
+        let check_str = uri.as_str();
+        for workspace in &self.workspace_folders {
+            if *uri == workspace.uri {
+                return workspace.uri.to_file_path();
+            }
 
-    fn expect_server_initialized(receiver: &Receiver<Message>, response_id: i32) {
-        match receiver.recv().unwrap() {
-            Message::Response(Response {
-                id,
-                result: Some(result),
-                error: None,
-            }) if id == RequestId::from(response_id)
-                && result
-                    == serde_json::json!({
-                    "capabilities": ServerCapabilities {
-                        document_range_formatting_provider: Some(OneOf::Left(true)),
-                        document_formatting_provider: Some(OneOf::Left(true)),
-                        text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                            TextDocumentSyncKind::INCREMENTAL,
-                        )),
-                        ..Default::default()
-                    },
-                    "serverInfo": Some(ServerInfo {
-                        name: env!("CARGO_PKG_NAME").to_string(),
-                        version: Some(env!("CARGO_PKG_VERSION").to_string()),
-                    }),
-                    }) => {}
-            _ => panic!("assertion failed"),
-        }
-    }
+            let prefix_str = workspace.uri.as_str();
+            if prefix_str.len() < best_len {
+                continue;
+            }
 
-    fn expect_response<T: DeserializeOwned>(receiver: &Receiver<Message>, response_id: i32) -> T {
-        match receiver.recv().unwrap() {
-            Message::Response(Response {
-                id,
-                result: Some(result),
-                error: None,
-            }) if id == RequestId::from(response_id) => {
-                serde_json::from_value::<T>(result).unwrap()
+            if check_str.starts_with(prefix_str) {
+                best_workspace = Some(&workspace.uri);
+                best_len = prefix_str.len()
             }
-            _ => panic!("assertion failed"),
         }
-    }
 
-    fn expect_server_shutdown(receiver: &Receiver<Message>, response_id: i32) {
-        match receiver.recv().unwrap() {
-            Message::Response(Response {
-                id,
-                result: Some(result),
-                error: None,
-            }) if id == RequestId::from(response_id) && result == serde_json::Value::Null => {}
-            _ => panic!("assertion failed"),
+        match best_workspace {
+            Some(workspace) => workspace.to_file_path(),
+            None => match &self.root_uri {
+                Some(root_uri) => root_uri.to_file_path(),
+                None => std::env::current_dir().expect("Could not find current directory"),
+            },
         }
     }
 
-    #[test]
-    fn test_lsp_initialize() {
-        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
-        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
-
-        let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
-        client.sender.send(initialized()).unwrap();
-        client.sender.send(shutdown(2)).unwrap();
-        client.sender.send(exit()).unwrap();
-
-        main_loop(server, false, &mut config_resolver).unwrap();
-
-        expect_server_initialized(&client.receiver, 1);
-        expect_server_shutdown(&client.receiver, 2);
-        assert!(client.receiver.is_empty());
-    }
+    /// Loads the configuration to format the document with.
+    /// Fails if the document cannot be formatted, as it is not a Lua document, is ignored, or its configuration is invalid.
+    /// An invalid configuration is never replaced with the defaults, as that would format the document unexpectedly.
+    fn load_configuration(
+        &mut self,
+        uri: &Uri,
+        formatting_options: Option<&FormattingOptions>,
+    ) -> Result<Config, FormattingError> {
+        if self.documents.get_document(uri).is_none() {
+            return Err(FormattingError::DocumentNotFound);
+        }
 
-    fn apply_text_edits_to(text: &str, mut edits: Vec<TextEdit>) -> String {
-        edits.sort_by(|a, b| match a.range.start.line.cmp(&b.range.start.line) {
-            Ordering::Equal => a
-                .range
-                .start
-                .character
-                .cmp(&b.range.start.character)
-                .reverse(),
-            order => order.reverse(),
-        });
-        let mut text = text.to_string();
-        for edit in edits {
-            let start = text
-                .lines()
-                .take(edit.range.start.line.try_into().unwrap())
-                .map(|line| line.len() + '\n'.len_utf8())
-                .sum::<usize>()
-                + <u32 as TryInto<usize>>::try_into(edit.range.start.character).unwrap();
-            let end = text
-                .lines()
-                .take(edit.range.end.line.try_into().unwrap())
-                .map(|line| line.len() + '\n'.len_utf8())
-                .sum::<usize>()
-                + <u32 as TryInto<usize>>::try_into(edit.range.end.character).unwrap();
-            text.replace_range(start..end, &edit.new_text);
+        if !self.is_lua_document(uri) {
+            return Err(FormattingError::NotLuaDocument);
         }
-        text
-    }
 
-    #[test]
-    fn test_lsp_document_formatting() {
-        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
-        let contents = "local  x  =  1";
+        let search_root = Some(self.find_config_root(uri));
+        let path = &uri.to_file_path();
 
-        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
-        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+        if self.settings.respect_ignores.unwrap_or(true)
+            && stylua_ignore::path_is_stylua_ignored(
+                path,
+                self.search_parent_directories(),
+                search_root.clone(),
+            )
+            .unwrap_or(false)
+        {
+            return Err(FormattingError::FileIsIgnored);
+        }
 
-        let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
-        client.sender.send(initialized()).unwrap();
-        client
-            .sender
-            .send(open_text_document(uri.clone(), contents.to_string()))
-            .unwrap();
-        client
-            .sender
-            .send(format_document(
-                2,
-                uri.clone(),
-                FormattingOptions::default(),
-            ))
-            .unwrap();
-        client.sender.send(shutdown(3)).unwrap();
-        client.sender.send(exit()).unwrap();
+        let mut config = self
+            .config_resolver
+            .load_configuration_with_search_root(path, search_root)
+            .map_err(FormattingError::Config)?;
 
-        main_loop(server, false, &mut config_resolver).unwrap();
+        if let Some(formatting_options) = formatting_options {
+            config.indent_width = formatting_options
+                .tab_size
+                .try_into()
+                .expect("u32 fits into usize");
+            config.indent_type = if formatting_options.insert_spaces {
+                IndentType::Spaces
+            } else {
+                IndentType::Tabs
+            };
+        }
 
-        expect_server_initialized(&client.receiver, 1);
+        Ok(config)
+    }
 
-        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
-        assert_eq!(
-            edits,
-            [
-                TextEdit {
-                    range: Range::new(Position::new(0, 6), Position::new(0, 7)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(0, 8), Position::new(0, 9)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(0, 12), Position::new(0, 13)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(0, 14), Position::new(0, 14)),
-                    new_text: "\n".to_string()
-                },
-            ]
-        );
-        let formatted = apply_text_edits_to(contents, edits);
-        assert_eq!(formatted, "local x = 1\n");
+    /// Responds to a formatting request for a document which is left unformatted, reporting why
+    fn respond_without_formatting(
+        &self,
+        id: RequestId,
+        uri: &Uri,
+        message_type: MessageType,
+        reason: &str,
+    ) -> Response {
+        if let Some(message) = self.client_options.failure_reporting.message(
+            message_type,
+            format!("did not format {} as {}", uri.as_str(), reason),
+        ) {
+            if self.sender.send(message).is_err() {
+                error!("could not report formatting failure, the connection has closed");
+            }
+        }
 
-        expect_server_shutdown(&client.receiver, 3);
-        assert!(client.receiver.is_empty());
+        Response::new_ok(id, serde_json::Value::Null)
     }
 
-    #[test]
-    fn test_lsp_document_formatting_with_unicode() {
-        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
-        let contents = "local  x  =  1 -- 测试\nlocal    y   =2";
+    /// Takes a snapshot of the document to work on in the worker pool, as it may change in the meantime
+    fn snapshot_document(&self, uri: &Uri) -> Option<FullTextDocument> {
+        let document = self.documents.get_document(uri)?;
+        Some(FullTextDocument::new(
+            document.language_id().to_string(),
+            document.version(),
+            document.get_content(None).to_string(),
+        ))
+    }
 
-        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
-        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+    /// Formats the document on the worker pool, which responds with the edits to apply.
+    /// Returns the response straight away if the document cannot be formatted.
+    fn respond_with_formatting(
+        &mut self,
+        id: lsp_server::RequestId,
+        uri: &Uri,
+        ranges: FormattingRanges,
+        formatting_options: &FormattingOptions,
+    ) -> Option<Response> {
+        let config = match self.load_configuration(
+            uri,
+            self.client_options
+                .respect_editor_formatting_options
+                .then_some(formatting_options),
+        ) {
+            Ok(config) => config,
+            Err(FormattingError::NotLuaDocument) => {
+                return Some(self.respond_without_formatting(
+                    id,
+                    uri,
+                    MessageType::INFO,
+                    "it is not a Lua or Luau document",
+                ))
+            }
+            Err(FormattingError::FileIsIgnored) => {
+                return Some(self.respond_without_formatting(
+                    id,
+                    uri,
+                    MessageType::INFO,
+                    "it is ignored by a .styluaignore file",
+                ))
+            }
+            Err(FormattingError::Config(error)) => {
+                return Some(self.respond_without_formatting(
+                    id,
+                    uri,
+                    MessageType::ERROR,
+                    &format!("its configuration could not be loaded: {error:#}"),
+                ))
+            }
+            Err(FormattingError::DocumentNotFound) => {
+                return Some(Response::new_err(
+                    id,
+                    ErrorCode::RequestFailed as i32,
+                    format!("no document found for '{}'", uri.as_str()),
+                ))
+            }
+        };
 
-        let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
-        client.sender.send(initialized()).unwrap();
-        client
-            .sender
-            .send(open_text_document(uri.clone(), contents.to_string()))
-            .unwrap();
-        client
-            .sender
-            .send(format_document(
-                2,
-                uri.clone(),
-                FormattingOptions::default(),
-            ))
-            .unwrap();
-        client.sender.send(shutdown(3)).unwrap();
-        client.sender.send(exit()).unwrap();
+        let document = self
+            .snapshot_document(uri)
+            .expect("document was found when loading configuration");
+        self.workers
+            .format(id, uri, document, config, ranges, self.verification());
 
-        main_loop(server, false, &mut config_resolver).unwrap();
+        None
+    }
 
-        expect_server_initialized(&client.receiver, 1);
+    /// Loads the configuration for the document and takes a snapshot of it, to compute something for it on the worker pool.
+    /// Returns `None` if there is nothing to compute, such as when the document is ignored.
+    fn prepare_document(&mut self, uri: &Uri) -> Option<(FullTextDocument, Config)> {
+        let config = self.load_configuration(uri, None).ok()?;
+        Some((self.snapshot_document(uri)?, config))
+    }
 
-        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
-        assert_eq!(
-            edits,
-            [
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 0,
-                            character: 6
-                        },
-                        end: Position {
-                            line: 0,
-                            character: 7
-                        }
-                    },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 0,
-                            character: 8
-                        },
-                        end: Position {
-                            line: 0,
-                            character: 9
-                        }
-                    },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 0,
-                            character: 11
-                        },
-                        end: Position {
-                            line: 0,
-                            character: 12
-                        }
-                    },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 1,
-                            character: 5
-                        },
-                        end: Position {
-                            line: 1,
-                            character: 7
+    /// Handles a request from the client, returning the response.
+    /// Returns `None` if the request will be responded to once work on the worker pool finishes.
+    fn handle_request(&mut self, request: lsp_server::Request) -> Option<Response> {
+        let response = match request.method.as_str() {
+            Formatting::METHOD => {
+                match serde_json::from_value::<DocumentFormattingParams>(request.params) {
+                    Ok(params) => {
+                        return self.respond_with_formatting(
+                            request.id,
+                            &params.text_document.uri,
+                            FormattingRanges::Ranges(Vec::new()),
+                            &params.options,
+                        )
+                    }
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            RangeFormatting::METHOD => {
+                match serde_json::from_value::<DocumentRangeFormattingParams>(request.params) {
+                    Ok(params) => {
+                        return self.respond_with_formatting(
+                            request.id,
+                            &params.text_document.uri,
+                            FormattingRanges::Ranges(vec![params.range]),
+                            &params.options,
+                        )
+                    }
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            RangesFormatting::METHOD => {
+                match serde_json::from_value::<DocumentRangesFormattingParams>(request.params) {
+                    // An empty list of ranges would otherwise format the whole document
+                    Ok(params) if params.ranges.is_empty() => {
+                        Response::new_ok(request.id, Vec::<TextEdit>::new())
+                    }
+                    Ok(params) => {
+                        return self.respond_with_formatting(
+                            request.id,
+                            &params.text_document.uri,
+                            FormattingRanges::Ranges(params.ranges),
+                            &params.options,
+                        )
+                    }
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            OnTypeFormatting::METHOD => {
+                match serde_json::from_value::<DocumentOnTypeFormattingParams>(request.params) {
+                    Ok(params) => {
+                        let position = params.text_document_position;
+                        return self.respond_with_formatting(
+                            request.id,
+                            &position.text_document.uri,
+                            FormattingRanges::OnType {
+                                position: position.position,
+                                ch: params.ch,
+                            },
+                            &params.options,
+                        );
+                    }
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            CodeActionRequest::METHOD => {
+                match serde_json::from_value::<CodeActionParams>(request.params) {
+                    Ok(params) => match self.prepare_document(&params.text_document.uri) {
+                        Some((document, config)) => {
+                            let client_options = self.client_options;
+                            let verification = self.verification();
+                            let uri = params.text_document.uri.clone();
+                            self.workers.respond(request.id, Some(&uri), move |id| {
+                                let actions = compute_code_actions(
+                                    &params,
+                                    &document,
+                                    config,
+                                    client_options,
+                                    verification,
+                                );
+                                vec![Message::Response(Response::new_ok(id, actions))]
+                            });
+                            return None;
                         }
+                        None => Response::new_ok(request.id, Vec::<CodeActionOrCommand>::new()),
                     },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 1,
-                            character: 8
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            CodeActionResolveRequest::METHOD => {
+                match serde_json::from_value::<CodeAction>(request.params) {
+                    Ok(action) => match action
+                        .data
+                        .clone()
+                        .and_then(|data| serde_json::from_value::<CodeActionData>(data).ok())
+                    {
+                        Some(data) => match self.prepare_document(&data.uri) {
+                            Some((document, config)) if document.version() == data.version => {
+                                let client_options = self.client_options;
+                                let verification = self.verification();
+                                let uri = data.uri.clone();
+                                self.workers.respond(request.id, Some(&uri), move |id| {
+                                    let action = resolve_code_action(
+                                        action,
+                                        data,
+                                        &document,
+                                        config,
+                                        client_options,
+                                        verification,
+                                    );
+                                    vec![Message::Response(Response::new_ok(id, action))]
+                                });
+                                return None;
+                            }
+                            _ => Response::new_err(
+                                request.id,
+                                ErrorCode::ContentModified as i32,
+                                "the document has changed since the code action was offered"
+                                    .to_string(),
+                            ),
                         },
-                        end: Position {
-                            line: 1,
-                            character: 9
-                        }
+                        // Every other action already has its edit
+                        None => Response::new_ok(request.id, action),
                     },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 1,
-                            character: 10
-                        },
-                        end: Position {
-                            line: 1,
-                            character: 12
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            DocumentDiagnosticRequest::METHOD => {
+                match serde_json::from_value::<DocumentDiagnosticParams>(request.params) {
+                    Ok(params) => {
+                        let uri = params.text_document.uri;
+                        match self.prepare_document(&uri) {
+                            Some((document, config)) => {
+                                let position_encoding = self.client_options.position_encoding;
+                                self.workers.respond(request.id, Some(&uri), move |id| {
+                                    let diagnostics =
+                                        compute_diagnostics(&document, config, position_encoding);
+                                    vec![Message::Response(Response::new_ok(
+                                        id,
+                                        diagnostic_report(diagnostics),
+                                    ))]
+                                });
+                                return None;
+                            }
+                            None => Response::new_ok(request.id, diagnostic_report(Vec::new())),
                         }
-                    },
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 1,
-                            character: 14
+                    }
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            ExecuteCommand::METHOD => {
+                match serde_json::from_value::<ExecuteCommandParams>(request.params) {
+                    Ok(params) => return self.execute_command(request.id, &params.command),
+                    Err(err) => Response::new_err(
+                        request.id,
+                        lsp_server::ErrorCode::RequestFailed as i32,
+                        err.to_string(),
+                    ),
+                }
+            }
+            _ => Response::new_err(
+                request.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("server does not support method '{}'", request.method),
+            ),
+        };
+
+        Some(response)
+    }
+
+    /// Runs a command over the workspace on the worker pool, which responds with a summary of the files in the workspace.
+    /// Returns the response straight away if the command is unknown.
+    fn execute_command(&mut self, id: RequestId, command: &str) -> Option<Response> {
+        let apply_edits = match command {
+            FORMAT_WORKSPACE_COMMAND => true,
+            CHECK_WORKSPACE_COMMAND => false,
+            _ => {
+                return Some(Response::new_err(
+                    id,
+                    ErrorCode::InvalidParams as i32,
+                    format!("unknown command '{}'", command),
+                ))
+            }
+        };
+
+        let workspace = self.snapshot_workspace();
+        let client_options = self.client_options;
+        let verification = self.verification();
+        let outgoing_requests = Arc::clone(&self.outgoing_requests);
+        self.workers.respond(id, None, move |id| {
+            let files = match workspace.files() {
+                Ok(files) => files,
+                Err(err) => {
+                    return vec![Message::Response(Response::new_err(
+                        id,
+                        ErrorCode::RequestFailed as i32,
+                        format!("{:#}", err),
+                    ))]
+                }
+            };
+            let (summary, changes) =
+                format_workspace(files, client_options, verification, apply_edits);
+
+            let mut messages = Vec::new();
+            if !changes.is_empty() {
+                messages.push(Message::Request(outgoing_requests.create(
+                    OutgoingRequest::ApplyEdit,
+                    ApplyWorkspaceEdit::METHOD,
+                    ApplyWorkspaceEditParams {
+                        label: Some(String::from("Format workspace")),
+                        edit: WorkspaceEdit {
+                            changes: Some(changes.into_iter().collect()),
+                            ..Default::default()
                         },
-                        end: Position {
-                            line: 1,
-                            character: 14
-                        }
                     },
-                    new_text: " ".to_string()
-                },
-                TextEdit {
-                    range: Range {
-                        start: Position {
-                            line: 1,
-                            character: 15
-                        },
-                        end: Position {
-                            line: 1,
-                            character: 15
+                )));
+            }
+            messages.push(Message::Response(Response::new_ok(id, summary)));
+            messages
+        });
+
+        None
+    }
+
+    /// The directories to search for Lua files when running a command over the workspace
+    fn workspace_roots(&self) -> Vec<PathBuf> {
+        if !self.workspace_folders.is_empty() {
+            return self
+                .workspace_folders
+                .iter()
+                .map(|folder| folder.uri.to_file_path())
+                .collect();
+        }
+
+        vec![match &self.root_uri {
+            Some(root_uri) => root_uri.to_file_path(),
+            None => std::env::current_dir().expect("Could not find current directory"),
+        }]
+    }
+
+    /// Takes a snapshot of everything needed to find the files in the workspace, so they can be found on the worker pool
+    fn snapshot_workspace(&self) -> WorkspaceSnapshot {
+        WorkspaceSnapshot {
+            roots: self.workspace_roots(),
+            opt: self.opt.clone(),
+            settings: self.settings.clone(),
+            root_uri: self.root_uri.clone(),
+            unsaved_config_files: self.config_resolver.unsaved_config_files().clone(),
+            open_documents: self
+                .documents
+                .documents()
+                .keys()
+                .filter_map(|uri| {
+                    let document = self.snapshot_document(uri)?;
+                    Some((uri.to_file_path(), (uri.clone(), document)))
+                })
+                .collect(),
+        }
+    }
+
+    /// Handles a notification from the client, returning any messages to send back in response
+    fn handle_notification(&mut self, notification: lsp_server::Notification) -> Vec<Message> {
+        if notification.method == DidChangeTextDocument::METHOD
+            && self.client_options.position_encoding == PositionEncoding::Utf8
+        {
+            self.apply_utf8_changes(&notification.params);
+        } else {
+            self.documents
+                .listen(notification.method.as_str(), &notification.params);
+        }
+
+        match notification.method.as_str() {
+            DidChangeConfiguration::METHOD => {
+                // Clients which support pulling settings may not send them with the notification
+                if self.client_options.workspace_configuration {
+                    return vec![Message::Request(self.request_settings())];
+                }
+
+                let Ok(params) =
+                    serde_json::from_value::<DidChangeConfigurationParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                self.apply_settings(params.settings["stylua"].clone());
+                Vec::new()
+            }
+            Cancel::METHOD => {
+                let Ok(params) = serde_json::from_value::<CancelParams>(notification.params) else {
+                    return Vec::new();
+                };
+
+                let id = match params.id {
+                    NumberOrString::Number(id) => RequestId::from(id),
+                    NumberOrString::String(id) => RequestId::from(id),
+                };
+                self.workers
+                    .cancel(&id, ErrorCode::RequestCanceled, "request was cancelled")
+                    .into_iter()
+                    .collect()
+            }
+            DidChangeWatchedFiles::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<DidChangeWatchedFilesParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+
+                for change in params.changes {
+                    let path = change.uri.to_file_path();
+                    if config::is_config_file(&path) || self.watched_config_files.contains(&path) {
+                        if let Err(err) = self.config_resolver.invalidate_config_file(&path) {
+                            error!("{:#}", err);
                         }
-                    },
-                    new_text: "\n".to_string()
+                    }
+                }
+
+                Vec::new()
+            }
+            DidChangeWorkspaceFolders::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<DidChangeWorkspaceFoldersParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+
+                let event = params.event;
+                self.workspace_folders.retain(|folder| {
+                    !event
+                        .removed
+                        .iter()
+                        .any(|removed| removed.uri == folder.uri)
+                });
+                for added in &event.added {
+                    if !self
+                        .workspace_folders
+                        .iter()
+                        .any(|folder| folder.uri == added.uri)
+                    {
+                        self.workspace_folders.push(added.clone());
+                    }
+                }
+
+                // Configuration cached within a changed folder may have been resolved against a different root
+                for folder in event.removed.iter().chain(&event.added) {
+                    self.config_resolver
+                        .invalidate_directory(&folder.uri.to_file_path());
+                }
+
+                Vec::new()
+            }
+            // Track the unsaved contents of any open configuration files, so they apply straight away
+            DidOpenTextDocument::METHOD | DidChangeTextDocument::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<TextDocumentNotificationParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+
+                // Any formatting in progress for the document is now outdated
+                let mut messages = self.workers.cancel_document(&params.text_document.uri);
+
+                let path = params.text_document.uri.to_file_path();
+                if config::is_config_file(&path) {
+                    let contents = self
+                        .documents
+                        .get_document_content(&params.text_document.uri, None)
+                        .map(str::to_string);
+                    if let Err(err) = self
+                        .config_resolver
+                        .set_unsaved_config_file(&path, contents)
+                    {
+                        error!("{:#}", err);
+                    }
+                }
+
+                if self.is_lua_document(&params.text_document.uri) {
+                    messages.extend(self.publish_diagnostics(params.text_document.uri));
+                }
+                messages
+            }
+            DidCloseTextDocument::METHOD => {
+                let Ok(params) =
+                    serde_json::from_value::<TextDocumentNotificationParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+
+                let mut messages = self.workers.cancel_document(&params.text_document.uri);
+
+                let path = params.text_document.uri.to_file_path();
+                if config::is_config_file(&path) {
+                    if let Err(err) = self.config_resolver.set_unsaved_config_file(&path, None) {
+                        error!("{:#}", err);
+                    }
+                }
+
+                // Clear any diagnostics we previously published, as the document is no longer tracked
+                messages.extend(self.publish_diagnostics(params.text_document.uri));
+                messages
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Requests the client to notify us whenever a configuration file changes, so that we do not use stale configuration.
+    /// Besides the `globs`, any configuration file we have loaded which they do not cover, such as a file which is extended
+    /// or the global configuration file, is watched individually. Returns `None` if there is nothing new to watch.
+    /// `.editorconfig` and `.styluaignore` files are read whenever they are needed, so they do not need to be watched.
+    fn register_watched_files(&mut self, globs: &[&str]) -> Option<lsp_server::Request> {
+        if !self.client_options.watched_files_registration {
+            return None;
+        }
+
+        let workspace_roots = self.workspace_roots();
+        let mut watchers = globs
+            .iter()
+            .map(|glob| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(glob.to_string()),
+                kind: None,
+            })
+            .collect::<Vec<_>>();
+
+        for path in self.config_resolver.config_files() {
+            let covered_by_globs = config::is_config_file(&path)
+                && workspace_roots.iter().any(|root| path.starts_with(root));
+            if covered_by_globs || !self.watched_config_files.insert(path.clone()) {
+                continue;
+            }
+
+            let relative_pattern = match (path.parent(), path.file_name()) {
+                (Some(directory), Some(file_name))
+                    if self.client_options.relative_watch_patterns =>
+                {
+                    file_path_to_uri(directory).map(|base_uri| RelativePattern {
+                        base_uri: OneOf::Right(base_uri),
+                        pattern: file_name.to_string_lossy().into_owned(),
+                    })
+                }
+                _ => None,
+            };
+            watchers.push(FileSystemWatcher {
+                glob_pattern: match relative_pattern {
+                    Some(relative_pattern) => GlobPattern::Relative(relative_pattern),
+                    None => GlobPattern::String(path.to_string_lossy().into_owned()),
+                },
+                kind: None,
+            });
+        }
+
+        if watchers.is_empty() {
+            return None;
+        }
+
+        // Each registration adds to the files being watched, so needs its own id
+        let id = self.outgoing_requests.next_id();
+        let registration_id = format!("{}/{}", DidChangeWatchedFiles::METHOD, id);
+        let register_options =
+            serde_json::to_value(DidChangeWatchedFilesRegistrationOptions { watchers }).ok()?;
+        Some(self.outgoing_requests.create_with_id(
+            id,
+            OutgoingRequest::RegisterWatchedFiles,
+            RegisterCapability::METHOD,
+            RegistrationParams {
+                registrations: vec![Registration {
+                    id: registration_id,
+                    method: DidChangeWatchedFiles::METHOD.to_string(),
+                    register_options: Some(register_options),
+                }],
+            },
+        ))
+    }
+
+    /// Requests the `stylua` section of the client's settings
+    fn request_settings(&mut self) -> lsp_server::Request {
+        let request = self.outgoing_requests.create(
+            OutgoingRequest::Settings,
+            WorkspaceConfiguration::METHOD,
+            ConfigurationParams {
+                items: vec![ConfigurationItem {
+                    scope_uri: None,
+                    section: Some(String::from("stylua")),
+                }],
+            },
+        );
+        self.latest_settings_request = Some(request.id.clone());
+        request
+    }
+
+    /// Handles a response from the client to a request we sent
+    fn handle_response(&mut self, response: Response) {
+        let Some(request) = self.outgoing_requests.remove(&response.id) else {
+            return;
+        };
+
+        match request {
+            OutgoingRequest::Settings => {
+                if self.latest_settings_request.as_ref() != Some(&response.id) {
+                    return;
+                }
+
+                if let Some(error) = response.error {
+                    error!("could not fetch settings: {}", error.message);
+                    return;
+                }
+
+                let settings = response
+                    .result
+                    .and_then(|result| {
+                        serde_json::from_value::<Vec<serde_json::Value>>(result).ok()
+                    })
+                    .and_then(|items| items.into_iter().next())
+                    .unwrap_or_default();
+                self.apply_settings(settings);
+            }
+            OutgoingRequest::ApplyEdit => {
+                if let Some(error) = response.error {
+                    error!("could not apply workspace edit: {}", error.message);
+                } else if let Some(result) = response.result.and_then(|result| {
+                    serde_json::from_value::<ApplyWorkspaceEditResponse>(result).ok()
+                }) {
+                    if !result.applied {
+                        error!(
+                            "the client did not apply the workspace edit: {}",
+                            result.failure_reason.unwrap_or_default()
+                        );
+                    }
+                }
+            }
+            OutgoingRequest::RegisterWatchedFiles => {
+                if let Some(error) = response.error {
+                    error!("could not watch configuration files: {}", error.message);
                 }
+            }
+        }
+    }
+
+    /// `TextDocuments` only understands UTF-16 positions, so incremental changes given in UTF-8 are converted
+    /// and applied one at a time, as each change is relative to the contents after the previous change.
+    fn apply_utf8_changes(&mut self, params: &serde_json::Value) {
+        let Ok(params) = serde_json::from_value::<DidChangeTextDocumentParams>(params.clone())
+        else {
+            return;
+        };
+
+        for mut change in params.content_changes {
+            if let (Some(range), Some(document)) = (
+                change.range.as_mut(),
+                self.documents.get_document(&params.text_document.uri),
+            ) {
+                for position in [&mut range.start, &mut range.end] {
+                    let offset = PositionEncoding::Utf8.offset_at(document, *position);
+                    *position = PositionEncoding::Utf16.position_at(document, offset);
+                }
+            }
+
+            let params = DidChangeTextDocumentParams {
+                text_document: params.text_document.clone(),
+                content_changes: vec![change],
+            };
+            self.documents.listen(
+                DidChangeTextDocument::METHOD,
+                &serde_json::to_value(params).expect("change can be serialized"),
+            );
+        }
+    }
+
+    fn is_lua_document(&self, uri: &Uri) -> bool {
+        self.documents.get_document(uri).is_some_and(|document| {
+            document.language_id() == "lua" || document.language_id() == "luau"
+        })
+    }
+
+    /// Creates a notification publishing the current diagnostics of the document, if we push diagnostics to the client
+    fn publish_diagnostics(&mut self, uri: Uri) -> Option<Message> {
+        if !self.client_options.publish_diagnostics {
+            return None;
+        }
+
+        if let Some((document, config)) = self.prepare_document(&uri) {
+            self.workers.publish_diagnostics(uri, document, config);
+            return None;
+        }
+
+        // Clear any diagnostics we previously published, such as when the document is closed or is now ignored,
+        // making sure any still being computed are not published afterwards
+        self.workers.cancel_diagnostics(&uri);
+        Some(Message::Notification(lsp_server::Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams::new(uri, Vec::new(), None),
+        )))
+    }
+}
+
+/// The parameters common to every text document notification
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentNotificationParams {
+    text_document: TextDocumentIdentifier,
+}
+
+/// Configuration files anywhere in the workspace are always watched
+const WORKSPACE_CONFIG_GLOBS: [&str; 2] = ["**/stylua.toml", "**/.stylua.toml"];
+
+/// Formats every Lua file in the workspace, applying the edits through `workspace/applyEdit`
+const FORMAT_WORKSPACE_COMMAND: &str = "stylua.formatWorkspace";
+/// Checks whether every Lua file in the workspace is formatted, without changing anything
+const CHECK_WORKSPACE_COMMAND: &str = "stylua.checkWorkspace";
+
+/// Everything needed to find the Lua files in the workspace away from the main loop.
+/// The configuration resolver of the server cannot be shared with the worker pool, so a new one is created from the same options and settings.
+struct WorkspaceSnapshot {
+    /// The directories to search for Lua files
+    roots: Vec<PathBuf>,
+    opt: opt::Opt,
+    settings: Settings,
+    root_uri: Option<Uri>,
+    unsaved_config_files: HashMap<PathBuf, String>,
+    /// Open documents may have unsaved changes, so are used instead of the contents on disk
+    open_documents: HashMap<PathBuf, (Uri, FullTextDocument)>,
+}
+
+impl WorkspaceSnapshot {
+    /// Finds every Lua file in the workspace, in the same way as when formatting a directory from the command line,
+    /// along with the configuration to format it with
+    fn files(mut self) -> anyhow::Result<Vec<WorkspaceFile>> {
+        let mut config_resolver = ConfigResolver::new(&self.opt)?;
+        self.settings
+            .apply_to(&mut config_resolver, self.root_uri.as_ref());
+        for (path, contents) in self.unsaved_config_files {
+            if let Err(err) = config_resolver.set_unsaved_config_file(&path, Some(contents)) {
+                error!("{:#}", err);
+            }
+        }
+
+        let mut files = Vec::new();
+        let mut seen_files = HashSet::new();
+
+        for root in &self.roots {
+            let walker = match create_walker_builder(std::slice::from_ref(root), &self.opt, root) {
+                Ok(walker_builder) => walker_builder.build(),
+                Err(err) => {
+                    error!("{:#}", err);
+                    continue;
+                }
+            };
+
+            for entry in walker {
+                let path = match entry {
+                    Ok(entry) => entry.into_path(),
+                    Err(err) => {
+                        error!("{:#}", err);
+                        continue;
+                    }
+                };
+                if !path.is_file()
+                    || (self.opt.glob.is_none() && !matches_default_glob(&path))
+                    || !seen_files.insert(path.clone())
+                {
+                    continue;
+                }
+
+                let (uri, document) = match self.open_documents.remove(&path) {
+                    Some((uri, document)) => (uri, Some(document)),
+                    None => match file_path_to_uri(&path) {
+                        Some(uri) => (uri, None),
+                        None => continue,
+                    },
+                };
+
+                let config = config_resolver
+                    .load_configuration_with_search_root(&path, Some(root.clone()))
+                    .map_err(|err| format!("{:#}", err));
+                files.push(WorkspaceFile {
+                    uri,
+                    path,
+                    config,
+                    document,
+                });
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+/// A Lua file found when running a command over the workspace
+struct WorkspaceFile {
+    uri: Uri,
+    path: PathBuf,
+    /// The configuration to format the file with, or why it could not be loaded
+    config: Result<Config, String>,
+    /// A snapshot of the document if it is open, as it may have unsaved changes
+    document: Option<FullTextDocument>,
+}
+
+/// Formats every file found in the workspace.
+/// Returns a summary, along with the edits to apply to each file which is not formatted if `compute_edits` is set.
+fn format_workspace(
+    files: Vec<WorkspaceFile>,
+    client_options: ClientOptions,
+    verification: OutputVerification,
+    compute_edits: bool,
+) -> (WorkspaceSummary, Vec<(Uri, Vec<TextEdit>)>) {
+    let mut summary = WorkspaceSummary::default();
+    let mut changes = Vec::new();
+
+    for file in files {
+        let uri = file.uri;
+        let config = match file.config {
+            Ok(config) => config,
+            Err(message) => {
+                summary.errors.push(WorkspaceFileError { uri, message });
+                continue;
+            }
+        };
+
+        let document = match file.document {
+            Some(document) => document,
+            None => match fs::read_to_string(&file.path) {
+                Ok(contents) => FullTextDocument::new(String::from("lua"), 0, contents),
+                Err(err) => {
+                    summary.errors.push(WorkspaceFileError {
+                        uri,
+                        message: err.to_string(),
+                    });
+                    continue;
+                }
+            },
+        };
+        let contents = document.get_content(None);
+
+        summary.checked_files += 1;
+        match format_code(contents, config, None, verification) {
+            Ok(formatted_contents) if formatted_contents != contents => {
+                if compute_edits {
+                    changes.push((
+                        uri.clone(),
+                        compute_text_edits(
+                            &document,
+                            client_options,
+                            contents,
+                            &formatted_contents,
+                        ),
+                    ));
+                }
+                summary.unformatted_files.push(uri);
+            }
+            Ok(_) => (),
+            Err(err) => summary.errors.push(WorkspaceFileError {
+                uri,
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    (summary, changes)
+}
+
+/// The result of running a command over every Lua file in the workspace
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct WorkspaceSummary {
+    /// The number of files which were checked
+    checked_files: usize,
+    /// The files which are not formatted. When formatting the workspace, these are the files which were changed
+    unformatted_files: Vec<Uri>,
+    /// The files which could not be formatted, such as those which fail to parse
+    errors: Vec<WorkspaceFileError>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WorkspaceFileError {
+    uri: Uri,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct InitializationOptions {
+    respect_editor_formatting_options: Option<bool>,
+    line_granular_edits: Option<bool>,
+    report_formatting_failures: Option<FailureReporting>,
+}
+
+/// The `stylua` section of the client's settings, which may change whilst the server is running.
+/// Any settings which are not set fall back to the command line arguments.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+struct Settings {
+    /// Overrides `--search-parent-directories`
+    search_parent_directories: Option<bool>,
+    /// Overrides `--config-path`. A relative path is resolved against the workspace root
+    config_path: Option<PathBuf>,
+    /// Whether to verify the output of formatting, as with `--verify`
+    verify: Option<bool>,
+    /// Whether files ignored by `.styluaignore` are left unformatted, which defaults to `true`
+    respect_ignores: Option<bool>,
+    /// The configuration to use when no configuration file is found
+    fallback_config: Option<Config>,
+}
+
+impl Settings {
+    /// Applies the settings which affect how configuration is found to the resolver
+    fn apply_to(&self, config_resolver: &mut ConfigResolver, root_uri: Option<&Uri>) {
+        let config_path = self.config_path.as_ref().map(|config_path| match root_uri {
+            Some(root_uri) => root_uri.to_file_path().join(config_path),
+            None => config_path.to_path_buf(),
+        });
+        if let Err(err) = config_resolver.set_config_path(config_path.as_deref()) {
+            error!("{:#}", err);
+        }
+        config_resolver.set_search_parent_directories(self.search_parent_directories);
+        config_resolver.set_default_configuration(self.fallback_config);
+    }
+}
+
+fn initialize_result(position_encoding: PositionEncoding) -> anyhow::Result<serde_json::Value> {
+    let initialize_result = InitializeResult {
+        capabilities: ServerCapabilities {
+            // UTF-16 is the default, so is only advertised implicitly
+            position_encoding: (position_encoding == PositionEncoding::Utf8)
+                .then_some(PositionEncodingKind::UTF8),
+            document_range_formatting_provider: Some(OneOf::Left(true)),
+            document_formatting_provider: Some(OneOf::Left(true)),
+            diagnostic_provider: Some(DiagnosticServerCapabilities::Options(DiagnosticOptions {
+                identifier: Some(String::from("stylua")),
+                inter_file_dependencies: false,
+                workspace_diagnostics: false,
+                ..Default::default()
+            })),
+            document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                first_trigger_character: String::from("}"),
+                more_trigger_character: Some(vec![
+                    String::from(")"),
+                    String::from("d"),
+                    String::from("\n"),
+                ]),
+            }),
+            code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+                code_action_kinds: Some(vec![
+                    CodeActionKind::REFACTOR_REWRITE,
+                    CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+                ]),
+                resolve_provider: Some(true),
+                ..Default::default()
+            })),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: vec![
+                    String::from(FORMAT_WORKSPACE_COMMAND),
+                    String::from(CHECK_WORKSPACE_COMMAND),
+                ],
+                ..Default::default()
+            }),
+            text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                TextDocumentSyncKind::INCREMENTAL,
+            )),
+            workspace: Some(WorkspaceServerCapabilities {
+                workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                    supported: Some(true),
+                    change_notifications: Some(OneOf::Left(true)),
+                }),
+                file_operations: None,
+            }),
+            ..Default::default()
+        },
+        server_info: Some(ServerInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        }),
+    };
+
+    // `lsp_types` does not yet support `rangesSupport`, so it is added to the capabilities manually
+    let mut initialize_result = serde_json::to_value(initialize_result)?;
+    initialize_result["capabilities"]["documentRangeFormattingProvider"] =
+        serde_json::json!({ "rangesSupport": true });
+
+    Ok(initialize_result)
+}
+
+fn main_loop<'a>(
+    connection: Connection,
+    opt: &'a opt::Opt,
+    num_threads: usize,
+    config_resolver: &'a mut ConfigResolver<'a>,
+) -> anyhow::Result<()> {
+    let (id, initialize_params) = connection.initialize_start()?;
+
+    let initialize_params = serde_json::from_value::<InitializeParams>(initialize_params)?;
+    let client_options = ClientOptions::new(&initialize_params);
+
+    connection.initialize_finish(id, initialize_result(client_options.position_encoding)?)?;
+
+    let mut language_server = LanguageServer::new(
+        initialize_params.workspace_folders.unwrap_or_default(),
+        #[allow(deprecated)]
+        initialize_params.root_uri,
+        opt,
+        client_options,
+        config_resolver,
+        FormattingWorkers::new(num_threads, connection.sender.clone(), client_options),
+        connection.sender.clone(),
+    );
+
+    if let Some(request) = language_server.register_watched_files(&WORKSPACE_CONFIG_GLOBS) {
+        connection.sender.send(Message::Request(request))?;
+    }
+    if client_options.workspace_configuration {
+        connection
+            .sender
+            .send(Message::Request(language_server.request_settings()))?;
+    }
+
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                // Respond to any requests still in progress before shutting down
+                if req.method == Shutdown::METHOD {
+                    language_server.workers.join();
+                }
+
+                if connection.handle_shutdown(&req)? {
+                    break;
+                }
+
+                if let Some(response) = language_server.handle_request(req) {
+                    connection.sender.send(Message::Response(response))?
+                }
+            }
+            Message::Response(response) => language_server.handle_response(response),
+            Message::Notification(notification) => {
+                for message in language_server.handle_notification(notification) {
+                    connection.sender.send(message)?
+                }
+            }
+        }
+
+        // Handling the message may have loaded configuration from files we are not yet watching
+        if let Some(request) = language_server.register_watched_files(&[]) {
+            connection.sender.send(Message::Request(request))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(opt: opt::Opt) -> anyhow::Result<()> {
+    // Load the configuration
+    let opt_for_config_resolver = opt.clone();
+    let mut config_resolver = ConfigResolver::new(&opt_for_config_resolver)?;
+
+    let (connection, io_threads) = Connection::stdio();
+
+    main_loop(
+        connection,
+        &opt,
+        std::cmp::max(opt.num_threads, 1),
+        &mut config_resolver,
+    )?;
+
+    io_threads.join()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::convert::TryInto;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    use clap::Parser;
+    use crossbeam_channel::Receiver;
+    use full_moon::LuaVersion;
+
+    use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
+    use lsp_textdocument::FullTextDocument;
+    use lsp_types::{
+        notification::{
+            Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
+            DidChangeWorkspaceFolders, DidCloseTextDocument, DidOpenTextDocument, Exit,
+            Initialized, LogMessage, Notification as NotificationType, PublishDiagnostics,
+            ShowMessage,
+        },
+        request::{
+            ApplyWorkspaceEdit, CodeActionRequest, CodeActionResolveRequest,
+            DocumentDiagnosticRequest, ExecuteCommand, Formatting, Initialize, OnTypeFormatting,
+            RangeFormatting, RegisterCapability, Request as RequestType, Shutdown,
+            WorkspaceConfiguration,
+        },
+        ApplyWorkspaceEditParams, CancelParams, ClientCapabilities, CodeAction,
+        CodeActionCapabilityResolveSupport, CodeActionClientCapabilities, CodeActionContext,
+        CodeActionKind, CodeActionOptions, CodeActionOrCommand, CodeActionParams,
+        CodeActionProviderCapability, ConfigurationParams, Diagnostic, DiagnosticOptions,
+        DiagnosticServerCapabilities, DiagnosticSeverity, DidChangeConfigurationParams,
+        DidChangeTextDocumentParams, DidChangeWatchedFilesClientCapabilities,
+        DidChangeWatchedFilesRegistrationOptions, DidChangeWorkspaceFoldersParams,
+        DidCloseTextDocumentParams, DidOpenTextDocumentParams, DocumentDiagnosticParams,
+        DocumentDiagnosticReport, DocumentDiagnosticReportResult, DocumentFormattingParams,
+        DocumentOnTypeFormattingOptions, DocumentOnTypeFormattingParams,
+        DocumentRangeFormattingParams, ExecuteCommandOptions, ExecuteCommandParams,
+        FormattingOptions, GeneralClientCapabilities, GlobPattern, InitializeParams,
+        LogMessageParams, MessageType, NumberOrString, OneOf, PartialResultParams, Position,
+        PositionEncodingKind, PublishDiagnosticsClientCapabilities, PublishDiagnosticsParams,
+        Range, RegistrationParams, ServerCapabilities, ServerInfo, TextDocumentClientCapabilities,
+        TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+        TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit,
+        Uri, VersionedTextDocumentIdentifier, WorkDoneProgressParams, WorkspaceClientCapabilities,
+        WorkspaceFolder, WorkspaceFoldersChangeEvent, WorkspaceFoldersServerCapabilities,
+        WorkspaceServerCapabilities,
+    };
+    use serde::de::DeserializeOwned;
+    use serde_json::to_value;
+
+    use crate::{
+        config::ConfigResolver,
+        lsp::{
+            main_loop, ClientOptions, CodeActionData, DocumentRangesFormattingParams,
+            FailureReporting, FormattingWorkers, InitializationOptions, PositionEncoding,
+            RangesFormatting, WorkspaceSummary,
+        },
+        opt::Opt,
+    };
+
+    use assert_fs::prelude::*;
+
+    macro_rules! construct_tree {
+        ({ $($file_name:literal:$file_contents:literal,)* }) => {{
+            let cwd = assert_fs::TempDir::new().unwrap();
+
+            $(
+                cwd.child($file_name).write_str($file_contents).unwrap();
+            )*
+
+            cwd
+        }};
+        ({ $($file_name:literal:$file_contents:expr,)* }) => {{
+            let cwd = assert_fs::TempDir::new().unwrap();
+
+            $(
+                cwd.child($file_name).write_str($file_contents).unwrap();
+            )*
+
+            cwd
+        }};
+    }
+
+    macro_rules! lsp_test {
+        ([$( $arguments:expr ),*], [$( $messages:expr ),*], [$( $tests:expr ),*]) => {
+            let opt = Opt::parse_from(vec!["BINARY_NAME", "--lsp", $($arguments)*]);
+            let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+            let (server, client) = Connection::memory();
+            $(
+                client.sender.send($messages).unwrap();
+            )*
+
+            main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+            let receiver = sort_responses(&client.receiver);
+            $(
+                $tests(&receiver);
+            )*
+        };
+    }
+
+    /// Formatting is performed on a worker pool, so a request which is answered straight away can be
+    /// responded to before an earlier formatting request. Sorts the responses by their id, leaving other messages in place.
+    fn sort_responses(receiver: &Receiver<Message>) -> Receiver<Message> {
+        let mut messages = receiver.try_iter().collect::<Vec<_>>();
+
+        let mut responses = messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::Response(response) => Some(response.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        responses.sort_by_key(|response| to_value(&response.id).unwrap().as_i64());
+
+        let mut responses = responses.into_iter();
+        for message in &mut messages {
+            if let Message::Response(response) = message {
+                *response = responses.next().unwrap();
+            }
+        }
+
+        let (sender, sorted_receiver) = crossbeam_channel::unbounded();
+        for message in messages {
+            sender.send(message).unwrap();
+        }
+        sorted_receiver
+    }
+
+    fn initialize(id: i32, root_path: Option<&Path>) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+            params: to_value(InitializeParams {
+                #[allow(deprecated)]
+                root_uri: root_path.map(|path| Uri::from_str(path.to_str().unwrap()).unwrap()),
+                ..Default::default()
+            })
+            .unwrap(),
+        })
+    }
+
+    fn initialize_with_options(id: i32, options: InitializationOptions) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+            params: to_value(InitializeParams {
+                initialization_options: Some(to_value(options).unwrap()),
+                ..Default::default()
+            })
+            .unwrap(),
+        })
+    }
+
+    fn initialized() -> Message {
+        Message::Notification(Notification {
+            method: Initialized::METHOD.to_string(),
+            params: serde_json::Value::Null,
+        })
+    }
+
+    fn open_text_document(uri: Uri, text: String) -> Message {
+        Message::Notification(Notification {
+            method: DidOpenTextDocument::METHOD.to_string(),
+            params: to_value(DidOpenTextDocumentParams {
+                text_document: TextDocumentItem {
+                    uri,
+                    language_id: "lua".to_string(),
+                    version: 0,
+                    text,
+                },
+            })
+            .unwrap(),
+        })
+    }
+
+    fn change_text_document(uri: Uri, version: i32, text: String) -> Message {
+        Message::Notification(Notification {
+            method: DidChangeTextDocument::METHOD.to_string(),
+            params: to_value(DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier { uri, version },
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text,
+                }],
+            })
+            .unwrap(),
+        })
+    }
+
+    fn close_text_document(uri: Uri) -> Message {
+        Message::Notification(Notification {
+            method: DidCloseTextDocument::METHOD.to_string(),
+            params: to_value(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri },
+            })
+            .unwrap(),
+        })
+    }
+
+    fn change_workspace_folders(added: Vec<Uri>, removed: Vec<Uri>) -> Message {
+        let to_folders = |uris: Vec<Uri>| {
+            uris.into_iter()
+                .map(|uri| WorkspaceFolder {
+                    name: uri.to_string(),
+                    uri,
+                })
+                .collect()
+        };
+
+        Message::Notification(Notification {
+            method: DidChangeWorkspaceFolders::METHOD.to_string(),
+            params: to_value(DidChangeWorkspaceFoldersParams {
+                event: WorkspaceFoldersChangeEvent {
+                    added: to_folders(added),
+                    removed: to_folders(removed),
+                },
+            })
+            .unwrap(),
+        })
+    }
+
+    fn format_document(id: i32, uri: Uri, options: FormattingOptions) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: Formatting::METHOD.to_string(),
+            params: to_value(DocumentFormattingParams {
+                text_document: TextDocumentIdentifier { uri },
+                options,
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .unwrap(),
+        })
+    }
+
+    fn shutdown(id: i32) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: Shutdown::METHOD.to_string(),
+            params: serde_json::Value::Null,
+        })
+    }
+
+    fn exit() -> Message {
+        Message::Notification(Notification {
+            method: Exit::METHOD.to_string(),
+            params: serde_json::Value::Null,
+        })
+    }
+
+    fn expect_server_initialized(receiver: &Receiver<Message>, response_id: i32) {
+        let mut expected_result = serde_json::json!({
+            "capabilities": ServerCapabilities {
+                document_range_formatting_provider: Some(OneOf::Left(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(DiagnosticOptions {
+                    identifier: Some(String::from("stylua")),
+                    inter_file_dependencies: false,
+                    workspace_diagnostics: false,
+                    ..Default::default()
+                })),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: String::from("}"),
+                    more_trigger_character: Some(vec![
+                        String::from(")"),
+                        String::from("d"),
+                        String::from("\n"),
+                    ]),
+                }),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::REFACTOR_REWRITE,
+                            CodeActionKind::SOURCE_ORGANIZE_IMPORTS,
+                        ]),
+                        resolve_provider: Some(true),
+                        ..Default::default()
+                    },
+                )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        String::from("stylua.formatWorkspace"),
+                        String::from("stylua.checkWorkspace"),
+                    ],
+                    ..Default::default()
+                }),
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::INCREMENTAL,
+                )),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
+                ..Default::default()
+            },
+            "serverInfo": Some(ServerInfo {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+        });
+        expected_result["capabilities"]["documentRangeFormattingProvider"] =
+            serde_json::json!({ "rangesSupport": true });
+
+        match receiver.recv().unwrap() {
+            Message::Response(Response {
+                id,
+                result: Some(result),
+                error: None,
+            }) if id == RequestId::from(response_id) && result == expected_result => {}
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    fn expect_response<T: DeserializeOwned>(receiver: &Receiver<Message>, response_id: i32) -> T {
+        match receiver.recv().unwrap() {
+            Message::Response(Response {
+                id,
+                result: Some(result),
+                error: None,
+            }) if id == RequestId::from(response_id) => {
+                serde_json::from_value::<T>(result).unwrap()
+            }
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    fn expect_server_shutdown(receiver: &Receiver<Message>, response_id: i32) {
+        match receiver.recv().unwrap() {
+            Message::Response(Response {
+                id,
+                result: Some(result),
+                error: None,
+            }) if id == RequestId::from(response_id) && result == serde_json::Value::Null => {}
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    #[test]
+    fn test_lsp_initialize() {
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client.sender.send(shutdown(2)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+        expect_server_shutdown(&client.receiver, 2);
+        assert!(client.receiver.is_empty());
+    }
+
+    fn apply_text_edits_to(text: &str, mut edits: Vec<TextEdit>) -> String {
+        edits.sort_by(|a, b| match a.range.start.line.cmp(&b.range.start.line) {
+            Ordering::Equal => a
+                .range
+                .start
+                .character
+                .cmp(&b.range.start.character)
+                .reverse(),
+            order => order.reverse(),
+        });
+        let mut text = text.to_string();
+        for edit in edits {
+            let start = text
+                .lines()
+                .take(edit.range.start.line.try_into().unwrap())
+                .map(|line| line.len() + '\n'.len_utf8())
+                .sum::<usize>()
+                + <u32 as TryInto<usize>>::try_into(edit.range.start.character).unwrap();
+            let end = text
+                .lines()
+                .take(edit.range.end.line.try_into().unwrap())
+                .map(|line| line.len() + '\n'.len_utf8())
+                .sum::<usize>()
+                + <u32 as TryInto<usize>>::try_into(edit.range.end.character).unwrap();
+            text.replace_range(start..end, &edit.new_text);
+        }
+        text
+    }
+
+    #[test]
+    fn test_lsp_document_formatting() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+        let contents = "local  x  =  1";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(
+                2,
+                uri.clone(),
+                FormattingOptions::default(),
+            ))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
+        assert_eq!(
+            edits,
+            [
+                TextEdit {
+                    range: Range::new(Position::new(0, 6), Position::new(0, 7)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(0, 8), Position::new(0, 9)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(0, 12), Position::new(0, 13)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(0, 14), Position::new(0, 14)),
+                    new_text: "\n".to_string()
+                },
+            ]
+        );
+        let formatted = apply_text_edits_to(contents, edits);
+        assert_eq!(formatted, "local x = 1\n");
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_document_formatting_with_unicode() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local  x  =  1 -- 测试\nlocal    y   =2";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(
+                2,
+                uri.clone(),
+                FormattingOptions::default(),
+            ))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
+        assert_eq!(
+            edits,
+            [
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 0,
+                            character: 6
+                        },
+                        end: Position {
+                            line: 0,
+                            character: 7
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 0,
+                            character: 8
+                        },
+                        end: Position {
+                            line: 0,
+                            character: 9
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 0,
+                            character: 11
+                        },
+                        end: Position {
+                            line: 0,
+                            character: 12
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 1,
+                            character: 5
+                        },
+                        end: Position {
+                            line: 1,
+                            character: 7
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 1,
+                            character: 8
+                        },
+                        end: Position {
+                            line: 1,
+                            character: 9
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 1,
+                            character: 10
+                        },
+                        end: Position {
+                            line: 1,
+                            character: 12
+                        }
+                    },
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 1,
+                            character: 14
+                        },
+                        end: Position {
+                            line: 1,
+                            character: 14
+                        }
+                    },
+                    new_text: " ".to_string()
+                },
+                TextEdit {
+                    range: Range {
+                        start: Position {
+                            line: 1,
+                            character: 15
+                        },
+                        end: Position {
+                            line: 1,
+                            character: 15
+                        }
+                    },
+                    new_text: "\n".to_string()
+                }
+            ]
+        );
+        let formatted = apply_text_edits_to(contents, edits);
+        assert_eq!(formatted, "local x = 1 -- 测试\nlocal y = 2\n");
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_range_formatting() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+        let contents = "local  x  =  1\nlocal    y   =   2";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(2),
+                method: RangeFormatting::METHOD.to_string(),
+                params: to_value(DocumentRangeFormattingParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    range: Range::new(Position::new(1, 0), Position::new(1, 18)),
+                    options: FormattingOptions::default(),
+                    work_done_progress_params: WorkDoneProgressParams::default(),
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
+        assert_eq!(
+            edits,
+            [
+                TextEdit {
+                    range: Range::new(Position::new(1, 6), Position::new(1, 9)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(1, 10), Position::new(1, 11)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(1, 12), Position::new(1, 13)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(1, 14), Position::new(1, 15)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(1, 16), Position::new(1, 17)),
+                    new_text: "".to_string()
+                },
+                TextEdit {
+                    range: Range::new(Position::new(1, 18), Position::new(1, 18)),
+                    new_text: "\n".to_string()
+                },
+            ]
+        );
+        let formatted = apply_text_edits_to(contents, edits);
+        assert_eq!(formatted, "local  x  =  1\nlocal y = 2\n");
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_ranges_formatting() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+        let contents = "local  x  =  1\nlocal  y  =  2\nlocal  z  =  3\n";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(2),
+                method: RangesFormatting::METHOD.to_string(),
+                params: to_value(DocumentRangesFormattingParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    ranges: vec![
+                        Range::new(Position::new(0, 0), Position::new(0, 14)),
+                        Range::new(Position::new(2, 0), Position::new(2, 14)),
+                    ],
+                    options: FormattingOptions::default(),
+                    work_done_progress_params: WorkDoneProgressParams::default(),
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
+        let formatted = apply_text_edits_to(contents, edits);
+        assert_eq!(formatted, "local x = 1\nlocal  y  =  2\nlocal z = 3\n");
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_ignore_formatting_for_non_lua_files() {
+        let uri = Uri::from_str("file:///home/documents/file.txt").unwrap();
+        let contents = "local x";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(Message::Notification(Notification {
+                method: DidOpenTextDocument::METHOD.to_string(),
+                params: to_value(DidOpenTextDocumentParams {
+                    text_document: TextDocumentItem {
+                        uri: uri.clone(),
+                        language_id: "txt".to_string(),
+                        version: 0,
+                        text: contents.to_string(),
+                    },
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(2),
+                method: RangeFormatting::METHOD.to_string(),
+                params: to_value(DocumentRangeFormattingParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    range: Range::new(Position::new(1, 0), Position::new(1, 18)),
+                    options: FormattingOptions::default(),
+                    work_done_progress_params: WorkDoneProgressParams::default(),
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let message = expect_message(
+            client.receiver.recv().unwrap(),
+            LogMessage::METHOD,
+            MessageType::INFO,
+        );
+        assert!(message.contains("not a Lua or Luau document"));
+        let edits: serde_json::Value = expect_response(&client.receiver, 2);
+        assert_eq!(edits, serde_json::Value::Null);
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_fails_for_unknown_files() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client.sender.send(initialize(1, None)).unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(format_document(
+                2,
+                uri.clone(),
+                FormattingOptions::default(),
+            ))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+
+        let Message::Response(Response {
+            id,
+            result: None,
+            error: Some(error),
+        }) = client.receiver.recv().unwrap()
+        else {
+            unreachable!()
+        };
+        assert!(id == RequestId::from(2));
+        assert_eq!(error.code, ErrorCode::RequestFailed as i32);
+        assert_eq!(
+            error.message,
+            format!("no document found for '{}'", uri.as_str())
+        );
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_respects_configuration_in_root_path() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "foo.lua": contents,
+        });
+
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_registers_watched_config_files() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "extends = 'shared/base.toml'",
+            "shared/base.toml": "quote_style = 'AutoPreferSingle'",
+            "foo.lua": contents,
+        });
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(1),
+                method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+                params: to_value(InitializeParams {
+                    #[allow(deprecated)]
+                    root_uri: Some(Uri::from_str(cwd.path().to_str().unwrap()).unwrap()),
+                    capabilities: ClientCapabilities {
+                        workspace: Some(WorkspaceClientCapabilities {
+                            did_change_watched_files: Some(
+                                DidChangeWatchedFilesClientCapabilities {
+                                    dynamic_registration: Some(true),
+                                    relative_pattern_support: None,
+                                },
+                            ),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(
+                2,
+                uri.clone(),
+                FormattingOptions::default(),
+            ))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        let receiver = sort_responses(&client.receiver);
+        expect_server_initialized(&receiver, 1);
+
+        // The formatting response comes from the worker pool, so may arrive either side of the second registration
+        let (requests, responses): (Vec<_>, Vec<_>) = receiver
+            .try_iter()
+            .partition(|message| matches!(message, Message::Request(_)));
+        let watchers = requests
+            .into_iter()
+            .map(|message| match message {
+                Message::Request(request) => {
+                    assert_eq!(request.method, RegisterCapability::METHOD);
+                    let params: RegistrationParams =
+                        serde_json::from_value(request.params).unwrap();
+                    assert_eq!(
+                        params.registrations[0].method,
+                        DidChangeWatchedFiles::METHOD
+                    );
+                    let options: DidChangeWatchedFilesRegistrationOptions = serde_json::from_value(
+                        params.registrations[0].register_options.clone().unwrap(),
+                    )
+                    .unwrap();
+                    options
+                        .watchers
+                        .into_iter()
+                        .map(|watcher| match watcher.glob_pattern {
+                            GlobPattern::String(glob) => glob,
+                            GlobPattern::Relative(_) => panic!("assertion failed"),
+                        })
+                        .collect::<Vec<_>>()
+                }
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+
+        // Configuration files in the workspace are covered by the globs,
+        // but the extended file is watched once it has been loaded
+        assert_eq!(
+            watchers,
+            [
+                vec![
+                    String::from("**/stylua.toml"),
+                    String::from("**/.stylua.toml")
+                ],
+                vec![cwd.child("shared/base.toml").to_str().unwrap().to_string()],
+            ]
+        );
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for response in responses {
+            sender.send(response).unwrap();
+        }
+        let edits: Vec<TextEdit> = expect_response(&receiver, 2);
+        assert_eq!(apply_text_edits_to(contents, edits), "local x = 'hello'\n");
+        expect_server_shutdown(&receiver, 3);
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_uses_unsaved_configuration() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "foo.lua": contents,
+        });
+
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+        let config_uri = Uri::from_str(cwd.child("stylua.toml").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                open_text_document(
+                    config_uri.clone(),
+                    "quote_style = 'AutoPreferSingle'".to_string()
+                ),
+                change_text_document(
+                    config_uri.clone(),
+                    1,
+                    "quote_style = 'ForceDouble'".to_string()
+                ),
+                format_document(3, uri.clone(), FormattingOptions::default()),
+                close_text_document(config_uri.clone()),
+                format_document(4, uri.clone(), FormattingOptions::default()),
+                shutdown(5),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 3);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = \"hello\"\n");
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 4);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 5)
+            ]
+        );
+    }
+
+    fn pull_diagnostics(id: i32, uri: Uri) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: DocumentDiagnosticRequest::METHOD.to_string(),
+            params: to_value(DocumentDiagnosticParams {
+                text_document: TextDocumentIdentifier { uri },
+                identifier: None,
+                previous_result_id: None,
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+            .unwrap(),
+        })
+    }
+
+    fn expect_diagnostics(receiver: &Receiver<Message>, response_id: i32) -> Vec<Diagnostic> {
+        match expect_response(receiver, response_id) {
+            DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(report)) => {
+                report.full_document_diagnostic_report.items
+            }
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    #[test]
+    fn test_lsp_diagnostics_for_unformatted_code() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local x = 1\nlocal  y  =  2\nlocal z = 3\n";
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, None),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                pull_diagnostics(2, uri),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let diagnostics = expect_diagnostics(receiver, 2);
+                    assert_eq!(diagnostics.len(), 1);
+                    assert_eq!(
+                        diagnostics[0].range,
+                        Range::new(Position::new(1, 0), Position::new(2, 0))
+                    );
+                    assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::HINT));
+                    assert_eq!(diagnostics[0].source.as_deref(), Some("stylua"));
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_diagnostics_for_parse_error() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local x = 1\nlocal y =\n";
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, None),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                pull_diagnostics(2, uri),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let diagnostics = expect_diagnostics(receiver, 2);
+                    assert!(!diagnostics.is_empty());
+                    for diagnostic in diagnostics {
+                        assert_eq!(diagnostic.severity, Some(DiagnosticSeverity::ERROR));
+                        assert!(diagnostic.range.start.line >= 1);
+                    }
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    fn initialize_with_published_diagnostics(id: i32) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+            params: to_value(InitializeParams {
+                capabilities: ClientCapabilities {
+                    text_document: Some(TextDocumentClientCapabilities {
+                        publish_diagnostics: Some(PublishDiagnosticsClientCapabilities::default()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap(),
+        })
+    }
+
+    #[test]
+    fn test_lsp_publishes_diagnostics() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        std::thread::scope(|scope| {
+            let server_thread =
+                scope.spawn(|| main_loop(server, &opt, 1, &mut config_resolver).unwrap());
+
+            client
+                .sender
+                .send(initialize_with_published_diagnostics(1))
+                .unwrap();
+            client.sender.send(initialized()).unwrap();
+            expect_server_initialized(&client.receiver, 1);
+
+            // Diagnostics are computed on the worker pool, and are dropped if the document changes in the meantime,
+            // so wait for them to be published before changing the document again
+            for (message, expected_count) in [
+                (
+                    open_text_document(uri.clone(), "local  x  =  1\n".to_string()),
+                    1,
+                ),
+                (
+                    change_text_document(uri.clone(), 1, "local x = 1\n".to_string()),
+                    0,
+                ),
+                (close_text_document(uri.clone()), 0),
+            ] {
+                client.sender.send(message).unwrap();
+                match client.receiver.recv().unwrap() {
+                    Message::Notification(notification) => {
+                        assert_eq!(notification.method, PublishDiagnostics::METHOD);
+                        let params: PublishDiagnosticsParams =
+                            serde_json::from_value(notification.params).unwrap();
+                        assert_eq!(params.uri, uri);
+                        assert_eq!(params.diagnostics.len(), expected_count);
+                    }
+                    _ => panic!("assertion failed"),
+                }
+            }
+
+            client.sender.send(shutdown(2)).unwrap();
+            client.sender.send(exit()).unwrap();
+            expect_server_shutdown(&client.receiver, 2);
+            server_thread.join().unwrap();
+        });
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_diagnostics_do_not_delay_shutdown() {
+        // Computing diagnostics for this file keeps the single worker busy whilst the server shuts down
+        let uri = Uri::from_str("file:///home/documents/nested_tables.lua").unwrap();
+        let contents = include_str!("../../benches/nested_tables.lua");
+
+        lsp_test!(
+            [],
+            [
+                initialize_with_published_diagnostics(1),
+                initialized(),
+                open_text_document(uri, contents.to_string()),
+                shutdown(2),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                // The diagnostics still being computed are dropped, rather than the shutdown waiting for them
+                |receiver| expect_server_shutdown(receiver, 2),
+                |receiver: &Receiver<Message>| assert!(receiver.is_empty())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_cwd_configuration_respected_for_nested_file() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "build/foo.lua": contents,
+        });
+
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_configuration_is_not_used_outside_of_cwd() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "build/foo.lua": contents,
+        });
+
+        let cwd = cwd.child("build");
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = \"hello\"\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_configuration_used_outside_of_cwd_when_search_parent_directories_is_enabled() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "build/foo.lua": contents,
+        });
+
+        let cwd = cwd.child("build");
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            ["--search-parent-directories"],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_configuration_root_follows_workspace_folder_changes() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "project/foo.lua": contents,
+        });
+
+        let folder = Uri::from_str(cwd.child("project").to_str().unwrap()).unwrap();
+        let uri = Uri::from_str(cwd.child("project/foo.lua").to_str().unwrap()).unwrap();
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                // The search for configuration now stops at the added folder
+                change_workspace_folders(vec![folder.clone()], vec![]),
+                format_document(3, uri.clone(), FormattingOptions::default()),
+                change_workspace_folders(vec![], vec![folder.clone()]),
+                format_document(4, uri.clone(), FormattingOptions::default()),
+                shutdown(5),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 3);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = \"hello\"\n");
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 4);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 5)
             ]
         );
-        let formatted = apply_text_edits_to(contents, edits);
-        assert_eq!(formatted, "local x = 1 -- 测试\nlocal y = 2\n");
-
-        expect_server_shutdown(&client.receiver, 3);
-        assert!(client.receiver.is_empty());
     }
 
     #[test]
-    fn test_lsp_range_formatting() {
-        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
-        let contents = "local  x  =  1\nlocal    y   =   2";
-
-        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
-        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+    fn test_lsp_configuration_is_searched_next_to_file() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            "build/stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "build/foo.lua": contents,
+        });
 
-        let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
-        client.sender.send(initialized()).unwrap();
-        client
-            .sender
-            .send(open_text_document(uri.clone(), contents.to_string()))
-            .unwrap();
-        client
-            .sender
-            .send(Message::Request(Request {
-                id: RequestId::from(2),
-                method: RangeFormatting::METHOD.to_string(),
-                params: to_value(DocumentRangeFormattingParams {
-                    text_document: TextDocumentIdentifier { uri },
-                    range: Range::new(Position::new(1, 0), Position::new(1, 18)),
-                    options: FormattingOptions::default(),
-                    work_done_progress_params: WorkDoneProgressParams::default(),
-                })
-                .unwrap(),
-            }))
-            .unwrap();
-        client.sender.send(shutdown(3)).unwrap();
-        client.sender.send(exit()).unwrap();
+        let uri = Uri::from_str(cwd.child("build/foo.lua").to_str().unwrap()).unwrap();
 
-        main_loop(server, false, &mut config_resolver).unwrap();
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri.clone(), FormattingOptions::default()),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 'hello'\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
 
-        expect_server_initialized(&client.receiver, 1);
+    #[test]
+    fn test_lsp_does_not_use_editor_formatting_options() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+        let contents = "do print(1) end";
 
-        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
-        assert_eq!(
-            edits,
+        lsp_test!(
+            [],
             [
-                TextEdit {
-                    range: Range::new(Position::new(1, 6), Position::new(1, 9)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(1, 10), Position::new(1, 11)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(1, 12), Position::new(1, 13)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(1, 14), Position::new(1, 15)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(1, 16), Position::new(1, 17)),
-                    new_text: "".to_string()
-                },
-                TextEdit {
-                    range: Range::new(Position::new(1, 18), Position::new(1, 18)),
-                    new_text: "\n".to_string()
+                initialize_with_options(
+                    1,
+                    InitializationOptions {
+                        respect_editor_formatting_options: None,
+                        ..Default::default()
+                    }
+                ),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(
+                    2,
+                    uri.clone(),
+                    FormattingOptions {
+                        tab_size: 2,
+                        insert_spaces: true,
+                        ..Default::default()
+                    }
+                ),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "do\n\tprint(1)\nend\n");
                 },
+                |receiver| expect_server_shutdown(receiver, 3)
             ]
         );
-        let formatted = apply_text_edits_to(contents, edits);
-        assert_eq!(formatted, "local  x  =  1\nlocal y = 2\n");
-
-        expect_server_shutdown(&client.receiver, 3);
-        assert!(client.receiver.is_empty());
     }
 
     #[test]
-    fn test_lsp_ignore_formatting_for_non_lua_files() {
-        let uri = Uri::from_str("file:///home/documents/file.txt").unwrap();
-        let contents = "local x";
+    fn test_lsp_respects_editor_formatting_options_if_enabled() {
+        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+        let contents = "do print(1) end";
 
-        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
-        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+        lsp_test!(
+            [],
+            [
+                initialize_with_options(
+                    1,
+                    InitializationOptions {
+                        respect_editor_formatting_options: Some(true),
+                        ..Default::default()
+                    }
+                ),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(
+                    2,
+                    uri.clone(),
+                    FormattingOptions {
+                        tab_size: 2,
+                        insert_spaces: true,
+                        ..Default::default()
+                    }
+                ),
+                shutdown(3),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "do\n  print(1)\nend\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 3)
+            ]
+        );
+    }
 
-        let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
-        client.sender.send(initialized()).unwrap();
-        client
-            .sender
-            .send(Message::Notification(Notification {
-                method: DidOpenTextDocument::METHOD.to_string(),
-                params: to_value(DidOpenTextDocumentParams {
-                    text_document: TextDocumentItem {
-                        uri: uri.clone(),
-                        language_id: "txt".to_string(),
-                        version: 0,
-                        text: contents.to_string(),
-                    },
-                })
-                .unwrap(),
-            }))
-            .unwrap();
-        client
-            .sender
-            .send(Message::Request(Request {
-                id: RequestId::from(2),
-                method: RangeFormatting::METHOD.to_string(),
-                params: to_value(DocumentRangeFormattingParams {
-                    text_document: TextDocumentIdentifier { uri },
-                    range: Range::new(Position::new(1, 0), Position::new(1, 18)),
-                    options: FormattingOptions::default(),
-                    work_done_progress_params: WorkDoneProgressParams::default(),
-                })
-                .unwrap(),
-            }))
-            .unwrap();
-        client.sender.send(shutdown(3)).unwrap();
-        client.sender.send(exit()).unwrap();
+    #[test]
+    fn test_lsp_stylua_ignore() {
+        let contents = "local   x    =   1";
+        let cwd = construct_tree!({
+            ".styluaignore": "ignored/",
+            "foo.lua": contents,
+            "ignored/bar.lua": contents,
+        });
 
-        main_loop(server, false, &mut config_resolver).unwrap();
+        let foo_uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+        let bar_uri = Uri::from_str(cwd.child("ignored/bar.lua").to_str().unwrap()).unwrap();
 
-        expect_server_initialized(&client.receiver, 1);
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(foo_uri.clone(), contents.to_string()),
+                open_text_document(bar_uri.clone(), contents.to_string()),
+                // The ignored file is answered straight away, so is requested first to keep the log message in order
+                format_document(3, bar_uri.clone(), FormattingOptions::default()),
+                format_document(2, foo_uri.clone(), FormattingOptions::default()),
+                shutdown(4),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver: &Receiver<Message>| {
+                    let message = expect_message(
+                        receiver.recv().unwrap(),
+                        LogMessage::METHOD,
+                        MessageType::INFO,
+                    );
+                    assert!(message.contains("ignored by a .styluaignore file"));
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = 1\n");
+                },
+                |receiver| {
+                    let edits: serde_json::Value = expect_response(receiver, 3);
+                    assert_eq!(edits, serde_json::Value::Null);
+                },
+                |receiver| expect_server_shutdown(receiver, 4)
+            ]
+        );
+    }
 
-        let edits: serde_json::Value = expect_response(&client.receiver, 2);
-        assert_eq!(edits, serde_json::Value::Null);
+    fn change_configuration(settings: serde_json::Value) -> Message {
+        Message::Notification(Notification {
+            method: DidChangeConfiguration::METHOD.to_string(),
+            params: to_value(DidChangeConfigurationParams { settings }).unwrap(),
+        })
+    }
 
-        expect_server_shutdown(&client.receiver, 3);
-        assert!(client.receiver.is_empty());
+    fn settings_response(id: i32, settings: serde_json::Value) -> Message {
+        Message::Response(Response::new_ok(RequestId::from(id), vec![settings]))
+    }
+
+    fn expect_settings_request(message: Message, id: i32) {
+        match message {
+            Message::Request(request) => {
+                assert_eq!(request.id, RequestId::from(id));
+                assert_eq!(request.method, WorkspaceConfiguration::METHOD);
+                let params: ConfigurationParams = serde_json::from_value(request.params).unwrap();
+                assert_eq!(params.items[0].section.as_deref(), Some("stylua"));
+            }
+            _ => panic!("assertion failed"),
+        }
     }
 
     #[test]
-    fn test_lsp_fails_for_unknown_files() {
-        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
+    fn test_lsp_pulls_settings() {
+        let contents = "local x = \"hello\"";
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+            "foo.lua": contents,
+        });
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
 
         let opt = Opt::parse_from(vec!["BINARY_NAME"]);
         let mut config_resolver = ConfigResolver::new(&opt).unwrap();
 
         let (server, client) = Connection::memory();
-        client.sender.send(initialize(1, None)).unwrap();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(1),
+                method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+                params: to_value(InitializeParams {
+                    #[allow(deprecated)]
+                    root_uri: Some(Uri::from_str(cwd.path().to_str().unwrap()).unwrap()),
+                    capabilities: ClientCapabilities {
+                        workspace: Some(WorkspaceClientCapabilities {
+                            configuration: Some(true),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap(),
+            }))
+            .unwrap();
         client.sender.send(initialized()).unwrap();
+        // The settings are pulled again before the client responds to the first request,
+        // so the response to the first request is out of date and ignored
+        client
+            .sender
+            .send(change_configuration(serde_json::Value::Null))
+            .unwrap();
+        client
+            .sender
+            .send(settings_response(
+                1,
+                serde_json::json!({
+                    "respect_ignores": false,
+                    "verify": true,
+                    "fallback_config": { "quote_style": "AutoPreferSingle" },
+                }),
+            ))
+            .unwrap();
+        client
+            .sender
+            .send(settings_response(0, serde_json::Value::Null))
+            .unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
         client
             .sender
             .send(format_document(
@@ -1005,40 +4106,68 @@ mod tests {
                 FormattingOptions::default(),
             ))
             .unwrap();
-        client.sender.send(shutdown(3)).unwrap();
+        // The settings are pulled again, and are reset as the section is no longer set
+        client
+            .sender
+            .send(change_configuration(serde_json::Value::Null))
+            .unwrap();
+        client
+            .sender
+            .send(settings_response(2, serde_json::Value::Null))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(
+                3,
+                uri.clone(),
+                FormattingOptions::default(),
+            ))
+            .unwrap();
+        client.sender.send(shutdown(4)).unwrap();
         client.sender.send(exit()).unwrap();
 
-        main_loop(server, false, &mut config_resolver).unwrap();
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
 
-        expect_server_initialized(&client.receiver, 1);
+        let receiver = sort_responses(&client.receiver);
+        expect_server_initialized(&receiver, 1);
 
-        let Message::Response(Response {
-            id,
-            result: None,
-            error: Some(error),
-        }) = client.receiver.recv().unwrap()
-        else {
-            unreachable!()
-        };
-        assert!(id == RequestId::from(2));
-        assert_eq!(error.code, ErrorCode::RequestFailed as i32);
-        assert_eq!(
-            error.message,
-            format!("no document found for '{}'", uri.as_str())
+        // Formatting responses come from the worker pool, so may arrive either side of the other messages
+        let (responses, messages): (Vec<_>, Vec<_>) = receiver
+            .try_iter()
+            .partition(|message| matches!(message, Message::Response(_)));
+        let mut messages = messages.into_iter();
+        for id in 0..3 {
+            expect_settings_request(messages.next().unwrap(), id);
+        }
+        // The file is ignored again once the settings are reset
+        let message = expect_message(
+            messages.next().unwrap(),
+            LogMessage::METHOD,
+            MessageType::INFO,
         );
+        assert!(message.contains(".styluaignore"));
+        assert!(messages.next().is_none());
 
-        expect_server_shutdown(&client.receiver, 3);
-        assert!(client.receiver.is_empty());
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for response in responses {
+            sender.send(response).unwrap();
+        }
+        let edits: Vec<TextEdit> = expect_response(&receiver, 2);
+        assert_eq!(apply_text_edits_to(contents, edits), "local x = 'hello'\n");
+        let edits: serde_json::Value = expect_response(&receiver, 3);
+        assert_eq!(edits, serde_json::Value::Null);
+        expect_server_shutdown(&receiver, 4);
+        assert!(receiver.is_empty());
     }
 
     #[test]
-    fn test_lsp_respects_configuration_in_root_path() {
+    fn test_lsp_uses_pushed_settings() {
         let contents = "local x = \"hello\"";
         let cwd = construct_tree!({
-            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "stylua.toml": "quote_style = 'ForceDouble'",
+            "other.toml": "quote_style = 'AutoPreferSingle'",
             "foo.lua": contents,
         });
-
         let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
 
         lsp_test!(
@@ -1047,8 +4176,13 @@ mod tests {
                 initialize(1, Some(cwd.path())),
                 initialized(),
                 open_text_document(uri.clone(), contents.to_string()),
+                change_configuration(serde_json::json!({
+                    "stylua": { "config_path": "other.toml" },
+                })),
                 format_document(2, uri.clone(), FormattingOptions::default()),
-                shutdown(3),
+                change_configuration(serde_json::json!({})),
+                format_document(3, uri.clone(), FormattingOptions::default()),
+                shutdown(4),
                 exit()
             ],
             [
@@ -1058,37 +4192,266 @@ mod tests {
                     let formatted = apply_text_edits_to(contents, edits);
                     assert_eq!(formatted, "local x = 'hello'\n");
                 },
-                |receiver| expect_server_shutdown(receiver, 3)
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 3);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local x = \"hello\"\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 4)
             ]
         );
     }
 
+    fn execute_command(id: i32, command: &str) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: ExecuteCommand::METHOD.to_string(),
+            params: to_value(ExecuteCommandParams {
+                command: command.to_string(),
+                arguments: Vec::new(),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .unwrap(),
+        })
+    }
+
     #[test]
-    fn test_lsp_cwd_configuration_respected_for_nested_file() {
-        let contents = "local x = \"hello\"";
+    fn test_lsp_workspace_commands() {
+        use super::ToFilePath;
+
+        let unformatted = "local  x  =  1";
+        let unsaved = "local  y  =  2";
         let cwd = construct_tree!({
-            "stylua.toml": "quote_style = 'AutoPreferSingle'",
-            "build/foo.lua": contents,
+            ".styluaignore": "ignored/",
+            "formatted.lua": "local x = 1\n",
+            "unformatted.lua": unformatted,
+            "broken.lua": "local x =",
+            "open.lua": "local y = 2\n",
+            "ignored/foo.lua": unformatted,
+            "notes.txt": unformatted,
+        });
+        let open_uri = Uri::from_str(cwd.child("open.lua").to_str().unwrap()).unwrap();
+
+        let file_names = |uris: Vec<Uri>| {
+            let mut file_names = uris
+                .iter()
+                .map(|uri| {
+                    uri.to_file_path()
+                        .strip_prefix(cwd.path())
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>();
+            file_names.sort();
+            file_names
+        };
+        let expect_summary = |receiver: &Receiver<Message>, id: i32| {
+            let summary: WorkspaceSummary = expect_response(receiver, id);
+            assert_eq!(summary.checked_files, 4);
+            assert_eq!(
+                file_names(summary.unformatted_files),
+                ["open.lua", "unformatted.lua"]
+            );
+            assert_eq!(
+                file_names(summary.errors.into_iter().map(|error| error.uri).collect()),
+                ["broken.lua"]
+            );
+        };
+        let expect_apply_edit =
+            |receiver: &Receiver<Message>, id: i32| match receiver.recv().unwrap() {
+                Message::Request(request) => {
+                    assert_eq!(request.id, RequestId::from(id));
+                    assert_eq!(request.method, ApplyWorkspaceEdit::METHOD);
+                    let params: ApplyWorkspaceEditParams =
+                        serde_json::from_value(request.params).unwrap();
+                    let changes = params.edit.changes.unwrap().into_iter().collect::<Vec<_>>();
+                    assert_eq!(
+                        file_names(changes.iter().map(|(uri, _)| uri.clone()).collect()),
+                        ["open.lua", "unformatted.lua"]
+                    );
+                    for (uri, edits) in changes {
+                        let (contents, expected) = if uri == open_uri {
+                            (unsaved, "local y = 2\n")
+                        } else {
+                            (unformatted, "local x = 1\n")
+                        };
+                        assert_eq!(apply_text_edits_to(contents, edits), expected);
+                    }
+                }
+                _ => panic!("assertion failed"),
+            };
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, Some(cwd.path())),
+                initialized(),
+                open_text_document(open_uri.clone(), unsaved.to_string()),
+                // Unknown commands are responded to straight away, rather than on the worker pool
+                execute_command(2, "stylua.unknown"),
+                execute_command(3, "stylua.checkWorkspace"),
+                execute_command(4, "stylua.formatWorkspace"),
+                // Each request to apply edits is given its own id
+                execute_command(5, "stylua.formatWorkspace"),
+                shutdown(6),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| expect_error(receiver, 2, ErrorCode::InvalidParams),
+                |receiver| expect_summary(receiver, 3),
+                |receiver| expect_apply_edit(receiver, 0),
+                |receiver| expect_summary(receiver, 4),
+                |receiver| expect_apply_edit(receiver, 1),
+                |receiver| expect_summary(receiver, 5),
+                |receiver| expect_server_shutdown(receiver, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lsp_workspace_commands_use_settings_and_unsaved_configuration() {
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'ForceDouble'",
+            "other.toml": "quote_style = 'ForceDouble'",
+            "foo.lua": "local x = \"hello\"\n",
         });
+        let config_uri = Uri::from_str(cwd.child("stylua.toml").to_str().unwrap()).unwrap();
 
-        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+        let expect_unformatted_files = |receiver: &Receiver<Message>, id: i32, count: usize| {
+            let summary: WorkspaceSummary = expect_response(receiver, id);
+            assert_eq!(summary.checked_files, 1);
+            assert_eq!(summary.unformatted_files.len(), count);
+        };
 
         lsp_test!(
             [],
             [
                 initialize(1, Some(cwd.path())),
                 initialized(),
+                open_text_document(
+                    config_uri.clone(),
+                    "quote_style = 'ForceSingle'".to_string()
+                ),
+                execute_command(2, "stylua.checkWorkspace"),
+                change_configuration(serde_json::json!({
+                    "stylua": { "config_path": "other.toml" },
+                })),
+                execute_command(3, "stylua.checkWorkspace"),
+                shutdown(4),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| expect_unformatted_files(receiver, 2, 1),
+                |receiver| expect_unformatted_files(receiver, 3, 0),
+                |receiver| expect_server_shutdown(receiver, 4)
+            ]
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_file_path_to_uri() {
+        use super::{file_path_to_uri, ToFilePath};
+
+        let uri = file_path_to_uri(Path::new("/home/user/my project/foo.lua")).unwrap();
+        assert_eq!(uri.as_str(), "file:///home/user/my%20project/foo.lua");
+        assert_eq!(
+            uri.to_file_path(),
+            PathBuf::from("/home/user/my project/foo.lua")
+        );
+    }
+
+    fn code_actions(id: i32, uri: Uri, range: Range, only: Option<Vec<CodeActionKind>>) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: CodeActionRequest::METHOD.to_string(),
+            params: to_value(CodeActionParams {
+                text_document: TextDocumentIdentifier { uri },
+                range,
+                context: CodeActionContext {
+                    only,
+                    ..Default::default()
+                },
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            })
+            .unwrap(),
+        })
+    }
+
+    fn apply_code_action(text: &str, action: &CodeActionOrCommand) -> String {
+        match action {
+            CodeActionOrCommand::CodeAction(CodeAction {
+                edit: Some(edit), ..
+            }) => {
+                let edits = edit.changes.as_ref().unwrap().values().next().unwrap();
+                apply_text_edits_to(text, edits.clone())
+            }
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    #[test]
+    fn test_lsp_code_actions() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local b = require(\"b\")\nlocal a = require(\"a\")\nlocal  x  =  1\n";
+
+        lsp_test!(
+            [],
+            [
+                initialize(1, None),
+                initialized(),
                 open_text_document(uri.clone(), contents.to_string()),
-                format_document(2, uri.clone(), FormattingOptions::default()),
+                code_actions(
+                    2,
+                    uri,
+                    Range::new(Position::new(2, 0), Position::new(3, 0)),
+                    None
+                ),
                 shutdown(3),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
                 |receiver| {
-                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
-                    let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "local x = 'hello'\n");
+                    let actions: Vec<CodeActionOrCommand> = expect_response(receiver, 2);
+                    let titles = actions
+                        .iter()
+                        .map(|action| match action {
+                            CodeActionOrCommand::CodeAction(action) => action.title.as_str(),
+                            CodeActionOrCommand::Command(command) => command.title.as_str(),
+                        })
+                        .collect::<Vec<_>>();
+                    assert_eq!(
+                        titles,
+                        vec![
+                            "Format selection",
+                            "Sort requires in this file",
+                            "Wrap statement in `-- stylua: ignore`",
+                            "Add `-- stylua: ignore start/end` around selection",
+                        ]
+                    );
+
+                    assert_eq!(
+                        apply_code_action(contents, &actions[0]),
+                        "local b = require(\"b\")\nlocal a = require(\"a\")\nlocal x = 1\n"
+                    );
+                    assert_eq!(
+                        apply_code_action(contents, &actions[1]),
+                        "local a = require(\"a\")\nlocal b = require(\"b\")\nlocal x = 1\n"
+                    );
+                    assert_eq!(
+                        apply_code_action(contents, &actions[2]),
+                        "local b = require(\"b\")\nlocal a = require(\"a\")\n-- stylua: ignore\nlocal  x  =  1\n"
+                    );
+                    assert_eq!(
+                        apply_code_action(contents, &actions[3]),
+                        "local b = require(\"b\")\nlocal a = require(\"a\")\n-- stylua: ignore start\nlocal  x  =  1\n-- stylua: ignore end\n"
+                    );
                 },
                 |receiver| expect_server_shutdown(receiver, 3)
             ]
@@ -1096,57 +4459,202 @@ mod tests {
     }
 
     #[test]
-    fn test_lsp_configuration_is_not_used_outside_of_cwd() {
-        let contents = "local x = \"hello\"";
-        let cwd = construct_tree!({
-            "stylua.toml": "quote_style = 'AutoPreferSingle'",
-            "build/foo.lua": contents,
-        });
-
-        let cwd = cwd.child("build");
-        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+    fn test_lsp_code_actions_filtered_by_kind() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local b = require(\"b\")\nlocal a = require(\"a\")\n";
 
         lsp_test!(
             [],
             [
-                initialize(1, Some(cwd.path())),
+                initialize(1, None),
                 initialized(),
                 open_text_document(uri.clone(), contents.to_string()),
-                format_document(2, uri.clone(), FormattingOptions::default()),
+                code_actions(
+                    2,
+                    uri,
+                    Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    Some(vec![CodeActionKind::SOURCE])
+                ),
                 shutdown(3),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
                 |receiver| {
-                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
-                    let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "local x = \"hello\"\n");
+                    let actions: Vec<CodeActionOrCommand> = expect_response(receiver, 2);
+                    assert_eq!(actions.len(), 1);
+                    assert_eq!(
+                        apply_code_action(contents, &actions[0]),
+                        "local a = require(\"a\")\nlocal b = require(\"b\")\n"
+                    );
                 },
                 |receiver| expect_server_shutdown(receiver, 3)
             ]
         );
     }
 
+    fn initialize_with_code_action_resolve(id: i32) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+            params: to_value(InitializeParams {
+                capabilities: ClientCapabilities {
+                    text_document: Some(TextDocumentClientCapabilities {
+                        code_action: Some(CodeActionClientCapabilities {
+                            resolve_support: Some(CodeActionCapabilityResolveSupport {
+                                properties: vec!["edit".to_string()],
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .unwrap(),
+        })
+    }
+
+    fn resolve_code_action(id: i32, action: CodeAction) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: CodeActionResolveRequest::METHOD.to_string(),
+            params: to_value(action).unwrap(),
+        })
+    }
+
+    fn sort_requires_action(uri: &Uri, version: i32) -> CodeAction {
+        CodeAction {
+            title: "Sort requires in this file".to_string(),
+            kind: Some(CodeActionKind::SOURCE_ORGANIZE_IMPORTS),
+            data: Some(
+                to_value(CodeActionData {
+                    uri: uri.clone(),
+                    version,
+                    range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+                    action: super::FormattingCodeAction::SortRequires,
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lsp_code_actions_resolve_formatting_edits() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local b = require(\"b\")\nlocal a = require(\"a\")\nlocal  x  =  1\n";
+
+        lsp_test!(
+            [],
+            [
+                initialize_with_code_action_resolve(1),
+                initialized(),
+                open_text_document(uri.clone(), contents.to_string()),
+                code_actions(
+                    2,
+                    uri.clone(),
+                    Range::new(Position::new(2, 0), Position::new(3, 0)),
+                    Some(vec![CodeActionKind::SOURCE])
+                ),
+                resolve_code_action(3, sort_requires_action(&uri, 0)),
+                // The action was offered for a different version of the document
+                resolve_code_action(4, sort_requires_action(&uri, 1)),
+                shutdown(5),
+                exit()
+            ],
+            [
+                |receiver| expect_server_initialized(receiver, 1),
+                |receiver| {
+                    let actions: Vec<CodeActionOrCommand> = expect_response(receiver, 2);
+                    assert_eq!(actions.len(), 1);
+                    match &actions[0] {
+                        CodeActionOrCommand::CodeAction(action) => {
+                            assert_eq!(action.title, "Sort requires in this file");
+                            assert!(action.edit.is_none());
+                            assert_eq!(
+                                serde_json::from_value::<CodeActionData>(
+                                    action.data.clone().unwrap()
+                                )
+                                .unwrap()
+                                .version,
+                                0
+                            );
+                        }
+                        _ => panic!("assertion failed"),
+                    }
+                },
+                |receiver| {
+                    let action: CodeAction = expect_response(receiver, 3);
+                    assert_eq!(
+                        apply_code_action(contents, &CodeActionOrCommand::CodeAction(action)),
+                        "local a = require(\"a\")\nlocal b = require(\"b\")\nlocal x = 1\n"
+                    );
+                },
+                |receiver| expect_error(receiver, 4, ErrorCode::ContentModified),
+                |receiver| expect_server_shutdown(receiver, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ignore_statement_uses_innermost_statement() {
+        let contents = "local function foo()\n\tlocal  x  =  1\nend\n";
+        let offset = contents.find('x').unwrap();
+        assert_eq!(
+            super::ignore_statement(contents, LuaVersion::lua51(), offset).unwrap(),
+            "local function foo()\n\t-- stylua: ignore\n\tlocal  x  =  1\nend\n"
+        );
+    }
+
+    #[test]
+    fn test_ignore_lines() {
+        let contents = "do\n  local x = 1\n  local y = 2\nend";
+        let start = contents.find("x").unwrap();
+        let end = contents.find("y").unwrap();
+        assert_eq!(
+            super::ignore_lines(contents, start, end),
+            "do\n  -- stylua: ignore start\n  local x = 1\n  local y = 2\n  -- stylua: ignore end\nend"
+        );
+        assert_eq!(
+            super::ignore_lines(contents, 0, contents.len()),
+            "-- stylua: ignore start\ndo\n  local x = 1\n  local y = 2\nend\n-- stylua: ignore end\n"
+        );
+    }
+
+    fn format_on_type(id: i32, uri: Uri, position: Position, ch: &str) -> Message {
+        Message::Request(Request {
+            id: RequestId::from(id),
+            method: OnTypeFormatting::METHOD.to_string(),
+            params: to_value(DocumentOnTypeFormattingParams {
+                text_document_position: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier { uri },
+                    position,
+                },
+                ch: ch.to_string(),
+                options: FormattingOptions::default(),
+            })
+            .unwrap(),
+        })
+    }
+
     #[test]
-    fn test_lsp_configuration_used_outside_of_cwd_when_search_parent_directories_is_enabled() {
-        let contents = "local x = \"hello\"";
-        let cwd = construct_tree!({
-            "stylua.toml": "quote_style = 'AutoPreferSingle'",
-            "build/foo.lua": contents,
-        });
-
-        let cwd = cwd.child("build");
-        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
+    fn test_lsp_on_type_formatting() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local  a  =  1\nlocal t = {  1,2  }\nlocal function foo()\nreturn   a\nend\nlocal  d\n";
 
         lsp_test!(
-            ["--search-parent-directories"],
+            [],
             [
-                initialize(1, Some(cwd.path())),
+                initialize(1, None),
                 initialized(),
                 open_text_document(uri.clone(), contents.to_string()),
-                format_document(2, uri.clone(), FormattingOptions::default()),
-                shutdown(3),
+                format_on_type(2, uri.clone(), Position::new(1, 19), "}"),
+                format_on_type(3, uri.clone(), Position::new(4, 3), "d"),
+                format_on_type(4, uri.clone(), Position::new(5, 8), "d"),
+                format_on_type(5, uri, Position::new(6, 0), "\n"),
+                shutdown(6),
                 exit()
             ],
             [
@@ -1154,49 +4662,145 @@ mod tests {
                 |receiver| {
                     let edits: Vec<TextEdit> = expect_response(receiver, 2);
                     let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "local x = 'hello'\n");
+                    assert_eq!(formatted, "local  a  =  1\nlocal t = { 1, 2 }\nlocal function foo()\nreturn   a\nend\nlocal  d\n");
                 },
-                |receiver| expect_server_shutdown(receiver, 3)
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 3);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local  a  =  1\nlocal t = {  1,2  }\nlocal function foo()\n\treturn a\nend\nlocal  d\n");
+                },
+                |receiver| {
+                    let edits: serde_json::Value = expect_response(receiver, 4);
+                    assert_eq!(edits, serde_json::Value::Null);
+                },
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 5);
+                    let formatted = apply_text_edits_to(contents, edits);
+                    assert_eq!(formatted, "local  a  =  1\nlocal t = {  1,2  }\nlocal function foo()\nreturn   a\nend\nlocal d\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 6)
             ]
         );
     }
 
     #[test]
-    fn test_lsp_configuration_is_searched_next_to_file() {
-        let contents = "local x = \"hello\"";
-        let cwd = construct_tree!({
-            "build/stylua.toml": "quote_style = 'AutoPreferSingle'",
-            "build/foo.lua": contents,
-        });
+    fn test_on_type_formatting_ignores_strings_and_unfinished_code() {
+        let find = |contents: &str, character: &str| {
+            super::find_on_type_formatting_statement(
+                contents,
+                LuaVersion::lua51(),
+                contents.len(),
+                character,
+            )
+        };
 
-        let uri = Uri::from_str(cwd.child("build/foo.lua").to_str().unwrap()).unwrap();
+        assert_eq!(find("print(\"}\"", "}"), None);
+        assert_eq!(find("local t = {\n", "\n"), None);
+        assert_eq!(find("foo(function()\nend", "d"), None);
+        assert_eq!(find("do\n\tlocal friend", "d"), None);
+        assert_eq!(find("do\n\tfoo()\nend", "d"), Some((0, 13)));
+        assert_eq!(find("foo(function()\nend)", ")"), Some((0, 19)));
+        assert_eq!(find("local  x  =  1\n  ", "\n"), Some((0, 14)));
+    }
+
+    fn cancel_request(id: i32) -> Message {
+        Message::Notification(Notification {
+            method: Cancel::METHOD.to_string(),
+            params: to_value(CancelParams {
+                id: NumberOrString::Number(id),
+            })
+            .unwrap(),
+        })
+    }
+
+    /// Expects a `window/logMessage` or `window/showMessage` notification, returning its message
+    fn expect_message(message: Message, method: &str, message_type: MessageType) -> String {
+        match message {
+            Message::Notification(notification) if notification.method == method => {
+                let params: LogMessageParams = serde_json::from_value(notification.params).unwrap();
+                assert_eq!(params.typ, message_type);
+                params.message
+            }
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    #[test]
+    fn test_lsp_reports_formatting_failures() {
+        let text_uri = Uri::from_str("file:///home/documents/file.txt").unwrap();
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
 
         lsp_test!(
             [],
             [
-                initialize(1, Some(cwd.path())),
+                initialize_with_options(
+                    1,
+                    InitializationOptions {
+                        report_formatting_failures: Some(FailureReporting::Show),
+                        ..Default::default()
+                    }
+                ),
                 initialized(),
-                open_text_document(uri.clone(), contents.to_string()),
-                format_document(2, uri.clone(), FormattingOptions::default()),
-                shutdown(3),
+                Message::Notification(Notification {
+                    method: DidOpenTextDocument::METHOD.to_string(),
+                    params: to_value(DidOpenTextDocumentParams {
+                        text_document: TextDocumentItem {
+                            uri: text_uri.clone(),
+                            language_id: "txt".to_string(),
+                            version: 0,
+                            text: "local x".to_string(),
+                        },
+                    })
+                    .unwrap(),
+                }),
+                open_text_document(uri.clone(), "local x =".to_string()),
+                format_document(2, text_uri.clone(), FormattingOptions::default()),
+                format_document(3, uri.clone(), FormattingOptions::default()),
+                shutdown(4),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
+                // Documents which are deliberately not formatted are only logged
+                |receiver: &Receiver<Message>| {
+                    let message = expect_message(
+                        receiver.recv().unwrap(),
+                        LogMessage::METHOD,
+                        MessageType::INFO,
+                    );
+                    assert!(message.contains("not a Lua or Luau document"));
+                },
                 |receiver| {
-                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
-                    let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "local x = 'hello'\n");
+                    let edits: serde_json::Value = expect_response(receiver, 2);
+                    assert_eq!(edits, serde_json::Value::Null);
                 },
-                |receiver| expect_server_shutdown(receiver, 3)
+                |receiver: &Receiver<Message>| {
+                    let message = expect_message(
+                        receiver.recv().unwrap(),
+                        ShowMessage::METHOD,
+                        MessageType::ERROR,
+                    );
+                    assert!(message.starts_with(
+                        "could not format file:///home/documents/file.lua: error parsing"
+                    ));
+                },
+                |receiver| {
+                    let edits: serde_json::Value = expect_response(receiver, 3);
+                    assert_eq!(edits, serde_json::Value::Null);
+                },
+                |receiver| expect_server_shutdown(receiver, 4)
             ]
         );
     }
 
     #[test]
-    fn test_lsp_does_not_use_editor_formatting_options() {
-        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
-        let contents = "do print(1) end";
+    fn test_lsp_reports_invalid_configuration() {
+        let contents = "local   x    =   1";
+        let cwd = construct_tree!({
+            "stylua.toml": "indent_type = ",
+            "foo.lua": contents,
+        });
+        let uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
 
         lsp_test!(
             [],
@@ -1204,29 +4808,30 @@ mod tests {
                 initialize_with_options(
                     1,
                     InitializationOptions {
-                        respect_editor_formatting_options: None,
+                        report_formatting_failures: Some(FailureReporting::Show),
+                        ..Default::default()
                     }
                 ),
                 initialized(),
                 open_text_document(uri.clone(), contents.to_string()),
-                format_document(
-                    2,
-                    uri.clone(),
-                    FormattingOptions {
-                        tab_size: 2,
-                        insert_spaces: true,
-                        ..Default::default()
-                    }
-                ),
+                format_document(2, uri.clone(), FormattingOptions::default()),
                 shutdown(3),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
+                |receiver: &Receiver<Message>| {
+                    let message = expect_message(
+                        receiver.recv().unwrap(),
+                        ShowMessage::METHOD,
+                        MessageType::ERROR,
+                    );
+                    assert!(message.contains("its configuration could not be loaded"));
+                },
+                // The document is not formatted with the default configuration instead
                 |receiver| {
-                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
-                    let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "do\n\tprint(1)\nend\n");
+                    let edits: serde_json::Value = expect_response(receiver, 2);
+                    assert_eq!(edits, serde_json::Value::Null);
                 },
                 |receiver| expect_server_shutdown(receiver, 3)
             ]
@@ -1234,81 +4839,260 @@ mod tests {
     }
 
     #[test]
-    fn test_lsp_respects_editor_formatting_options_if_enabled() {
-        let uri = Uri::from_str("file:///home/documents/file.luau").unwrap();
-        let contents = "do print(1) end";
+    fn test_lsp_does_not_report_formatting_failures_when_off() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client
+            .sender
+            .send(initialize_with_options(
+                1,
+                InitializationOptions {
+                    report_formatting_failures: Some(FailureReporting::Off),
+                    ..Default::default()
+                },
+            ))
+            .unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), "local x =".to_string()))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(2, uri, FormattingOptions::default()))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        expect_server_initialized(&client.receiver, 1);
+        let edits: serde_json::Value = expect_response(&client.receiver, 2);
+        assert_eq!(edits, serde_json::Value::Null);
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    fn expect_error(receiver: &Receiver<Message>, response_id: i32, code: ErrorCode) {
+        match receiver.recv().unwrap() {
+            Message::Response(Response {
+                id,
+                result: None,
+                error: Some(error),
+            }) if id == RequestId::from(response_id) => assert_eq!(error.code, code as i32),
+            _ => panic!("assertion failed"),
+        }
+    }
+
+    #[test]
+    fn test_lsp_cancels_formatting_requests() {
+        // Formatting this file keeps the single worker busy whilst the later requests are cancelled
+        let slow_uri = Uri::from_str("file:///home/documents/nested_tables.lua").unwrap();
+        let slow_contents = include_str!("../../benches/nested_tables.lua");
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local  x  =  1\n";
 
         lsp_test!(
             [],
             [
-                initialize_with_options(
-                    1,
-                    InitializationOptions {
-                        respect_editor_formatting_options: Some(true)
-                    }
-                ),
+                initialize(1, None),
                 initialized(),
+                open_text_document(slow_uri.clone(), slow_contents.to_string()),
                 open_text_document(uri.clone(), contents.to_string()),
-                format_document(
-                    2,
-                    uri.clone(),
-                    FormattingOptions {
-                        tab_size: 2,
-                        insert_spaces: true,
-                        ..Default::default()
-                    }
-                ),
-                shutdown(3),
+                format_document(2, slow_uri, FormattingOptions::default()),
+                format_document(3, uri.clone(), FormattingOptions::default()),
+                cancel_request(3),
+                format_document(4, uri.clone(), FormattingOptions::default()),
+                change_text_document(uri.clone(), 1, "local  y  =  2\n".to_string()),
+                format_document(5, uri, FormattingOptions::default()),
+                shutdown(6),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
                 |receiver| {
-                    let edits: Vec<TextEdit> = expect_response(receiver, 2);
-                    let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "do\n  print(1)\nend\n");
+                    let _: Vec<TextEdit> = expect_response(receiver, 2);
                 },
-                |receiver| expect_server_shutdown(receiver, 3)
+                |receiver| expect_error(receiver, 3, ErrorCode::RequestCanceled),
+                |receiver| expect_error(receiver, 4, ErrorCode::ContentModified),
+                |receiver| {
+                    let edits: Vec<TextEdit> = expect_response(receiver, 5);
+                    let formatted = apply_text_edits_to("local  y  =  2\n", edits);
+                    assert_eq!(formatted, "local y = 2\n");
+                },
+                |receiver| expect_server_shutdown(receiver, 6)
             ]
         );
     }
 
     #[test]
-    fn test_lsp_stylua_ignore() {
-        let contents = "local   x    =   1";
-        let cwd = construct_tree!({
-            ".styluaignore": "ignored/",
-            "foo.lua": contents,
-            "ignored/bar.lua": contents,
+    fn test_formatting_workers_respond_when_work_panics() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let workers =
+            FormattingWorkers::new(1, sender, ClientOptions::new(&InitializeParams::default()));
+
+        workers.respond(RequestId::from(1), None, |_| panic!("formatting failed"));
+        workers.respond(RequestId::from(2), None, |id| {
+            vec![Message::Response(Response::new_ok(id, ()))]
         });
+        // Shutdown does not wait forever on the request which panicked
+        workers.join();
 
-        let foo_uri = Uri::from_str(cwd.child("foo.lua").to_str().unwrap()).unwrap();
-        let bar_uri = Uri::from_str(cwd.child("ignored/bar.lua").to_str().unwrap()).unwrap();
+        expect_error(&receiver, 1, ErrorCode::InternalError);
+        let _: serde_json::Value = expect_response(&receiver, 2);
+        assert!(receiver.is_empty());
+    }
+
+    #[test]
+    fn test_position_encodings() {
+        let document = FullTextDocument::new(
+            "lua".to_string(),
+            0,
+            "local x = '测试😀'\r\nlocal y".to_string(),
+        );
+        let offset = "local x = '测试😀".len();
+
+        assert_eq!(
+            PositionEncoding::Utf8.position_at(&document, offset),
+            Position::new(0, 21)
+        );
+        assert_eq!(
+            PositionEncoding::Utf16.position_at(&document, offset),
+            Position::new(0, 15)
+        );
+        assert_eq!(
+            PositionEncoding::Utf8.offset_at(&document, Position::new(0, 21)),
+            offset
+        );
+        // Positions in the middle of a character round down, and positions past the end of a line are clamped
+        assert_eq!(
+            PositionEncoding::Utf8.offset_at(&document, Position::new(0, 12)),
+            "local x = '".len()
+        );
+        assert_eq!(
+            PositionEncoding::Utf8.offset_at(&document, Position::new(0, 100)),
+            "local x = '测试😀'".len()
+        );
+        assert_eq!(
+            PositionEncoding::Utf8.offset_at(&document, Position::new(1, 5)),
+            "local x = '测试😀'\r\nlocal".len()
+        );
+    }
+
+    #[test]
+    fn test_lsp_utf8_position_encoding() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local  x  =  '测试'  -- 测试\n";
+
+        let opt = Opt::parse_from(vec!["BINARY_NAME"]);
+        let mut config_resolver = ConfigResolver::new(&opt).unwrap();
+
+        let (server, client) = Connection::memory();
+        client
+            .sender
+            .send(Message::Request(Request {
+                id: RequestId::from(1),
+                method: <Initialize as lsp_types::request::Request>::METHOD.to_string(),
+                params: to_value(InitializeParams {
+                    capabilities: ClientCapabilities {
+                        general: Some(GeneralClientCapabilities {
+                            position_encodings: Some(vec![
+                                PositionEncodingKind::UTF16,
+                                PositionEncodingKind::UTF8,
+                            ]),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client.sender.send(initialized()).unwrap();
+        client
+            .sender
+            .send(open_text_document(uri.clone(), contents.to_string()))
+            .unwrap();
+        // Insert a character after the string, given as a UTF-8 position
+        client
+            .sender
+            .send(Message::Notification(Notification {
+                method: DidChangeTextDocument::METHOD.to_string(),
+                params: to_value(DidChangeTextDocumentParams {
+                    text_document: VersionedTextDocumentIdentifier {
+                        uri: uri.clone(),
+                        version: 1,
+                    },
+                    content_changes: vec![TextDocumentContentChangeEvent {
+                        range: Some(Range::new(Position::new(0, 21), Position::new(0, 21))),
+                        range_length: None,
+                        text: String::from(";"),
+                    }],
+                })
+                .unwrap(),
+            }))
+            .unwrap();
+        client
+            .sender
+            .send(format_document(2, uri, FormattingOptions::default()))
+            .unwrap();
+        client.sender.send(shutdown(3)).unwrap();
+        client.sender.send(exit()).unwrap();
+
+        main_loop(server, &opt, 1, &mut config_resolver).unwrap();
+
+        let result: serde_json::Value = expect_response(&client.receiver, 1);
+        assert_eq!(result["capabilities"]["positionEncoding"], "utf-8");
+
+        // Positions are byte offsets, which is how edits are applied here
+        let edits: Vec<TextEdit> = expect_response(&client.receiver, 2);
+        let formatted = apply_text_edits_to("local  x  =  '测试';  -- 测试\n", edits);
+        assert_eq!(formatted, "local x = \"测试\" -- 测试\n");
+
+        expect_server_shutdown(&client.receiver, 3);
+        assert!(client.receiver.is_empty());
+    }
+
+    #[test]
+    fn test_lsp_line_granular_edits() {
+        let uri = Uri::from_str("file:///home/documents/file.lua").unwrap();
+        let contents = "local  x  =  1\nlocal y = 2\nlocal  z = 3\n";
 
         lsp_test!(
             [],
             [
-                initialize(1, Some(cwd.path())),
+                initialize_with_options(
+                    1,
+                    InitializationOptions {
+                        line_granular_edits: Some(true),
+                        ..Default::default()
+                    }
+                ),
                 initialized(),
-                open_text_document(foo_uri.clone(), contents.to_string()),
-                open_text_document(bar_uri.clone(), contents.to_string()),
-                format_document(2, foo_uri.clone(), FormattingOptions::default()),
-                format_document(3, bar_uri.clone(), FormattingOptions::default()),
-                shutdown(4),
+                open_text_document(uri.clone(), contents.to_string()),
+                format_document(2, uri, FormattingOptions::default()),
+                shutdown(3),
                 exit()
             ],
             [
                 |receiver| expect_server_initialized(receiver, 1),
                 |receiver| {
                     let edits: Vec<TextEdit> = expect_response(receiver, 2);
+                    assert_eq!(edits.len(), 2);
+                    for edit in &edits {
+                        assert_eq!(edit.range.start.character, 0);
+                        assert_eq!(edit.range.end.character, 0);
+                        assert_eq!(edit.range.end.line, edit.range.start.line + 1);
+                    }
                     let formatted = apply_text_edits_to(contents, edits);
-                    assert_eq!(formatted, "local x = 1\n");
-                },
-                |receiver| {
-                    let edits: serde_json::Value = expect_response(receiver, 3);
-                    assert_eq!(edits, serde_json::Value::Null);
+                    assert_eq!(formatted, "local x = 1\nlocal y = 2\nlocal z = 3\n");
                 },
-                |receiver| expect_server_shutdown(receiver, 4)
+                |receiver| expect_server_shutdown(receiver, 3)
             ]
         );
     }
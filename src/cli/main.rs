@@ -2,32 +2,54 @@
 use anyhow::{bail, Context, Result};
 use clap::StructOpt;
 use console::style;
-use ignore::{overrides::OverrideBuilder, WalkBuilder};
 use log::{LevelFilter, *};
 use serde_json::json;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs;
 use std::io::{stderr, stdin, stdout, Read, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use thiserror::Error;
 use threadpool::ThreadPool;
 
-use stylua_lib::{format_code, Config, OutputVerification, Range};
+use stylua_lib::{
+    format_code_with_cursor, format_code_with_ranges, format_code_with_transformations, Config,
+    OutputVerification,
+};
 
+use baseline::Baseline;
+use cache::FormatCache;
+use changed_lines::{ChangedLines, ChangedSince};
+use explain::{classify_diff, DiffCategory};
+use report::{FileReport, ReportFormat};
+
+mod baseline;
+mod cache;
+mod changed_lines;
 mod config;
+#[cfg(feature = "schema")]
+mod config_schema;
+mod explain;
 #[cfg(feature = "lsp")]
 mod lsp;
 mod opt;
 mod output_diff;
+#[cfg(feature = "serialize")]
+mod print_config;
+mod report;
 mod stylua_ignore;
 
-use stylua_ignore::{is_explicitly_provided, path_is_stylua_ignored, should_respect_ignores};
+use stylua_ignore::{
+    create_walker_builder, is_explicitly_provided, matches_default_glob, path_is_stylua_ignored,
+    should_respect_ignores,
+};
 
 static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
 static UNFORMATTED_FILE_COUNT: AtomicU32 = AtomicU32::new(0);
+/// The number of hunks of each kind of change found, when explaining diffs
+static EXPLAINED_CATEGORY_COUNTS: Mutex<BTreeMap<DiffCategory, u32>> = Mutex::new(BTreeMap::new());
 
 enum FormatResult {
     /// Operation was a success, the output was either written to a file or stdout. If diffing, there was no diff to create.
@@ -37,6 +59,10 @@ enum FormatResult {
     SuccessBufferedOutput(Vec<u8>),
     /// There is a diff output. This stores the diff created
     Diff(Vec<u8>),
+    /// There are problems to include in a report, which is output once every file has been checked
+    Report(FileReport),
+    /// There is a patch for the given file, which is output with all other patches once every file has been checked
+    Patch(String, Vec<u8>),
 }
 
 /// Wraps an error to include information about the file it resonated from
@@ -47,33 +73,19 @@ struct ErrorFileWrapper {
     error: anyhow::Error,
 }
 
+/// Returns the file and the errors found whilst parsing it, if the error is a parse error
+fn as_parse_error(err: &anyhow::Error) -> Option<(&str, &[full_moon::Error])> {
+    let ErrorFileWrapper { file, error } = err.downcast_ref::<ErrorFileWrapper>()?;
+    match error.downcast_ref::<stylua_lib::Error>()? {
+        stylua_lib::Error::ParseError(errs) => Some((file, errs)),
+        _ => None,
+    }
+}
+
 fn convert_parse_error_to_json(file: &str, errs: Vec<full_moon::Error>) -> serde_json::Value {
     errs.iter()
         .map(|err| {
-            let message = match err {
-                full_moon::Error::AstError(ast_error) => format!(
-                    "unexpected token `{}`: {}",
-                    ast_error.token(),
-                    ast_error.error_message()
-                ),
-                full_moon::Error::TokenizerError(error) => match error.error() {
-                    full_moon::tokenizer::TokenizerErrorType::UnclosedComment => {
-                        "unclosed comment".to_string()
-                    }
-                    full_moon::tokenizer::TokenizerErrorType::UnclosedString => {
-                        "unclosed string".to_string()
-                    }
-                    full_moon::tokenizer::TokenizerErrorType::InvalidNumber => {
-                        "invalid number".to_string()
-                    }
-                    full_moon::tokenizer::TokenizerErrorType::UnexpectedToken(character) => {
-                        format!("unexpected character {character}")
-                    }
-                    full_moon::tokenizer::TokenizerErrorType::InvalidSymbol(symbol) => {
-                        format!("invalid symbol {symbol}")
-                    }
-                },
-            };
+            let message = report::parse_error_message(err);
             let (start_position, end_position) = err.range();
             json!({
                 "type": "parse_error",
@@ -97,19 +109,41 @@ fn create_diff(
     original: &str,
     expected: &str,
     file_name: &str,
-) -> Result<Option<Vec<u8>>> {
-    match opt.output_format {
+    lua_version: full_moon::LuaVersion,
+) -> Result<Option<FormatResult>> {
+    // The syntax to classify changes with, when explaining diffs
+    let explain = opt.explain.then_some(lua_version);
+
+    let diff = match opt.output_format {
         opt::OutputFormat::Standard => output_diff::output_diff(
             original,
             expected,
             3,
             &format!("Diff in {file_name}:"),
             opt.color,
+            explain,
         ),
-        opt::OutputFormat::Unified => output_diff::output_diff_unified(original, expected),
+        opt::OutputFormat::Unified => {
+            output_diff::output_diff_unified(original, expected, file_name)
+        }
+        opt::OutputFormat::Patch => {
+            return Ok(
+                output_diff::output_diff_patch(original, expected, file_name)?
+                    .map(|patch| FormatResult::Patch(file_name.to_string(), patch)),
+            );
+        }
         opt::OutputFormat::Json => {
             output_diff::output_diff_json(original, expected)
-                .map(|mismatches| {
+                .map(|mut mismatches| {
+                    if let Some(lua_version) = explain {
+                        for mismatch in &mut mismatches {
+                            mismatch.categories = Some(classify_diff(
+                                &mismatch.original,
+                                &mismatch.expected,
+                                lua_version,
+                            ));
+                        }
+                    }
                     serde_json::to_vec(&json!({
                         "file": file_name,
                         "mismatches": mismatches
@@ -128,25 +162,100 @@ fn create_diff(
             if original == expected {
                 Ok(None)
             } else {
+                if let Some(lua_version) = explain {
+                    let mismatches =
+                        output_diff::output_diff_json(original, expected).unwrap_or_default();
+                    let mut counts = EXPLAINED_CATEGORY_COUNTS.lock().unwrap();
+                    for mismatch in mismatches {
+                        for category in
+                            classify_diff(&mismatch.original, &mismatch.expected, lua_version)
+                        {
+                            *counts.entry(category).or_default() += 1;
+                        }
+                    }
+                }
+
                 Ok(Some(format!("{file_name}\n").into_bytes()))
             }
         }
-    }
+        opt::OutputFormat::Sarif
+        | opt::OutputFormat::Checkstyle
+        | opt::OutputFormat::Junit
+        | opt::OutputFormat::GithubActions => {
+            return Ok(
+                output_diff::output_diff_json(original, expected).map(|mismatches| {
+                    FormatResult::Report(FileReport::from_mismatches(file_name, mismatches))
+                }),
+            );
+        }
+    }?;
+
+    Ok(diff.map(FormatResult::Diff))
 }
 
 fn format_file(
     path: &Path,
     config: Config,
-    range: Option<Range>,
     opt: &opt::Opt,
     verify_output: OutputVerification,
+    cache: Option<&FormatCache>,
+    changed_lines: Option<&ChangedLines>,
+    baseline: Option<&Baseline>,
 ) -> Result<FormatResult> {
-    let contents =
-        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    // When checking staged changes, check the contents which will be committed rather than the working tree
+    let staged_contents = match changed_lines {
+        Some(changed_lines) if opt.check => changed_lines
+            .staged_contents(path)
+            .with_context(|| format!("failed to read staged {}", path.display()))?,
+        _ => None,
+    };
+    let is_staged_contents = staged_contents.is_some();
+    let contents = match staged_contents {
+        Some(contents) => contents,
+        None => fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?,
+    };
+
+    let changed_ranges = changed_lines.map(|changed_lines| match is_staged_contents {
+        true => changed_lines.staged_ranges(path, &contents),
+        false => changed_lines.ranges(path, &contents),
+    });
+    let ranges = match changed_ranges {
+        Some(Some(ranges)) => ranges,
+        Some(None) => {
+            debug!("skipping {}: no changed lines", path.display());
+            return Ok(FormatResult::Complete);
+        }
+        None => opt.range(&contents).into_iter().collect(),
+    };
+    let transformations = opt.transformations();
+    let is_partial_format = !ranges.is_empty() || transformations.is_some();
+
+    // Skip formatting entirely if the cache knows this file is already formatted
+    let fingerprint = cache.map(|_| FormatCache::fingerprint(&contents, &config));
+    if let (Some(cache), Some(fingerprint)) = (cache, fingerprint) {
+        if cache.is_formatted(path, fingerprint) {
+            debug!("skipping {}: already formatted (cached)", path.display());
+            // The file may have been unformatted when the baseline was last updated
+            if let (Some(baseline), true) = (baseline, opt.check && opt.update_baseline) {
+                baseline.record(path, None);
+            }
+            return Ok(FormatResult::Complete);
+        }
+    }
 
     let before_formatting = Instant::now();
-    let formatted_contents = format_code(&contents, config, range, verify_output)
-        .with_context(|| format!("could not format file {}", path.display()))?;
+    let formatted_contents = match &transformations {
+        Some(transformations) => format_code_with_transformations(
+            &contents,
+            config,
+            &ranges,
+            transformations,
+            verify_output,
+        ),
+        None => format_code_with_ranges(&contents, config, &ranges, verify_output),
+    }
+    .with_context(|| format!("could not format file {}", path.display()))?;
     let after_formatting = Instant::now();
 
     debug!(
@@ -161,18 +270,46 @@ fn format_file(
             &contents,
             &formatted_contents,
             path.display().to_string().as_str(),
+            config.syntax.into(),
         )
         .context("failed to create diff")?;
 
-        match diff {
-            Some(diff) => Ok(FormatResult::Diff(diff)),
-            None => Ok(FormatResult::Complete),
+        if let (Some(cache), Some(fingerprint)) = (cache, fingerprint) {
+            // Only a full format tells us whether the whole file is formatted
+            match (&diff, is_partial_format) {
+                (None, false) => cache.mark_formatted(path, fingerprint),
+                (Some(_), _) => cache.mark_unformatted(path),
+                (None, true) => (),
+            }
+        }
+
+        if let Some(baseline) = baseline {
+            if opt.update_baseline {
+                baseline.record(path, diff.as_ref().map(|_| contents.as_str()));
+                return Ok(FormatResult::Complete);
+            }
+
+            if diff.is_some() && baseline.contains(path, &contents) {
+                debug!("skipping {}: unformatted, but in baseline", path.display());
+                return Ok(FormatResult::Complete);
+            }
         }
+
+        Ok(diff.unwrap_or(FormatResult::Complete))
     } else {
         if formatted_contents != contents {
-            fs::write(path, formatted_contents)
+            fs::write(path, &formatted_contents)
                 .with_context(|| format!("could not write to {}", path.display()))?;
         }
+
+        if let Some(cache) = cache {
+            match is_partial_format {
+                false => cache
+                    .mark_formatted(path, FormatCache::fingerprint(&formatted_contents, &config)),
+                true => cache.mark_unformatted(path),
+            }
+        }
+
         Ok(FormatResult::Complete)
     }
 }
@@ -182,29 +319,66 @@ fn format_file(
 fn format_string(
     input: String,
     config: Config,
-    range: Option<Range>,
     opt: &opt::Opt,
     verify_output: OutputVerification,
     should_skip: bool,
 ) -> Result<FormatResult> {
-    let formatted_contents = if should_skip {
-        input.clone()
+    let (formatted_contents, cursor_offset) = if should_skip {
+        (input.clone(), opt.cursor_offset)
     } else {
-        format_code(&input, config, range, verify_output).context("failed to format from stdin")?
+        match opt.cursor_offset {
+            Some(cursor_offset) => {
+                let formatted = format_code_with_cursor(
+                    &input,
+                    config,
+                    opt.range(&input),
+                    cursor_offset,
+                    verify_output,
+                )
+                .context("failed to format from stdin")?;
+                (formatted.code, Some(formatted.cursor_offset))
+            }
+            None => {
+                let range = opt.range(&input);
+                let formatted = match opt.transformations() {
+                    Some(transformations) => format_code_with_transformations(
+                        &input,
+                        config,
+                        range.as_slice(),
+                        &transformations,
+                        verify_output,
+                    ),
+                    None => {
+                        format_code_with_ranges(&input, config, range.as_slice(), verify_output)
+                    }
+                };
+                (formatted.context("failed to format from stdin")?, None)
+            }
+        }
     };
 
     if opt.check {
-        let diff = create_diff(opt, &input, &formatted_contents, "stdin")
-            .context("failed to create diff")?;
+        let diff = create_diff(
+            opt,
+            &input,
+            &formatted_contents,
+            "stdin",
+            config.syntax.into(),
+        )
+        .context("failed to create diff")?;
 
-        match diff {
-            Some(diff) => Ok(FormatResult::Diff(diff)),
-            None => Ok(FormatResult::Complete),
-        }
+        Ok(diff.unwrap_or(FormatResult::Complete))
     } else {
-        Ok(FormatResult::SuccessBufferedOutput(
-            formatted_contents.into_bytes(),
-        ))
+        // When tracking the cursor, its new position is output on the first line, before the formatted code
+        let output = match cursor_offset {
+            Some(cursor_offset) => format!(
+                "{}\n{formatted_contents}",
+                serde_json::json!({ "cursor_offset": cursor_offset })
+            ),
+            None => formatted_contents,
+        };
+
+        Ok(FormatResult::SuccessBufferedOutput(output.into_bytes()))
     }
 }
 
@@ -223,10 +397,32 @@ fn format(opt: opt::Opt) -> Result<i32> {
         }
     }
 
+    if opt.print_config_schema {
+        #[cfg(feature = "schema")]
+        {
+            return config_schema::print_config_schema();
+        }
+        #[cfg(not(feature = "schema"))]
+        {
+            bail!("attempted to print the configuration schema, but this binary was not built with 'schema' feature enabled")
+        }
+    }
+
     if opt.files.is_empty() {
         bail!("no files provided");
     }
 
+    if opt.print_config {
+        #[cfg(feature = "serialize")]
+        {
+            return print_config::print_config(&opt);
+        }
+        #[cfg(not(feature = "serialize"))]
+        {
+            bail!("attempted to print configuration, but this binary was not built with 'serialize' feature enabled")
+        }
+    }
+
     // Check for incompatible options
     if !opt.check
         && matches!(
@@ -236,18 +432,28 @@ fn format(opt: opt::Opt) -> Result<i32> {
     {
         bail!("--output-format=unified and --output-format=summary can only be used when --check is enabled");
     }
+    if !opt.check
+        && (matches!(opt.output_format, opt::OutputFormat::Patch)
+            || ReportFormat::from_output_format(opt.output_format).is_some())
+    {
+        bail!(
+            "--output-format={:?} can only be used when --check is enabled",
+            opt.output_format
+        );
+    }
+
+    if opt.baseline.is_some() && !opt.check {
+        bail!("--baseline can only be used when --check is enabled");
+    }
+
+    if opt.cursor_offset.is_some() && opt.files != [Path::new("-")] {
+        bail!("--cursor-offset can only be used when formatting a single input from stdin");
+    }
 
     // Load the configuration
     let opt_for_config_resolver = opt.clone();
     let mut config_resolver = config::ConfigResolver::new(&opt_for_config_resolver)?;
 
-    // Create range if provided
-    let range = if opt.range_start.is_some() || opt.range_end.is_some() {
-        Some(Range::from_values(opt.range_start, opt.range_end))
-    } else {
-        None
-    };
-
     // Determine if we need to verify the output
     let verify_output = if opt.verify {
         OutputVerification::Full
@@ -257,46 +463,46 @@ fn format(opt: opt::Opt) -> Result<i32> {
 
     let cwd = std::env::current_dir()?;
 
-    // Build WalkBuilder with the files given, using any overrides set
-    let mut walker_builder = WalkBuilder::new(&opt.files[0]);
-    for file_path in &opt.files[1..] {
-        walker_builder.add(file_path);
-    }
-
-    walker_builder
-        .standard_filters(true)
-        .hidden(!opt.allow_hidden)
-        .parents(true)
-        .git_exclude(!opt.no_ignore_vcs)
-        .git_global(!opt.no_ignore_vcs)
-        .git_ignore(!opt.no_ignore_vcs)
-        .add_custom_ignore_filename(".styluaignore");
-
-    // Look for an ignore file in the current working directory
-    let ignore_path = cwd.join(".styluaignore");
-    if ignore_path.is_file() {
-        walker_builder.add_ignore(ignore_path);
-    }
-
-    let use_default_glob = match opt.glob {
-        Some(ref globs) => {
-            // Build overriders with any patterns given
-            let mut overrides = OverrideBuilder::new(cwd);
-            for pattern in globs {
-                overrides.add(pattern)?;
-            }
-            let overrides = overrides.build()?;
-            walker_builder.overrides(overrides);
-            // We shouldn't use the default glob anymore
-            false
-        }
-        None => true,
+    // Load the formatting cache if enabled
+    let cache = if opt.cache {
+        let location = cwd.join(
+            opt.cache_location
+                .as_deref()
+                .unwrap_or_else(|| Path::new(cache::DEFAULT_CACHE_LOCATION)),
+        );
+        Some(Arc::new(FormatCache::load(location, cwd.clone())))
+    } else {
+        None
+    };
+
+    // Load the baseline of unformatted files if provided. It is only created if we are updating it
+    let baseline = opt
+        .baseline
+        .as_ref()
+        .map(|location| Baseline::load(location, cwd.clone(), opt.update_baseline).map(Arc::new))
+        .transpose()?;
+
+    // Load the lines changed in the git repository, if we should only format changed lines
+    let changed_since = match (&opt.changed_since, opt.staged) {
+        (Some(revision), _) => Some(ChangedSince::Revision(revision.clone())),
+        (None, true) => Some(ChangedSince::Staged),
+        (None, false) => None,
     };
+    let changed_lines = changed_since
+        .map(|changed_since| ChangedLines::load(&cwd, &changed_since).map(Arc::new))
+        .transpose()
+        .context("could not determine changed lines")?;
+
+    // Build WalkBuilder with the files given, using any overrides set
+    let walker_builder = create_walker_builder(&opt.files, &opt, &cwd)?;
+    // If any glob patterns were given, we shouldn't use the default glob anymore
+    let use_default_glob = opt.glob.is_none();
 
     debug!("creating a pool with {} threads", opt.num_threads);
     let pool = ThreadPool::new(std::cmp::max(opt.num_threads, 2)); // Use a minimum of 2 threads, because we need at least one output reader as well as a formatter
     let (tx, rx) = crossbeam_channel::unbounded::<Result<_>>();
     let output_format = opt.output_format;
+    let report_format = ReportFormat::from_output_format(output_format);
     let opt = Arc::new(opt);
 
     // Output a header if in summary mode
@@ -312,6 +518,9 @@ fn format(opt: opt::Opt) -> Result<i32> {
 
     // Create a thread to handle the formatting output
     pool.execute(move || {
+        let mut reports = Vec::new();
+        let mut patches = Vec::new();
+
         for output in rx {
             match output {
                 Ok(result) => match result {
@@ -340,34 +549,78 @@ fn format(opt: opt::Opt) -> Result<i32> {
                             Err(err) => error!("{:#}", err),
                         }
                     }
+                    FormatResult::Report(report) => {
+                        if EXIT_CODE.load(Ordering::SeqCst) != 2 {
+                            EXIT_CODE.store(1, Ordering::SeqCst);
+                        }
+
+                        UNFORMATTED_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+                        reports.push(report);
+                    }
+                    FormatResult::Patch(file, patch) => {
+                        if EXIT_CODE.load(Ordering::SeqCst) != 2 {
+                            EXIT_CODE.store(1, Ordering::SeqCst);
+                        }
+
+                        UNFORMATTED_FILE_COUNT.fetch_add(1, Ordering::SeqCst);
+                        patches.push((file, patch));
+                    }
+                },
+                Err(err) if report_format.is_some() => match as_parse_error(&err) {
+                    // Parse errors are included in the report, rather than logged
+                    Some((file, errs)) => {
+                        EXIT_CODE.store(2, Ordering::SeqCst);
+                        reports.push(FileReport::from_parse_errors(file, errs));
+                    }
+                    None => error!("{:#}", err),
                 },
                 Err(err) if matches!(output_format, opt::OutputFormat::Json) => {
-                    match err.downcast_ref::<ErrorFileWrapper>() {
-                        Some(ErrorFileWrapper { file, error }) => {
-                            match error.downcast_ref::<stylua_lib::Error>() {
-                                Some(stylua_lib::Error::ParseError(err)) => {
-                                    let structured_err =
-                                        convert_parse_error_to_json(file, err.to_vec());
-                                    // Force write to stderr directly
-                                    // TODO: can we do this through error! instead?
-                                    let stderr = stderr();
-                                    let mut handle = stderr.lock();
-                                    match handle.write_all(structured_err.to_string().as_bytes()) {
-                                        Ok(_) => (),
-                                        Err(err) => {
-                                            error!("could not output to stdout: {:#}", err)
-                                        }
-                                    };
+                    match as_parse_error(&err) {
+                        Some((file, errs)) => {
+                            let structured_err = convert_parse_error_to_json(file, errs.to_vec());
+                            // Force write to stderr directly
+                            // TODO: can we do this through error! instead?
+                            let stderr = stderr();
+                            let mut handle = stderr.lock();
+                            match handle.write_all(structured_err.to_string().as_bytes()) {
+                                Ok(_) => (),
+                                Err(err) => {
+                                    error!("could not output to stdout: {:#}", err)
                                 }
-                                _ => error!("{:#}", err),
-                            }
+                            };
                         }
-                        _ => error!("{:#}", err),
+                        None => error!("{:#}", err),
                     }
                 }
                 Err(err) => error!("{:#}", err),
             }
         }
+
+        // Files are checked in parallel, so sort the patches to keep the combined patch stable
+        if !patches.is_empty() {
+            patches.sort_by(|a, b| a.0.cmp(&b.0));
+            let stdout = stdout();
+            let mut handle = stdout.lock();
+            for (_, patch) in patches {
+                if let Err(err) = handle.write_all(&patch) {
+                    error!("could not output to stdout: {:#}", err);
+                    break;
+                }
+            }
+        }
+
+        // Reports are a single document, so can only be output once every file has been checked
+        if let Some(report_format) = report_format {
+            // Files are checked in parallel, so sort them to keep the report stable
+            reports.sort_by(|a, b| a.file.cmp(&b.file));
+            let report = report::render_report(report_format, &reports);
+            let stdout = stdout();
+            let mut handle = stdout.lock();
+            match handle.write_all(report.as_bytes()) {
+                Ok(_) => (),
+                Err(err) => error!("could not output to stdout: {:#}", err),
+            }
+        }
     });
 
     let walker = walker_builder.build();
@@ -404,7 +657,6 @@ fn format(opt: opt::Opt) -> Result<i32> {
                                     format_string(
                                         buf,
                                         config,
-                                        range,
                                         &opt,
                                         verify_output,
                                         should_skip_format,
@@ -432,20 +684,11 @@ fn format(opt: opt::Opt) -> Result<i32> {
 
                     if path.is_file() {
                         // If the user didn't provide a glob pattern, we should match against our default one
-                        if use_default_glob && should_respect_ignores(opt.as_ref(), path.as_path())
+                        if use_default_glob
+                            && should_respect_ignores(opt.as_ref(), path.as_path())
+                            && !matches_default_glob(&path)
                         {
-                            lazy_static::lazy_static! {
-                                static ref DEFAULT_GLOB: globset::GlobSet = {
-                                    let mut builder = globset::GlobSetBuilder::new();
-                                    builder.add(globset::Glob::new("**/*.lua").expect("cannot create default glob"));
-                                    #[cfg(feature = "luau")]
-                                    builder.add(globset::Glob::new("**/*.luau").expect("cannot create default luau glob"));
-                                    builder.build().expect("cannot build default globset")
-                                };
-                            }
-                            if !DEFAULT_GLOB.is_match(&path) {
-                                continue;
-                            }
+                            continue;
                         }
 
                         // If `--respect-ignores` was given and this is an explicit file path,
@@ -460,17 +703,27 @@ fn format(opt: opt::Opt) -> Result<i32> {
                         let config = config_resolver.load_configuration(&path)?;
 
                         let tx = tx.clone();
+                        let cache = cache.clone();
+                        let changed_lines = changed_lines.clone();
+                        let baseline = baseline.clone();
                         pool.execute(move || {
                             tx.send(
-                                format_file(&path, config, range, &opt, verify_output).map_err(
-                                    |error| {
-                                        ErrorFileWrapper {
-                                            file: path.display().to_string(),
-                                            error,
-                                        }
-                                        .into()
-                                    },
-                                ),
+                                format_file(
+                                    &path,
+                                    config,
+                                    &opt,
+                                    verify_output,
+                                    cache.as_deref(),
+                                    changed_lines.as_deref(),
+                                    baseline.as_deref(),
+                                )
+                                .map_err(|error| {
+                                    ErrorFileWrapper {
+                                        file: path.display().to_string(),
+                                        error,
+                                    }
+                                    .into()
+                                }),
                             )
                             .unwrap()
                         });
@@ -495,6 +748,16 @@ fn format(opt: opt::Opt) -> Result<i32> {
     drop(tx);
     pool.join();
 
+    if let Some(cache) = cache {
+        if let Err(error) = cache.save() {
+            warn!("{:#}", error);
+        }
+    }
+
+    if let (Some(baseline), true) = (&baseline, opt.update_baseline) {
+        baseline.save()?;
+    }
+
     // Output summary
 
     if matches!(opt.output_format, opt::OutputFormat::Summary) {
@@ -520,6 +783,19 @@ fn format(opt: opt::Opt) -> Result<i32> {
                     .force_styling(opt.color.should_use_color()),
                 if file_count == 1 { "" } else { "s" }
             );
+
+            if opt.explain {
+                for (category, count) in EXPLAINED_CATEGORY_COUNTS.lock().unwrap().iter() {
+                    println!(
+                        "  {}: {} change{}",
+                        style(category)
+                            .cyan()
+                            .force_styling(opt.color.should_use_color()),
+                        count,
+                        if *count == 1 { "" } else { "s" }
+                    );
+                }
+            }
         }
     }
 
@@ -648,143 +924,298 @@ mod tests {
     }
 
     #[test]
-    fn test_stylua_ignore() {
+    fn test_cache_skips_formatted_files() {
         let cwd = construct_tree!({
-            ".styluaignore": "ignored/",
             "foo.lua": "local   x    =   1",
-            "ignored/bar.lua": "local   x    =   1",
         });
 
         let mut cmd = create_stylua();
-        cmd.current_dir(cwd.path()).arg(".").assert().success();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--cache", "--verbose", "foo.lua"])
+            .assert()
+            .success()
+            .get_output()
+            .stderr
+            .clone();
+        assert!(!String::from_utf8(output)
+            .unwrap()
+            .contains("already formatted (cached)"));
 
         cwd.child("foo.lua").assert("local x = 1\n");
-        cwd.child("ignored/bar.lua").assert("local   x    =   1");
+        assert!(cwd.child(".stylua-cache").path().is_file());
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--cache", "--check", "--verbose", "foo.lua"])
+            .assert()
+            .success()
+            .get_output()
+            .stderr
+            .clone();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("already formatted (cached)"));
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn explicitly_provided_files_dont_check_ignores() {
+    fn test_cache_invalidated_when_contents_or_configuration_change() {
         let cwd = construct_tree!({
-            ".styluaignore": "foo.lua",
-            "foo.lua": "local   x    =   1",
+            "foo.lua": "local x = \"hello\"\n",
         });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .arg("foo.lua")
+            .args(["--cache", "--check", "foo.lua"])
             .assert()
             .success();
 
-        cwd.child("foo.lua").assert("local x = 1\n");
+        // A different configuration should not use the cached result
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args([
+                "--cache",
+                "--check",
+                "--quote-style",
+                "ForceSingle",
+                "foo.lua",
+            ])
+            .assert()
+            .failure()
+            .code(1);
+
+        // Changed contents should not use the cached result
+        cwd.child("foo.lua")
+            .write_str("local   x = \"hello\"\n")
+            .unwrap();
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--cache", "--check", "foo.lua"])
+            .assert()
+            .failure()
+            .code(1);
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn explicitly_provided_files_dont_check_ignores_stdin() {
+    fn test_cache_location() {
         let cwd = construct_tree!({
-            ".styluaignore": "foo.lua",
+            "foo.lua": "local x = 1\n",
         });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--stdin-filepath", "foo.lua", "-"])
-            .write_stdin("local   x    =   1")
+            .args([
+                "--cache",
+                "--cache-location",
+                "cache/stylua.json",
+                "foo.lua",
+            ])
             .assert()
+            .success();
+
+        assert!(cwd.child("cache/stylua.json").path().is_file());
+        assert!(!cwd.child(".stylua-cache").path().exists());
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_baseline_only_fails_on_new_problems() {
+        let cwd = construct_tree!({
+            "legacy.lua": "local   x = 1\n",
+            "edited.lua": "local   y = 2\n",
+            "formatted.lua": "local z = 3\n",
+        });
+        let check_with_baseline = |extra_args: &[&str]| {
+            let mut cmd = create_stylua();
+            cmd.current_dir(cwd.path())
+                .args(["--check", "--baseline", ".stylua-baseline.json", "."])
+                .args(extra_args)
+                .assert()
+        };
+
+        // The baseline must exist unless we are creating it
+        check_with_baseline(&[]).failure().code(2);
+
+        check_with_baseline(&["--update-baseline"])
             .success()
-            .stdout("local x = 1\n");
+            .stdout("");
+        let baseline: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(cwd.child(".stylua-baseline.json").path()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            baseline["files"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["edited.lua", "legacy.lua"]
+        );
+
+        check_with_baseline(&[]).success().stdout("");
+
+        // Files which are new, or were edited but are still unformatted, fail the check
+        cwd.child("edited.lua")
+            .write_str("local   y = 3\n")
+            .unwrap();
+        cwd.child("new.lua").write_str("local   w = 4\n").unwrap();
+        let output = check_with_baseline(&["--output-format=summary"])
+            .failure()
+            .code(1)
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("edited.lua"));
+        assert!(output.contains("new.lua"));
+        assert!(!output.contains("legacy.lua"));
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn explicitly_provided_files_not_in_cwd() {
+    fn test_baseline_updated_for_cached_files() {
         let cwd = construct_tree!({
-            ".styluaignore": "foo.lua",
+            "foo.lua": "local   x = 1\n",
         });
+        let check = |extra_args: &[&str]| {
+            let mut cmd = create_stylua();
+            cmd.current_dir(cwd.path())
+                .args(["--check", "--cache", "foo.lua"])
+                .args(extra_args)
+                .assert()
+        };
+        let baseline_files = || {
+            let baseline: serde_json::Value = serde_json::from_str(
+                &std::fs::read_to_string(cwd.child(".stylua-baseline.json").path()).unwrap(),
+            )
+            .unwrap();
+            baseline["files"].as_object().unwrap().len()
+        };
+
+        check(&["--baseline", ".stylua-baseline.json", "--update-baseline"]).success();
+        assert_eq!(baseline_files(), 1);
+
+        // Once the file is formatted and cached, updating the baseline removes it
+        cwd.child("foo.lua").write_str("local x = 1\n").unwrap();
+        check(&[]).success();
+        check(&["--baseline", ".stylua-baseline.json", "--update-baseline"]).success();
+        assert_eq!(baseline_files(), 0);
 
-        let another = construct_tree!({});
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_baseline_requires_check() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x = 1\n",
+        });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
             .args([
-                "--respect-ignores",
-                "--stdin-filepath",
-                another.child("foo.lua").to_str().unwrap(),
-                "-",
+                "--baseline",
+                "baseline.json",
+                "--update-baseline",
+                "foo.lua",
             ])
-            .write_stdin("local   x    =   1")
             .assert()
-            .success()
-            .stdout("local x = 1\n");
+            .failure()
+            .code(2);
+        cwd.child("foo.lua").assert("local   x = 1\n");
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn test_respect_ignores() {
+    fn test_range_lines() {
         let cwd = construct_tree!({
-            ".styluaignore": "foo.lua",
-            "foo.lua": "local   x    =   1",
+            "foo.lua": "local   a   =   1\nlocal   b   =   2\nlocal   c   =   3\n",
         });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--respect-ignores", "foo.lua"])
+            .args([
+                "--range-start-line",
+                "2",
+                "--range-end-line",
+                "2",
+                "foo.lua",
+            ])
             .assert()
             .success();
 
-        cwd.child("foo.lua").assert("local   x    =   1");
+        cwd.child("foo.lua")
+            .assert("local   a   =   1\nlocal b = 2\nlocal   c   =   3\n");
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn test_respect_ignores_stdin() {
-        let cwd = construct_tree!({
-            ".styluaignore": "foo.lua",
-        });
+    fn test_range_lines_with_columns_stdin() {
+        let cwd = construct_tree!({});
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--respect-ignores", "--stdin-filepath", "foo.lua", "-"])
-            .write_stdin("local   x    =   1")
+            .args([
+                "--line-endings",
+                "Windows",
+                "--range-start-line",
+                "1:1",
+                "--range-end-line",
+                "2:5",
+                "-",
+            ])
+            .write_stdin("local   a   =   1\r\nlocal   b   =   2\r\n")
             .assert()
             .success()
-            .stdout("local   x    =   1");
+            .stdout("local a = 1\r\nlocal   b   =   2\r\n");
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn test_respect_ignores_directory_no_glob() {
-        // https://github.com/JohnnyMorganz/StyLua/issues/845
-        let cwd = construct_tree!({
-            ".styluaignore": "build/",
-            "build/foo.lua": "local   x    =   1",
-        });
+    fn test_range_lines_invalid_position() {
+        let mut cmd = create_stylua();
+        cmd.args(["--range-start-line", "0:1", "-"])
+            .write_stdin("local x = 1\n")
+            .assert()
+            .failure();
+    }
+
+    #[test]
+    fn test_cursor_offset() {
+        let cwd = construct_tree!({});
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--check", "--respect-ignores", "build/foo.lua"])
+            .args(["--cursor-offset", "21", "-"])
+            .write_stdin("local   x   =    foo( 1,2 )")
             .assert()
-            .success();
+            .success()
+            .stdout("{\"cursor_offset\":14}\nlocal x = foo(1, 2)\n");
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn test_formatting_respects_gitignore() {
+    fn test_cursor_offset_requires_stdin() {
         let cwd = construct_tree!({
-            ".git/dummy.txt": "", // Need a .git folder for .gitignore lookup
-            ".gitignore": "foo.lua",
             "foo.lua": "local   x    =   1",
         });
 
         let mut cmd = create_stylua();
-        cmd.current_dir(cwd.path()).args(["."]).assert().success();
+        cmd.current_dir(cwd.path())
+            .args(["--cursor-offset", "0", "foo.lua"])
+            .assert()
+            .failure()
+            .code(2);
 
         cwd.child("foo.lua").assert("local   x    =   1");
 
@@ -792,37 +1223,595 @@ mod tests {
     }
 
     #[test]
-    fn test_formatting_still_formats_gitignore_files_if_requested() {
+    fn test_only_applies_selected_transformations() {
         let cwd = construct_tree!({
-            ".git/dummy.txt": "", // Need a .git folder for .gitignore lookup
-            ".gitignore": "foo.lua",
-            "foo.lua": "local   x    =   1",
+            "foo.lua": "local   x = foo 'hello'   \nif x then\n  print( 'bar' )\nend\n",
         });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--no-ignore-vcs", "."])
+            .args(["--only", "quotes,trailing_whitespace", "foo.lua"])
             .assert()
             .success();
 
-        cwd.child("foo.lua").assert("local x = 1\n");
+        cwd.child("foo.lua")
+            .assert("local   x = foo \"hello\"\nif x then\n  print( \"bar\" )\nend\n");
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args([
+                "--only",
+                "call_parentheses",
+                "--only",
+                "indentation",
+                "foo.lua",
+            ])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua")
+            .assert("local   x = foo(\"hello\")\nif x then\n\tprint( \"bar\" )\nend\n");
 
         cwd.close().unwrap();
     }
 
     #[test]
-    fn test_stdin_filepath_respects_cwd_configuration_next_to_file() {
+    fn test_github_actions_report_includes_diffs_and_parse_errors() {
         let cwd = construct_tree!({
-            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "foo.lua": "local x = 1\nlocal   y = 2\n",
+            "bar.lua": "local x = \n",
+            "formatted.lua": "local x = 1\n",
         });
 
         let mut cmd = create_stylua();
         cmd.current_dir(cwd.path())
-            .args(["--stdin-filepath", "foo.lua", "-"])
-            .write_stdin("local x = \"hello\"")
+            .args(["--check", "--output-format=GithubActions", "."])
             .assert()
-            .success()
-            .stdout("local x = 'hello'\n");
+            .failure()
+            .code(2)
+            .stdout(
+                "::error file=./bar.lua,line=1,endLine=1,col=9,title=Code could not be parsed::unexpected token `=`: expected an expression\n\
+                 ::error file=./foo.lua,line=2,endLine=2,title=Code is not formatted::code is not formatted, expected:%0Alocal y = 2\n",
+            );
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_explain_categorises_diffs() {
+        let cwd = construct_tree!({
+            "foo.lua": "local x = 'hello'\n\nlocal   y = 2\n",
+        });
+        let check = |output_format: &str| {
+            let mut cmd = create_stylua();
+            let output = cmd
+                .current_dir(cwd.path())
+                .args(["--check", "--explain", "--color=never"])
+                .arg(format!("--output-format={output_format}"))
+                .arg("foo.lua")
+                .assert()
+                .failure()
+                .code(1)
+                .get_output()
+                .stdout
+                .clone();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            check("Standard"),
+            "Diff in foo.lua:\nnote: whitespace, quote style\n1        |-local x = 'hello'\n    1    |+local x = \"hello\"\n2   2    | \n3        |-local   y = 2\n    3    |+local y = 2\n"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&check("Json")).unwrap();
+        assert_eq!(
+            json["mismatches"][0]["categories"],
+            serde_json::json!(["quote_style"])
+        );
+        assert_eq!(
+            json["mismatches"][1]["categories"],
+            serde_json::json!(["whitespace"])
+        );
+
+        let summary = check("Summary");
+        assert!(summary.ends_with("  whitespace: 1 change\n  quote style: 1 change\n"));
+
+        // Explanations are opt-in
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--check", "--output-format=Json", "foo.lua"])
+            .assert()
+            .failure()
+            .get_output()
+            .stdout
+            .clone();
+        assert!(!String::from_utf8(output).unwrap().contains("categories"));
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_patch_can_be_applied() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x = 1\n",
+            "nested/bar.lua": "local y   = 2\nlocal z = 3\n",
+            "formatted.lua": "local w = 4\n",
+        });
+
+        let mut cmd = create_stylua();
+        let patch = cmd
+            .current_dir(cwd.path())
+            .args(["--check", "--output-format=Patch", "."])
+            .assert()
+            .failure()
+            .code(1)
+            .get_output()
+            .stdout
+            .clone();
+        let patch = String::from_utf8(patch).unwrap();
+        assert!(patch.starts_with("diff --git a/foo.lua b/foo.lua\n--- a/foo.lua\n+++ b/foo.lua\n"));
+        assert!(patch.contains("diff --git a/nested/bar.lua b/nested/bar.lua\n"));
+
+        std::fs::write(cwd.child("stylua.patch").path(), patch).unwrap();
+        let status = std::process::Command::new("git")
+            .current_dir(cwd.path())
+            .args(["apply", "stylua.patch"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        cwd.child("foo.lua").assert("local x = 1\n");
+        cwd.child("nested/bar.lua")
+            .assert("local y = 2\nlocal z = 3\n");
+        cwd.child("formatted.lua").assert("local w = 4\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_patch_rejects_files_outside_current_directory() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x = 1\n",
+            "nested/bar.lua": "local y = 2\n",
+        });
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.child("nested").path())
+            .args(["--check", "--output-format=Patch", "../foo.lua"])
+            .assert()
+            .failure()
+            .get_output()
+            .clone();
+        assert!(output.stdout.is_empty());
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("outside of the current directory"));
+
+        // Files which are already formatted don't appear in the patch
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.child("nested").path())
+            .args(["--check", "--output-format=Patch", "../nested/bar.lua"])
+            .assert()
+            .success()
+            .stdout("");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_unified_diff_files_outside_current_directory() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x = 1\n",
+            "nested/bar.lua": "local y = 2\n",
+        });
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.child("nested").path())
+            .args(["--check", "--output-format=Unified", "../foo.lua"])
+            .assert()
+            .code(1)
+            .get_output()
+            .stdout
+            .clone();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("--- ../foo.lua\n+++ ../foo.lua\n"));
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_checkstyle_report() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x = 1\n",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--check", "--output-format=checkstyle", "foo.lua"])
+            .assert()
+            .failure()
+            .code(1)
+            .stdout(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+  <file name="foo.lua">
+    <error line="1" severity="error" message="code is not formatted, expected:&#10;local x = 1" source="stylua.unformatted"/>
+  </file>
+</checkstyle>
+"#,
+            );
+
+        cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--output-format=checkstyle", "foo.lua"])
+            .assert()
+            .failure()
+            .code(2);
+        cwd.child("foo.lua").assert("local   x = 1\n");
+
+        cwd.close().unwrap();
+    }
+
+    fn git(cwd: &assert_fs::TempDir, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .current_dir(cwd.path())
+            .args([
+                "-c",
+                "user.name=stylua",
+                "-c",
+                "user.email=stylua@example.com",
+            ])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// Initialises a git repository in the directory, committing all of its files
+    fn init_git_repository(cwd: &assert_fs::TempDir) {
+        git(cwd, &["init", "--quiet"]);
+        git(cwd, &["add", "."]);
+        git(
+            cwd,
+            &["commit", "--quiet", "--no-gpg-sign", "-m", "initial"],
+        );
+    }
+
+    #[test]
+    fn test_changed_since_only_formats_changed_lines() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   a   =   1\nlocal b = 2\nlocal   c   =   3\n",
+            "unchanged.lua": "local   x   =   1\n",
+        });
+        init_git_repository(&cwd);
+
+        cwd.child("foo.lua")
+            .write_str("local   a   =   1\nlocal   b   =   20\nlocal   c   =   3\n")
+            .unwrap();
+        cwd.child("new.lua")
+            .write_str("local   y   =   2\n")
+            .unwrap();
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--changed-since", "HEAD", "."])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua")
+            .assert("local   a   =   1\nlocal b = 20\nlocal   c   =   3\n");
+        cwd.child("unchanged.lua").assert("local   x   =   1\n");
+        cwd.child("new.lua").assert("local y = 2\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_changed_since_non_ascii_file_name() {
+        let cwd = construct_tree!({
+            "café.lua": "local a = 1\n",
+            "plain.lua": "local a = 1\n",
+        });
+        init_git_repository(&cwd);
+
+        cwd.child("café.lua")
+            .write_str("local   a   =   10\n")
+            .unwrap();
+        cwd.child("plain.lua")
+            .write_str("local   a   =   10\n")
+            .unwrap();
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--changed-since", "HEAD", "--check", "."])
+            .assert()
+            .failure()
+            .code(1)
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("café.lua"));
+        assert!(output.contains("plain.lua"));
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_staged_only_formats_staged_lines() {
+        let cwd = construct_tree!({
+            "foo.lua": "local a = 1\nlocal b = 2\nlocal c = 3\n",
+        });
+        init_git_repository(&cwd);
+
+        cwd.child("foo.lua")
+            .write_str("local   a   =   10\nlocal b = 2\nlocal c = 3\n")
+            .unwrap();
+        git(&cwd, &["add", "foo.lua"]);
+        cwd.child("foo.lua")
+            .write_str("local   a   =   10\nlocal b = 2\nlocal   c   =   30\n")
+            .unwrap();
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--staged", "--check", "."])
+            .assert()
+            .failure()
+            .code(1);
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--staged", "."])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua")
+            .assert("local a = 10\nlocal b = 2\nlocal   c   =   30\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_staged_with_unstaged_changes_above() {
+        let cwd = construct_tree!({
+            "foo.lua": "local a = 1\nlocal b = 2\nlocal c = 3\n",
+        });
+        init_git_repository(&cwd);
+
+        cwd.child("foo.lua")
+            .write_str("local a = 1\nlocal b = 2\nlocal   c   =   30\n")
+            .unwrap();
+        git(&cwd, &["add", "foo.lua"]);
+        cwd.child("foo.lua")
+            .write_str("local   x   =   0\nlocal a = 1\nlocal b = 2\nlocal   c   =   30\n")
+            .unwrap();
+
+        // The staged contents are checked, so only the staged line is reported
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--staged", "--check", "--output-format=Unified", "foo.lua"])
+            .assert()
+            .failure()
+            .code(1)
+            .get_output()
+            .stdout
+            .clone();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("-local   c   =   30\n+local c = 30\n"));
+        assert!(!output.contains("local   x"));
+
+        // When formatting the working tree, the staged line is found after the unstaged line above it
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--staged", "foo.lua"])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua")
+            .assert("local   x   =   0\nlocal a = 1\nlocal b = 2\nlocal c = 30\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_changed_since_outside_git_repository() {
+        let cwd = construct_tree!({
+            "foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--changed-since", "HEAD", "foo.lua"])
+            .assert()
+            .failure()
+            .code(2);
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_stylua_ignore() {
+        let cwd = construct_tree!({
+            ".styluaignore": "ignored/",
+            "foo.lua": "local   x    =   1",
+            "ignored/bar.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path()).arg(".").assert().success();
+
+        cwd.child("foo.lua").assert("local x = 1\n");
+        cwd.child("ignored/bar.lua").assert("local   x    =   1");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn explicitly_provided_files_dont_check_ignores() {
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+            "foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .arg("foo.lua")
+            .assert()
+            .success();
+
+        cwd.child("foo.lua").assert("local x = 1\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn explicitly_provided_files_dont_check_ignores_stdin() {
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--stdin-filepath", "foo.lua", "-"])
+            .write_stdin("local   x    =   1")
+            .assert()
+            .success()
+            .stdout("local x = 1\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn explicitly_provided_files_not_in_cwd() {
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+        });
+
+        let another = construct_tree!({});
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args([
+                "--respect-ignores",
+                "--stdin-filepath",
+                another.child("foo.lua").to_str().unwrap(),
+                "-",
+            ])
+            .write_stdin("local   x    =   1")
+            .assert()
+            .success()
+            .stdout("local x = 1\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_respect_ignores() {
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+            "foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--respect-ignores", "foo.lua"])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua").assert("local   x    =   1");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_respect_ignores_stdin() {
+        let cwd = construct_tree!({
+            ".styluaignore": "foo.lua",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--respect-ignores", "--stdin-filepath", "foo.lua", "-"])
+            .write_stdin("local   x    =   1")
+            .assert()
+            .success()
+            .stdout("local   x    =   1");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_respect_ignores_directory_no_glob() {
+        // https://github.com/JohnnyMorganz/StyLua/issues/845
+        let cwd = construct_tree!({
+            ".styluaignore": "build/",
+            "build/foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--check", "--respect-ignores", "build/foo.lua"])
+            .assert()
+            .success();
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_formatting_respects_gitignore() {
+        let cwd = construct_tree!({
+            ".git/dummy.txt": "", // Need a .git folder for .gitignore lookup
+            ".gitignore": "foo.lua",
+            "foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path()).args(["."]).assert().success();
+
+        cwd.child("foo.lua").assert("local   x    =   1");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_formatting_still_formats_gitignore_files_if_requested() {
+        let cwd = construct_tree!({
+            ".git/dummy.txt": "", // Need a .git folder for .gitignore lookup
+            ".gitignore": "foo.lua",
+            "foo.lua": "local   x    =   1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--no-ignore-vcs", "."])
+            .assert()
+            .success();
+
+        cwd.child("foo.lua").assert("local x = 1\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_stdin_filepath_respects_cwd_configuration_next_to_file() {
+        let cwd = construct_tree!({
+            "stylua.toml": "quote_style = 'AutoPreferSingle'",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--stdin-filepath", "foo.lua", "-"])
+            .write_stdin("local x = \"hello\"")
+            .assert()
+            .success()
+            .stdout("local x = 'hello'\n");
 
         cwd.close().unwrap();
     }
@@ -971,6 +1960,105 @@ mod tests {
         cwd.close().unwrap();
     }
 
+    #[test]
+    fn test_configuration_inherits_from_parent_configuration() {
+        let cwd = construct_tree!({
+            "stylua.toml": "indent_type = 'Spaces'\nindent_width = 2",
+            "build/stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "build/foo.lua": "local x = {\n\ta = \"hello\",\n}",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .arg("build/foo.lua")
+            .assert()
+            .success();
+
+        cwd.child("build/foo.lua")
+            .assert("local x = {\n  a = 'hello',\n}\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_configuration_root_stops_inheritance() {
+        let cwd = construct_tree!({
+            "stylua.toml": "indent_type = 'Spaces'\nindent_width = 2",
+            "build/stylua.toml": "root = true\nquote_style = 'AutoPreferSingle'",
+            "build/foo.lua": "local x = {\n  a = \"hello\",\n}",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .arg("build/foo.lua")
+            .assert()
+            .success();
+
+        cwd.child("build/foo.lua")
+            .assert("local x = {\n\ta = 'hello',\n}\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_configuration_extends_explicit_file() {
+        let cwd = construct_tree!({
+            "shared/base.toml": "root = true\nindent_type = 'Spaces'\nindent_width = 2",
+            "packages/foo/stylua.toml": "extends = '../../shared/base.toml'\nquote_style = 'AutoPreferSingle'",
+            "packages/foo/foo.lua": "local x = {\n\ta = \"hello\",\n}",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .arg("packages/foo/foo.lua")
+            .assert()
+            .success();
+
+        cwd.child("packages/foo/foo.lua")
+            .assert("local x = {\n  a = 'hello',\n}\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_configuration_extends_cycle_is_error() {
+        let cwd = construct_tree!({
+            "a.toml": "extends = 'b.toml'",
+            "b.toml": "extends = 'a.toml'",
+            "stylua.toml": "extends = 'a.toml'",
+            "foo.lua": "local x = 1",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .arg("foo.lua")
+            .assert()
+            .failure();
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_configuration_inherits_from_global_configuration_when_searching_parent_directories() {
+        let cwd = construct_tree!({
+            "xdg/stylua.toml": "indent_type = 'Spaces'\nindent_width = 2",
+            "project/stylua.toml": "quote_style = 'AutoPreferSingle'",
+            "project/foo.lua": "local x = {\n\ta = \"hello\",\n}",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path().join("project"))
+            .env("XDG_CONFIG_HOME", cwd.path().join("xdg"))
+            .args(["--search-parent-directories", "foo.lua"])
+            .assert()
+            .success();
+
+        cwd.child("project/foo.lua")
+            .assert("local x = {\n  a = 'hello',\n}\n");
+
+        cwd.close().unwrap();
+    }
+
     #[test]
     fn test_respect_config_path_override() {
         let cwd = construct_tree!({
@@ -1005,6 +2093,176 @@ mod tests {
         cwd.close().unwrap();
     }
 
+    #[test]
+    fn test_configuration_overrides_applied_to_matching_files() {
+        let cwd = construct_tree!({
+            "stylua.toml": "[[overrides]]\nfiles = [\"spec/**/*.lua\"]\nquote_style = 'AutoPreferSingle'\n",
+            "foo.lua": "local x = 'hello'",
+            "spec/nested/foo.lua": "local x = \"hello\"",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path()).arg(".").assert().success();
+
+        cwd.child("foo.lua").assert("local x = \"hello\"\n");
+        cwd.child("spec/nested/foo.lua")
+            .assert("local x = 'hello'\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    fn test_configuration_overrides_applied_for_stdin_filepath() {
+        let cwd = construct_tree!({
+            "stylua.toml": "[[overrides]]\nfiles = [\"*.luau\"]\nquote_style = 'AutoPreferSingle'\n",
+        });
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--stdin-filepath", "src/foo.luau", "-"])
+            .write_stdin("local x = \"hello\"")
+            .assert()
+            .success()
+            .stdout("local x = 'hello'\n");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "serialize"))]
+    fn test_print_config_hidden_without_serialize_feature() {
+        let mut cmd = create_stylua();
+        let output = cmd
+            .arg("--help")
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        let help = String::from_utf8(output).unwrap();
+        assert!(!help.contains("--print-config\n"));
+        assert!(!help.contains("--print-config-format"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "schema"))]
+    fn test_print_config_schema_hidden_without_schema_feature() {
+        let mut cmd = create_stylua();
+        let output = cmd
+            .arg("--help")
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert!(!String::from_utf8(output)
+            .unwrap()
+            .contains("--print-config-schema"));
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_print_config_shows_sources() {
+        let cwd = construct_tree!({
+            "stylua.toml": "indent_type = 'Spaces'\n\n[[overrides]]\nfiles = ['*.luau']\nquote_style = 'ForceSingle'\n",
+            "src/stylua.toml": "column_width = 80",
+            "src/foo.luau": "local x = 1",
+        });
+
+        let root_config = cwd.path().join("stylua.toml").display().to_string();
+        let src_config = cwd.path().join("src/stylua.toml").display().to_string();
+
+        let mut cmd = create_stylua();
+        cmd.current_dir(cwd.path())
+            .args(["--print-config", "--indent-width", "2", "src/foo.luau"])
+            .assert()
+            .success()
+            .stdout(format!(
+                "# Configuration for src/foo.luau
+block_newline_gaps = \"Never\" # default
+call_parentheses = \"Always\" # default
+collapse_simple_statement = \"Never\" # default
+column_width = 80 # {src_config}
+indent_type = \"Spaces\" # {root_config}
+indent_width = 2 # --indent-width
+line_endings = \"Unix\" # default
+no_call_parentheses = false # default
+quote_style = \"ForceSingle\" # {root_config} (overrides[0])
+space_after_function_names = \"Never\" # default
+syntax = \"All\" # default
+
+[sort_requires]
+enabled = false # default
+"
+            ));
+
+        // The file should not have been formatted
+        cwd.child("src/foo.luau").assert("local x = 1");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_print_config_json_for_stdin_filepath() {
+        let cwd = construct_tree!({
+            "stylua.toml": "column_width = 80",
+        });
+
+        let config_path = cwd.path().join("stylua.toml").display().to_string();
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args([
+                "--print-config",
+                "--print-config-format",
+                "Json",
+                "--stdin-filepath",
+                "foo.lua",
+                "-",
+            ])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+
+        let output: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(output["file"], "foo.lua");
+        assert_eq!(output["config"]["column_width"], 80);
+        assert_eq!(output["sources"]["column_width"], config_path.as_str());
+        assert_eq!(output["sources"]["indent_width"], "default");
+        assert_eq!(output["sources"]["sort_requires.enabled"], "default");
+
+        cwd.close().unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "serialize", feature = "editorconfig"))]
+    fn test_print_config_shows_editorconfig_sources() {
+        let cwd = construct_tree!({
+            ".editorconfig": "root = true\n\n[*.lua]\nindent_style = space\n",
+        });
+
+        let mut cmd = create_stylua();
+        let output = cmd
+            .current_dir(cwd.path())
+            .args(["--print-config", "--print-config-format", "Json", "foo.lua"])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+
+        let output: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(output["config"]["indent_type"], "Spaces");
+        assert_eq!(output["sources"]["indent_type"], ".editorconfig");
+        assert_eq!(output["sources"]["quote_style"], "default");
+
+        cwd.close().unwrap();
+    }
+
     #[test]
     fn test_uses_cli_overrides_instead_of_default_configuration() {
         let cwd = construct_tree!({
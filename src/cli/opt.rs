@@ -1,8 +1,9 @@
 use clap::{ArgEnum, StructOpt};
 use std::path::PathBuf;
+use std::str::FromStr;
 use stylua_lib::{
     BlockNewlineGaps, CallParenType, CollapseSimpleStatement, IndentType, LineEndings, LuaVersion,
-    QuoteStyle, SpaceAfterFunctionNames,
+    QuoteStyle, Range, SpaceAfterFunctionNames, Transformation,
 };
 
 lazy_static::lazy_static! {
@@ -48,6 +49,12 @@ pub struct Opt {
     #[structopt(long, arg_enum, ignore_case = true, default_value_t = OutputFormat::Standard)]
     pub output_format: OutputFormat,
 
+    /// Explains each difference found when using 'check' mode, such as a change in whitespace or quote style.
+    ///
+    /// The kinds of change are included in the Standard and Json output formats, and counted in the Summary output format.
+    #[structopt(long, requires = "check")]
+    pub explain: bool,
+
     /// Verifies the output correctness after formatting.
     ///
     /// Checks the generated AST with the original AST to detect if code correctness has changed.
@@ -86,6 +93,81 @@ pub struct Opt {
     #[structopt(long)]
     pub range_end: Option<usize>,
 
+    /// A starting line to format files, given as `LINE` or `LINE:COLUMN`.
+    ///
+    /// Lines and columns are 1-indexed, with columns counted in characters.
+    /// Any content before this position will be ignored.
+    #[structopt(long, value_name = "LINE[:COLUMN]", conflicts_with = "range-start")]
+    pub range_start_line: Option<LinePosition>,
+
+    /// An ending line to format files, given as `LINE` or `LINE:COLUMN`.
+    ///
+    /// Lines and columns are 1-indexed, with columns counted in characters.
+    /// Any content after this position will be ignored. If no column is given, the whole line is included.
+    #[structopt(long, value_name = "LINE[:COLUMN]", conflicts_with = "range-end")]
+    pub range_end_line: Option<LinePosition>,
+
+    /// The byte offset of the cursor within the input, to track where it moves to after formatting.
+    ///
+    /// Can only be used when formatting from stdin. The new byte offset of the cursor is output as JSON
+    /// on the first line (e.g. `{"cursor_offset":12}`), followed by the formatted code.
+    #[structopt(long, conflicts_with = "check")]
+    pub cursor_offset: Option<usize>,
+
+    /// Caches which files are already formatted, so they can be skipped on subsequent runs.
+    ///
+    /// A file is skipped if its contents, the configuration used to format it, and the StyLua version are unchanged.
+    /// The cache is stored at `.stylua-cache` in the current directory, unless `--cache-location` is provided.
+    #[structopt(long)]
+    pub cache: bool,
+
+    /// The path to store the formatting cache at when `--cache` is enabled.
+    #[structopt(long, parse(from_os_str))]
+    pub cache_location: Option<PathBuf>,
+
+    /// A baseline of files which are known to be unformatted, so `--check` only fails on new problems.
+    ///
+    /// Files in the baseline pass the check until their contents change, at which point they must be formatted.
+    /// Use `--update-baseline` to record the currently unformatted files.
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    pub baseline: Option<PathBuf>,
+
+    /// Records every unformatted file in the `--baseline` file, rather than failing the check.
+    #[structopt(long, requires = "baseline")]
+    pub update_baseline: bool,
+
+    /// Only format lines which have changed since the given git revision.
+    ///
+    /// Changes in the working tree, staged changes and untracked files are all considered changed.
+    /// Files without any changes are skipped.
+    #[structopt(
+        long,
+        conflicts_with_all = &["staged", "range-start", "range-end", "range-start-line", "range-end-line"]
+    )]
+    pub changed_since: Option<String>,
+
+    /// Only format lines which have staged changes in git.
+    ///
+    /// Files without any staged changes are skipped.
+    #[structopt(
+        long,
+        conflicts_with_all = &["range-start", "range-end", "range-start-line", "range-end-line"]
+    )]
+    pub staged: bool,
+
+    /// Only apply the given families of formatting changes, leaving all other code untouched.
+    ///
+    /// Useful to adopt formatting gradually, one family at a time. Multiple families can be separated by commas,
+    /// e.g. `--only quotes,call_parentheses`.
+    #[structopt(
+        long,
+        arg_enum,
+        use_value_delimiter = true,
+        value_name = "TRANSFORMATIONS",
+        conflicts_with = "cursor-offset"
+    )]
+    pub only: Vec<ArgTransformation>,
+
     /// Formatting options to apply when formatting code.
     #[structopt(flatten, next_help_heading = "FORMATTING OPTIONS")]
     pub format_opts: FormatOpts,
@@ -116,6 +198,101 @@ pub struct Opt {
     /// Run Stylua as a language server (following LSP protocol)
     #[structopt(long)]
     pub lsp: bool,
+
+    /// Prints the resolved configuration for the files provided, and where each option was set, instead of formatting.
+    ///
+    /// Use `-` to print the configuration used when formatting from stdin, taking into account `--stdin-filepath`.
+    // Hidden unless built with the `serialize` feature, which it requires
+    #[structopt(long, hide = !cfg!(feature = "serialize"))]
+    pub print_config: bool,
+
+    /// The format to print the resolved configuration in when using `--print-config`.
+    #[structopt(
+        long,
+        arg_enum,
+        ignore_case = true,
+        default_value_t = ConfigFormat::Toml,
+        hide = !cfg!(feature = "serialize")
+    )]
+    pub print_config_format: ConfigFormat,
+
+    /// Prints a JSON Schema describing the stylua.toml configuration file, for use in editors.
+    // Hidden unless built with the `schema` feature, which it requires
+    #[structopt(long, hide = !cfg!(feature = "schema"))]
+    pub print_config_schema: bool,
+}
+
+impl Opt {
+    /// Resolves the range to format within the contents, from the byte offsets or lines provided
+    pub fn range(&self, contents: &str) -> Option<Range> {
+        if self.range_start_line.is_none() && self.range_end_line.is_none() {
+            return match (self.range_start, self.range_end) {
+                (None, None) => None,
+                (start, end) => Some(Range::from_values(start, end)),
+            };
+        }
+
+        let line_range = Range::from_lines(
+            contents,
+            self.range_start_line.map(|position| position.line),
+            self.range_start_line.and_then(|position| position.column),
+            self.range_end_line.map(|position| position.line),
+            self.range_end_line.and_then(|position| position.column),
+        );
+
+        Some(Range::from_values(
+            self.range_start.or(line_range.start),
+            self.range_end.or(line_range.end),
+        ))
+    }
+
+    /// The transformations to apply, or `None` if all formatting should be applied
+    pub fn transformations(&self) -> Option<Vec<Transformation>> {
+        if self.only.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.only
+                .iter()
+                .copied()
+                .map(Transformation::from)
+                .collect(),
+        )
+    }
+}
+
+/// A position within a file given as a line, and optionally a column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinePosition {
+    pub line: usize,
+    pub column: Option<usize>,
+}
+
+impl FromStr for LinePosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| {
+            match value.parse::<usize>() {
+            Ok(value) if value > 0 => Ok(value),
+            _ => Err(format!(
+                "expected a position of the form LINE or LINE:COLUMN, where both are positive integers, but got '{s}'"
+            )),
+        }
+        };
+
+        match s.split_once(':') {
+            Some((line, column)) => Ok(LinePosition {
+                line: parse(line)?,
+                column: Some(parse(column)?),
+            }),
+            None => Ok(LinePosition {
+                line: parse(s)?,
+                column: None,
+            }),
+        }
+    }
 }
 
 #[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
@@ -162,10 +339,59 @@ pub enum OutputFormat {
     Standard,
     /// Outputs using unified diff formatting
     Unified,
+    /// Outputs a single patch containing the changes to every file, which can be applied with `git apply`
+    Patch,
     /// Outputs in json
     Json,
     /// Outputs a human-friendly summary
     Summary,
+    /// Outputs a SARIF log, for GitHub code scanning
+    Sarif,
+    /// Outputs a Checkstyle XML report, for Jenkins and GitLab
+    Checkstyle,
+    /// Outputs a JUnit XML report, for test dashboards
+    Junit,
+    /// Outputs GitHub Actions workflow commands, annotating each unformatted line
+    GithubActions,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+#[clap(rename_all = "PascalCase")]
+pub enum ConfigFormat {
+    /// Outputs in toml, with a comment describing where each option was set
+    Toml,
+    /// Outputs in json
+    Json,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+#[clap(rename_all = "snake_case")]
+pub enum ArgTransformation {
+    /// Changing the quotes used for strings
+    Quotes,
+    /// Adding or removing parentheses around call arguments
+    CallParentheses,
+    /// Changing the indentation at the start of each line
+    Indentation,
+    /// Changing the whitespace between tokens on the same line
+    Spacing,
+    /// Removing whitespace at the end of each line
+    TrailingWhitespace,
+    /// Changing the line endings
+    LineEndings,
+}
+
+impl From<ArgTransformation> for Transformation {
+    fn from(other: ArgTransformation) -> Self {
+        match other {
+            ArgTransformation::Quotes => Transformation::Quotes,
+            ArgTransformation::CallParentheses => Transformation::CallParentheses,
+            ArgTransformation::Indentation => Transformation::Indentation,
+            ArgTransformation::Spacing => Transformation::Spacing,
+            ArgTransformation::TrailingWhitespace => Transformation::TrailingWhitespace,
+            ArgTransformation::LineEndings => Transformation::LineEndings,
+        }
+    }
 }
 
 #[derive(StructOpt, Clone, Copy, Debug)]
@@ -252,6 +478,7 @@ convert_enum!(LuaVersion, ArgLuaVersion, {
 });
 
 convert_enum!(LineEndings, ArgLineEndings, {
+    Auto,
     Unix,
     Windows,
 });
@@ -297,11 +524,32 @@ convert_enum!(SpaceAfterFunctionNames, ArgSpaceAfterFunctionNames, {
 
 #[cfg(test)]
 mod tests {
-    use super::Opt;
+    use super::{LinePosition, Opt};
     use clap::IntoApp;
 
     #[test]
     fn verify_opt() {
         Opt::command().debug_assert()
     }
+
+    #[test]
+    fn test_parse_line_position() {
+        assert_eq!(
+            "12".parse::<LinePosition>(),
+            Ok(LinePosition {
+                line: 12,
+                column: None
+            })
+        );
+        assert_eq!(
+            "12:3".parse::<LinePosition>(),
+            Ok(LinePosition {
+                line: 12,
+                column: Some(3)
+            })
+        );
+        assert!("0".parse::<LinePosition>().is_err());
+        assert!("12:".parse::<LinePosition>().is_err());
+        assert!("twelve".parse::<LinePosition>().is_err());
+    }
 }
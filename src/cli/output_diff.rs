@@ -1,12 +1,16 @@
 // Based off https://github.com/mitsuhiko/similar/blob/main/examples/terminal-inline.rs
 // Licensed under https://github.com/mitsuhiko/similar/blob/main/LICENSE
+use crate::explain::{classify_diff, DiffCategory};
 use crate::opt;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use console::{style, Style};
+use full_moon::LuaVersion;
 use serde::Serialize;
 use similar::{ChangeTag, DiffOp, TextDiff};
+use std::collections::BTreeSet;
 use std::fmt;
 use std::io::Write;
+use std::path::{Component, Path};
 
 struct Line(Option<usize>);
 
@@ -25,6 +29,7 @@ pub fn output_diff(
     context_size: usize,
     title: &str,
     color: opt::Color,
+    explain: Option<LuaVersion>,
 ) -> Result<Option<Vec<u8>>> {
     let diff = TextDiff::from_lines(old, new);
     let diff_opts = diff.grouped_ops(context_size);
@@ -46,6 +51,33 @@ pub fn output_diff(
         if idx > 0 {
             writeln!(&mut buffer, "{:-^1$}", "-", 80)?;
         }
+        if let Some(lua_version) = explain {
+            let categories = group
+                .iter()
+                .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+                .flat_map(|op| {
+                    classify_diff(
+                        &diff.old_slices()[op.old_range()].concat(),
+                        &diff.new_slices()[op.new_range()].concat(),
+                        lua_version,
+                    )
+                })
+                .collect::<BTreeSet<_>>();
+            writeln!(
+                &mut buffer,
+                "{}",
+                style(format!(
+                    "note: {}",
+                    categories
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+                .cyan()
+                .force_styling(should_use_color)
+            )?;
+        }
         for op in group {
             for change in diff.iter_inline_changes(op) {
                 let (sign, s) = match change.tag() {
@@ -92,7 +124,32 @@ pub fn output_diff(
     Ok(Some(buffer))
 }
 
-pub fn output_diff_unified(old: &str, new: &str) -> Result<Option<Vec<u8>>> {
+/// Returns the path of the file to use in the headers of a unified diff.
+/// Paths are made relative to the current directory and use `/` separators, so the diff can be applied
+/// with `git apply` or `patch -p1`.
+/// Files outside of the current directory cannot be represented in such a diff, so return `None`.
+fn relative_path(file_name: &str) -> Option<String> {
+    let path = Path::new(file_name);
+    let current_directory = std::env::current_dir().ok();
+    let path = current_directory
+        .as_deref()
+        .and_then(|current_directory| path.strip_prefix(current_directory).ok())
+        .unwrap_or(path);
+
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => components.push(component.to_string_lossy()),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(components.join("/"))
+}
+
+/// Outputs a unified diff. Files outside of the current directory use the path as given in the headers.
+pub fn output_diff_unified(old: &str, new: &str, file_name: &str) -> Result<Option<Vec<u8>>> {
     let text_diff = TextDiff::from_lines(old, new);
 
     // If there are no changes, return nothing
@@ -100,23 +157,57 @@ pub fn output_diff_unified(old: &str, new: &str) -> Result<Option<Vec<u8>>> {
         return Ok(None);
     }
 
+    let (old_header, new_header) = match relative_path(file_name) {
+        Some(path) => (format!("a/{path}"), format!("b/{path}")),
+        None => (file_name.to_string(), file_name.to_string()),
+    };
     let mut buffer = Vec::new();
     write!(
         &mut buffer,
         "{}",
-        text_diff.unified_diff().header("old", "new")
+        text_diff.unified_diff().header(&old_header, &new_header)
+    )?;
+    Ok(Some(buffer))
+}
+
+/// Outputs a unified diff with a `diff --git` header, so that the diffs of multiple files can be concatenated
+/// into a single patch. Files outside of the current directory cannot be represented in a patch, so are rejected.
+pub fn output_diff_patch(old: &str, new: &str, file_name: &str) -> Result<Option<Vec<u8>>> {
+    let text_diff = TextDiff::from_lines(old, new);
+
+    // If there are no changes, return nothing
+    if text_diff.ratio() == 1.0 {
+        return Ok(None);
+    }
+
+    let Some(path) = relative_path(file_name) else {
+        bail!(
+            "cannot create a patch for {file_name}, as it is outside of the current directory. \
+            Run stylua from a directory containing the file"
+        );
+    };
+    let mut buffer = format!("diff --git a/{path} b/{path}\n").into_bytes();
+    write!(
+        &mut buffer,
+        "{}",
+        text_diff
+            .unified_diff()
+            .header(&format!("a/{path}"), &format!("b/{path}"))
     )?;
     Ok(Some(buffer))
 }
 
 #[derive(Serialize)]
 pub struct DiffMismatch {
-    original_start_line: usize,
-    original_end_line: usize,
-    expected_start_line: usize,
-    expected_end_line: usize,
-    original: String,
-    expected: String,
+    pub original_start_line: usize,
+    pub original_end_line: usize,
+    pub expected_start_line: usize,
+    pub expected_end_line: usize,
+    pub original: String,
+    pub expected: String,
+    /// The kinds of change made to the hunk, when explaining diffs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Vec<DiffCategory>>,
 }
 
 pub fn output_diff_json(old: &str, new: &str) -> Option<Vec<DiffMismatch>> {
@@ -157,6 +248,7 @@ pub fn output_diff_json(old: &str, new: &str) -> Option<Vec<DiffMismatch>> {
                         expected_end_line: new_index + new_len - 1,
                         original,
                         expected,
+                        categories: None,
                     });
                 }
                 DiffOp::Delete {
@@ -176,6 +268,7 @@ pub fn output_diff_json(old: &str, new: &str) -> Option<Vec<DiffMismatch>> {
                         expected_end_line: new_index,
                         original: actual.to_string(),
                         expected: "".to_string(),
+                        categories: None,
                     })
                 }
                 DiffOp::Insert {
@@ -195,6 +288,7 @@ pub fn output_diff_json(old: &str, new: &str) -> Option<Vec<DiffMismatch>> {
                         expected_end_line: new_index + new_len - 1,
                         original: "".to_string(),
                         expected: expected.to_string(),
+                        categories: None,
                     })
                 }
                 DiffOp::Equal { .. } => (), // Don't record an equals diff, its unnecessary
@@ -213,13 +307,13 @@ mod tests {
 
     #[test]
     fn test_no_diff() {
-        let output = output_diff("local x = 1", "local x = 1", 0, "", Color::Auto).unwrap();
+        let output = output_diff("local x = 1", "local x = 1", 0, "", Color::Auto, None).unwrap();
         assert!(output.is_none())
     }
 
     #[test]
     fn test_deletion_diff() {
-        let output = output_diff("local x = 1", "", 0, "", Color::Never)
+        let output = output_diff("local x = 1", "", 0, "", Color::Never, None)
             .unwrap()
             .expect("expected change, found no diff");
         println!("{}", String::from_utf8(output.to_owned()).unwrap());
@@ -233,7 +327,7 @@ mod tests {
 
     #[test]
     fn test_addition_diff() {
-        let output = output_diff("", "local x = 1", 0, "", Color::Never)
+        let output = output_diff("", "local x = 1", 0, "", Color::Never, None)
             .unwrap()
             .expect("expected change, found no diff");
         assert_eq!(
@@ -244,9 +338,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_unified_diff_headers() {
+        let output = output_diff_unified("local  x = 1\n", "local x = 1\n", "./src/foo.lua")
+            .unwrap()
+            .expect("expected change, found no diff");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "--- a/src/foo.lua\n+++ b/src/foo.lua\n@@ -1 +1 @@\n-local  x = 1\n+local x = 1\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_paths_outside_current_directory() {
+        let outside = std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("foo.lua");
+        for file_name in [outside.to_str().unwrap(), "../foo.lua"] {
+            let output = output_diff_unified("local  x = 1\n", "local x = 1\n", file_name)
+                .unwrap()
+                .expect("expected change, found no diff");
+            assert!(String::from_utf8(output)
+                .unwrap()
+                .starts_with(&format!("--- {file_name}\n+++ {file_name}\n")));
+        }
+
+        let inside = std::env::current_dir().unwrap().join("src").join("foo.lua");
+        let output =
+            output_diff_unified("local  x = 1\n", "local x = 1\n", inside.to_str().unwrap())
+                .unwrap()
+                .expect("expected change, found no diff");
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("--- a/src/foo.lua\n+++ b/src/foo.lua\n"));
+    }
+
+    #[test]
+    fn test_patch_diff_rejects_paths_outside_current_directory() {
+        let error = output_diff_patch("local  x = 1\n", "local x = 1\n", "../foo.lua")
+            .expect_err("expected paths outside the current directory to be rejected");
+        assert!(error
+            .to_string()
+            .contains("outside of the current directory"));
+
+        // Files which are already formatted produce no diff, so don't need a path
+        assert!(
+            output_diff_patch("local x = 1\n", "local x = 1\n", "../foo.lua")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_patch_diff() {
+        let output = output_diff_patch("local  x = 1\n", "local x = 1\n", "foo.lua")
+            .unwrap()
+            .expect("expected change, found no diff");
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("diff --git a/foo.lua b/foo.lua\n--- a/foo.lua\n+++ b/foo.lua\n"));
+        assert!(
+            output_diff_patch("local x = 1\n", "local x = 1\n", "foo.lua")
+                .unwrap()
+                .is_none()
+        );
+    }
+
     #[test]
     fn test_change_diff() {
-        let output = output_diff("local  x = 1", "local x = 1", 0, "", Color::Never)
+        let output = output_diff("local  x = 1", "local x = 1", 0, "", Color::Never, None)
             .unwrap()
             .expect("expected change, found no diff");
         assert_eq!(
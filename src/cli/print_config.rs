@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::io::{stdout, Write};
+use std::path::Path;
+use stylua_lib::Config;
+
+use crate::config::{flatten_config, ConfigResolver, ConfigSources};
+use crate::opt::{ConfigFormat, Opt};
+
+/// The source given to options which were not set anywhere
+const DEFAULT_SOURCE: &str = "default";
+
+fn source_of<'a>(sources: &'a ConfigSources, key: &str) -> &'a str {
+    sources
+        .get(key)
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_SOURCE)
+}
+
+/// Outputs the configuration as toml, with a trailing comment on each option describing where it was set
+fn output_config_toml(file_name: &str, config: &Config, sources: &ConfigSources) -> Result<String> {
+    let flattened = flatten_config(config)?;
+
+    // Top-level options must come before any tables
+    let (top_level, nested): (Vec<_>, Vec<_>) = flattened
+        .into_iter()
+        .partition(|(key, _)| !key.contains('.'));
+
+    let mut output = format!("# Configuration for {file_name}\n");
+    for (key, value) in &top_level {
+        output += &format!("{key} = {value} # {}\n", source_of(sources, key));
+    }
+
+    let mut current_table = None;
+    for (key, value) in &nested {
+        let (table, name) = key.rsplit_once('.').expect("nested option contains a `.`");
+        if current_table != Some(table) {
+            output += &format!("\n[{table}]\n");
+            current_table = Some(table);
+        }
+        output += &format!("{name} = {value} # {}\n", source_of(sources, key));
+    }
+
+    Ok(output)
+}
+
+/// Outputs the configuration as a single line of json, alongside where every option was set
+fn output_config_json(file_name: &str, config: &Config, sources: &ConfigSources) -> Result<String> {
+    let all_sources = flatten_config(config)?
+        .into_keys()
+        .map(|key| {
+            let source = source_of(sources, &key).to_string();
+            (key, source)
+        })
+        .collect::<ConfigSources>();
+
+    let mut output = serde_json::to_string(&json!({
+        "file": file_name,
+        "config": config,
+        "sources": all_sources,
+    }))?;
+    output.push('\n');
+
+    Ok(output)
+}
+
+/// Prints the resolved configuration for every file provided, instead of formatting them
+pub fn print_config(opt: &Opt) -> Result<i32> {
+    let mut config_resolver = ConfigResolver::new(opt)?;
+    let mut outputs = Vec::new();
+
+    for file in &opt.files {
+        let (file_name, path) = if file == Path::new("-") {
+            let file_name = match &opt.stdin_filepath {
+                Some(stdin_filepath) => stdin_filepath.display().to_string(),
+                None => String::from("stdin"),
+            };
+            (file_name, None)
+        } else {
+            (file.display().to_string(), Some(file.as_path()))
+        };
+
+        let (config, sources) = config_resolver
+            .load_configuration_with_sources(path)
+            .with_context(|| format!("could not load configuration for {file_name}"))?;
+
+        outputs.push(match opt.print_config_format {
+            ConfigFormat::Toml => output_config_toml(&file_name, &config, &sources)?,
+            ConfigFormat::Json => output_config_json(&file_name, &config, &sources)?,
+        });
+    }
+
+    let separator = match opt.print_config_format {
+        ConfigFormat::Toml => "\n",
+        ConfigFormat::Json => "",
+    };
+
+    stdout()
+        .lock()
+        .write_all(outputs.join(separator).as_bytes())
+        .context("could not output to stdout")?;
+
+    Ok(0)
+}
@@ -0,0 +1,490 @@
+use crate::opt::OutputFormat;
+use crate::output_diff::DiffMismatch;
+use serde_json::json;
+use std::fmt::Write;
+
+/// An output format which reports problems as annotations on lines of a file, rather than as a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// A SARIF log, as consumed by GitHub code scanning
+    Sarif,
+    /// A Checkstyle XML report, as consumed by Jenkins and GitLab
+    Checkstyle,
+    /// A JUnit XML report, as consumed by test dashboards
+    Junit,
+    /// GitHub Actions workflow commands, which annotate the lines in a pull request
+    GithubActions,
+}
+
+impl ReportFormat {
+    /// Returns the report format for the output format, if it is one
+    pub fn from_output_format(output_format: OutputFormat) -> Option<Self> {
+        match output_format {
+            OutputFormat::Sarif => Some(ReportFormat::Sarif),
+            OutputFormat::Checkstyle => Some(ReportFormat::Checkstyle),
+            OutputFormat::Junit => Some(ReportFormat::Junit),
+            OutputFormat::GithubActions => Some(ReportFormat::GithubActions),
+            OutputFormat::Standard
+            | OutputFormat::Unified
+            | OutputFormat::Patch
+            | OutputFormat::Json
+            | OutputFormat::Summary => None,
+        }
+    }
+}
+
+/// The kind of problem an annotation describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationKind {
+    /// The code is not formatted
+    Unformatted,
+    /// The code could not be parsed, so was not formatted
+    ParseError,
+}
+
+impl AnnotationKind {
+    fn rule_id(self) -> &'static str {
+        match self {
+            AnnotationKind::Unformatted => "unformatted",
+            AnnotationKind::ParseError => "parse_error",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            AnnotationKind::Unformatted => "Code is not formatted",
+            AnnotationKind::ParseError => "Code could not be parsed",
+        }
+    }
+}
+
+/// A problem found on a range of lines within a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    pub kind: AnnotationKind,
+    /// The first line of the problem, 1-indexed
+    pub start_line: usize,
+    /// The last line of the problem, 1-indexed and inclusive
+    pub end_line: usize,
+    /// The column the problem starts at, 1-indexed, if known
+    pub start_column: Option<usize>,
+    pub message: String,
+}
+
+/// The problems found within a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    pub file: String,
+    pub annotations: Vec<Annotation>,
+}
+
+impl FileReport {
+    /// Creates a report annotating each hunk of the diff between the original and formatted code
+    pub fn from_mismatches(file: &str, mismatches: Vec<DiffMismatch>) -> Self {
+        let annotations = mismatches
+            .into_iter()
+            .map(|mismatch| {
+                // Mismatch lines are 0-indexed, and an insertion is positioned before its start line
+                let start_line = mismatch.original_start_line + 1;
+                let end_line = if mismatch.original.is_empty() {
+                    start_line
+                } else {
+                    mismatch.original_end_line + 1
+                };
+                let message = if mismatch.expected.is_empty() {
+                    "code is not formatted, these lines should be removed".to_string()
+                } else {
+                    format!(
+                        "code is not formatted, expected:\n{}",
+                        mismatch.expected.trim_end_matches('\n')
+                    )
+                };
+
+                Annotation {
+                    kind: AnnotationKind::Unformatted,
+                    start_line,
+                    end_line,
+                    start_column: None,
+                    message,
+                }
+            })
+            .collect();
+
+        Self {
+            file: file.to_string(),
+            annotations,
+        }
+    }
+
+    /// Creates a report annotating each error found whilst parsing the file
+    pub fn from_parse_errors(file: &str, errors: &[full_moon::Error]) -> Self {
+        let annotations = errors
+            .iter()
+            .map(|error| {
+                let (start_position, end_position) = error.range();
+                Annotation {
+                    kind: AnnotationKind::ParseError,
+                    start_line: start_position.line(),
+                    end_line: end_position.line().max(start_position.line()),
+                    start_column: Some(start_position.character()),
+                    message: parse_error_message(error),
+                }
+            })
+            .collect();
+
+        Self {
+            file: file.to_string(),
+            annotations,
+        }
+    }
+
+    fn has_kind(&self, kind: AnnotationKind) -> bool {
+        self.annotations
+            .iter()
+            .any(|annotation| annotation.kind == kind)
+    }
+}
+
+/// Describes an error found whilst parsing
+pub fn parse_error_message(error: &full_moon::Error) -> String {
+    match error {
+        full_moon::Error::AstError(ast_error) => format!(
+            "unexpected token `{}`: {}",
+            ast_error.token(),
+            ast_error.error_message()
+        ),
+        full_moon::Error::TokenizerError(error) => match error.error() {
+            full_moon::tokenizer::TokenizerErrorType::UnclosedComment => {
+                "unclosed comment".to_string()
+            }
+            full_moon::tokenizer::TokenizerErrorType::UnclosedString => {
+                "unclosed string".to_string()
+            }
+            full_moon::tokenizer::TokenizerErrorType::InvalidNumber => "invalid number".to_string(),
+            full_moon::tokenizer::TokenizerErrorType::UnexpectedToken(character) => {
+                format!("unexpected character {character}")
+            }
+            full_moon::tokenizer::TokenizerErrorType::InvalidSymbol(symbol) => {
+                format!("invalid symbol {symbol}")
+            }
+        },
+    }
+}
+
+/// Renders the reports of every file with problems into the given format
+pub fn render_report(format: ReportFormat, reports: &[FileReport]) -> String {
+    match format {
+        ReportFormat::Sarif => render_sarif(reports),
+        ReportFormat::Checkstyle => render_checkstyle(reports),
+        ReportFormat::Junit => render_junit(reports),
+        ReportFormat::GithubActions => render_github_actions(reports),
+    }
+}
+
+fn render_sarif(reports: &[FileReport]) -> String {
+    let rules = [AnnotationKind::Unformatted, AnnotationKind::ParseError]
+        .iter()
+        .map(|kind| {
+            json!({
+                "id": kind.rule_id(),
+                "shortDescription": { "text": kind.description() },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let results = reports
+        .iter()
+        .flat_map(|report| {
+            report.annotations.iter().map(move |annotation| {
+                let mut region = json!({
+                    "startLine": annotation.start_line,
+                    "endLine": annotation.end_line,
+                });
+                if let Some(start_column) = annotation.start_column {
+                    region["startColumn"] = json!(start_column);
+                }
+
+                json!({
+                    "ruleId": annotation.kind.rule_id(),
+                    "level": "error",
+                    "message": { "text": annotation.message },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": report.file.replace('\\', "/") },
+                            "region": region,
+                        },
+                    }],
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut output = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "StyLua",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/JohnnyMorganz/StyLua",
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+    .to_string();
+    output.push('\n');
+    output
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push_str("&#9;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+fn render_checkstyle(reports: &[FileReport]) -> String {
+    let mut output = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    output.push_str("<checkstyle version=\"4.3\">\n");
+
+    for report in reports {
+        writeln!(output, "  <file name=\"{}\">", escape_xml(&report.file)).unwrap();
+        for annotation in &report.annotations {
+            write!(output, "    <error line=\"{}\"", annotation.start_line).unwrap();
+            if let Some(start_column) = annotation.start_column {
+                write!(output, " column=\"{start_column}\"").unwrap();
+            }
+            writeln!(
+                output,
+                " severity=\"error\" message=\"{}\" source=\"stylua.{}\"/>",
+                escape_xml(&annotation.message),
+                annotation.kind.rule_id()
+            )
+            .unwrap();
+        }
+        output.push_str("  </file>\n");
+    }
+
+    output.push_str("</checkstyle>\n");
+    output
+}
+
+fn render_junit(reports: &[FileReport]) -> String {
+    // A file which could not be parsed is an error, whilst a file which is not formatted is a failure
+    let errors = reports
+        .iter()
+        .filter(|report| report.has_kind(AnnotationKind::ParseError))
+        .count();
+    let failures = reports.len() - errors;
+    let counts = format!(
+        "tests=\"{}\" failures=\"{failures}\" errors=\"{errors}\"",
+        reports.len()
+    );
+
+    let mut output = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(output, "<testsuites name=\"StyLua\" {counts}>").unwrap();
+    writeln!(output, "  <testsuite name=\"StyLua\" {counts}>").unwrap();
+
+    for report in reports {
+        let (element, kind) = if report.has_kind(AnnotationKind::ParseError) {
+            ("error", AnnotationKind::ParseError)
+        } else {
+            ("failure", AnnotationKind::Unformatted)
+        };
+        let details = report
+            .annotations
+            .iter()
+            .map(|annotation| {
+                format!(
+                    "{}:{}: {}\n",
+                    report.file, annotation.start_line, annotation.message
+                )
+            })
+            .collect::<String>();
+
+        writeln!(
+            output,
+            "    <testcase name=\"{}\" classname=\"stylua\">",
+            escape_xml(&report.file)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "      <{element} message=\"{}\" type=\"{}\">{}</{element}>",
+            escape_xml(kind.description()),
+            kind.rule_id(),
+            escape_xml(&details)
+        )
+        .unwrap();
+        output.push_str("    </testcase>\n");
+    }
+
+    output.push_str("  </testsuite>\n");
+    output.push_str("</testsuites>\n");
+    output
+}
+
+/// Escapes the message of a workflow command
+fn escape_github_actions_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property of a workflow command, which cannot contain the property separators either
+fn escape_github_actions_property(value: &str) -> String {
+    escape_github_actions_data(value)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+fn render_github_actions(reports: &[FileReport]) -> String {
+    let mut output = String::new();
+
+    for report in reports {
+        for annotation in &report.annotations {
+            write!(
+                output,
+                "::error file={},line={},endLine={}",
+                escape_github_actions_property(&report.file),
+                annotation.start_line,
+                annotation.end_line
+            )
+            .unwrap();
+            if let Some(start_column) = annotation.start_column {
+                write!(output, ",col={start_column}").unwrap();
+            }
+            writeln!(
+                output,
+                ",title={}::{}",
+                escape_github_actions_property(annotation.kind.description()),
+                escape_github_actions_data(&annotation.message)
+            )
+            .unwrap();
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_diff::output_diff_json;
+
+    fn reports() -> Vec<FileReport> {
+        let mismatches =
+            output_diff_json("local x = 1\nlocal  y = 2\n", "local x = 1\nlocal y = 2\n")
+                .expect("expected a diff");
+        vec![
+            FileReport::from_mismatches("src/foo.lua", mismatches),
+            FileReport {
+                file: "bar <1>.lua".to_string(),
+                annotations: vec![Annotation {
+                    kind: AnnotationKind::ParseError,
+                    start_line: 3,
+                    end_line: 3,
+                    start_column: Some(5),
+                    message: "unexpected token `,`: expected an expression".to_string(),
+                }],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_report_from_mismatches() {
+        let report = &reports()[0];
+        assert_eq!(
+            report.annotations,
+            vec![Annotation {
+                kind: AnnotationKind::Unformatted,
+                start_line: 2,
+                end_line: 2,
+                start_column: None,
+                message: "code is not formatted, expected:\nlocal y = 2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sarif_report() {
+        let output: serde_json::Value =
+            serde_json::from_str(&render_report(ReportFormat::Sarif, &reports())).unwrap();
+        let results = &output["runs"][0]["results"];
+        assert_eq!(results.as_array().unwrap().len(), 2);
+        assert_eq!(results[0]["ruleId"], "unformatted");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "src/foo.lua"
+        );
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["region"],
+            json!({ "startLine": 2, "endLine": 2 })
+        );
+        assert_eq!(results[1]["ruleId"], "parse_error");
+        assert_eq!(
+            results[1]["locations"][0]["physicalLocation"]["region"]["startColumn"],
+            5
+        );
+    }
+
+    #[test]
+    fn test_checkstyle_report() {
+        assert_eq!(
+            render_report(ReportFormat::Checkstyle, &reports()),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+  <file name="src/foo.lua">
+    <error line="2" severity="error" message="code is not formatted, expected:&#10;local y = 2" source="stylua.unformatted"/>
+  </file>
+  <file name="bar &lt;1&gt;.lua">
+    <error line="3" column="5" severity="error" message="unexpected token `,`: expected an expression" source="stylua.parse_error"/>
+  </file>
+</checkstyle>
+"#
+        );
+    }
+
+    #[test]
+    fn test_junit_report() {
+        assert_eq!(
+            render_report(ReportFormat::Junit, &reports()),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="StyLua" tests="2" failures="1" errors="1">
+  <testsuite name="StyLua" tests="2" failures="1" errors="1">
+    <testcase name="src/foo.lua" classname="stylua">
+      <failure message="Code is not formatted" type="unformatted">src/foo.lua:2: code is not formatted, expected:&#10;local y = 2&#10;</failure>
+    </testcase>
+    <testcase name="bar &lt;1&gt;.lua" classname="stylua">
+      <error message="Code could not be parsed" type="parse_error">bar &lt;1&gt;.lua:3: unexpected token `,`: expected an expression&#10;</error>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn test_github_actions_report() {
+        assert_eq!(
+            render_report(ReportFormat::GithubActions, &reports()),
+            "::error file=src/foo.lua,line=2,endLine=2,title=Code is not formatted::code is not formatted, expected:%0Alocal y = 2\n\
+             ::error file=bar <1>.lua,line=3,endLine=3,col=5,title=Code could not be parsed::unexpected token `,`: expected an expression\n"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::config::find_ignore_file_path;
 use crate::opt::Opt;
 use anyhow::{Context, Result};
-use ignore::gitignore::Gitignore;
+use ignore::{gitignore::Gitignore, overrides::OverrideBuilder, WalkBuilder};
 use std::path::{Path, PathBuf};
 
 fn get_ignore(
@@ -28,6 +28,57 @@ fn get_ignore(
     }
 }
 
+/// Builds a walker over the given paths, which must not be empty.
+/// Hidden files and files ignored by git or a `.styluaignore` are skipped, unless the options say otherwise.
+/// Any `--glob` patterns, and a `.styluaignore` in `cwd`, are applied relative to `cwd`.
+pub fn create_walker_builder(paths: &[PathBuf], opt: &Opt, cwd: &Path) -> Result<WalkBuilder> {
+    let mut walker_builder = WalkBuilder::new(&paths[0]);
+    for path in &paths[1..] {
+        walker_builder.add(path);
+    }
+
+    walker_builder
+        .standard_filters(true)
+        .hidden(!opt.allow_hidden)
+        .parents(true)
+        .git_exclude(!opt.no_ignore_vcs)
+        .git_global(!opt.no_ignore_vcs)
+        .git_ignore(!opt.no_ignore_vcs)
+        .add_custom_ignore_filename(".styluaignore");
+
+    // Look for an ignore file in the current working directory
+    let ignore_path = cwd.join(".styluaignore");
+    if ignore_path.is_file() {
+        walker_builder.add_ignore(ignore_path);
+    }
+
+    if let Some(globs) = &opt.glob {
+        // Build overriders with any patterns given
+        let mut overrides = OverrideBuilder::new(cwd);
+        for pattern in globs {
+            overrides.add(pattern)?;
+        }
+        walker_builder.overrides(overrides.build()?);
+    }
+
+    Ok(walker_builder)
+}
+
+/// Whether the path matches the glob used to find Lua files when no `--glob` patterns are given
+pub fn matches_default_glob(path: &Path) -> bool {
+    lazy_static::lazy_static! {
+        static ref DEFAULT_GLOB: globset::GlobSet = {
+            let mut builder = globset::GlobSetBuilder::new();
+            builder.add(globset::Glob::new("**/*.lua").expect("cannot create default glob"));
+            #[cfg(feature = "luau")]
+            builder.add(globset::Glob::new("**/*.luau").expect("cannot create default luau glob"));
+            builder.build().expect("cannot build default globset")
+        };
+    }
+
+    DEFAULT_GLOB.is_match(path)
+}
+
 /// Whether the provided path was explicitly provided to the tool
 pub fn is_explicitly_provided(opt: &Opt, path: &Path) -> bool {
     opt.files.iter().any(|p| path == *p)
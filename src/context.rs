@@ -6,6 +6,7 @@ use full_moon::{
     node::Node,
     tokenizer::{Token, TokenType},
 };
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum FormatNode {
@@ -17,22 +18,36 @@ pub enum FormatNode {
     Normal,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Context {
     /// The configuration passed to the formatter
     config: Config,
-    /// An optional range of values to format within the file.
-    range: Option<FormatRange>,
+    /// The ranges of values to format within the file. If empty, the whole file is formatted.
+    ranges: Rc<[FormatRange]>,
     /// Whether the formatting has currently been disabled. This should occur when we see the relevant comment.
     formatting_disabled: bool,
 }
 
+/// Whether the node lies completely within the formatting range
+fn range_contains_node(range: &FormatRange, node: &impl Node) -> bool {
+    let after_start = match (range.start, node.start_position()) {
+        (Some(start_bound), Some(node_start)) => node_start.bytes() >= start_bound,
+        _ => true,
+    };
+    let before_end = match (range.end, node.end_position()) {
+        (Some(end_bound), Some(node_end)) => node_end.bytes() <= end_bound,
+        _ => true,
+    };
+
+    after_start && before_end
+}
+
 impl Context {
-    /// Creates a new Context, with the given configuration
-    pub fn new(config: Config, range: Option<FormatRange>) -> Self {
+    /// Creates a new Context, with the given configuration and ranges to format
+    pub fn new(config: Config, ranges: &[FormatRange]) -> Self {
         Self {
             config,
-            range,
+            ranges: ranges.into(),
             formatting_disabled: false,
         }
     }
@@ -75,14 +90,14 @@ impl Context {
 
         Self {
             formatting_disabled,
-            ..*self
+            ..self.clone()
         }
     }
 
     /// Checks whether we should format the given node.
     /// Firstly determine if formatting is disabled (due to the relevant comment)
     /// If not, determine whether the node has an ignore comment present.
-    /// If not, checks whether the provided node is outside the formatting range.
+    /// If not, checks whether the provided node is outside all of the formatting ranges.
     /// If not, the node should be formatted.
     pub fn should_format_node(&self, node: &impl Node) -> FormatNode {
         // If formatting is disabled we should immediately bailed out.
@@ -108,20 +123,13 @@ impl Context {
             }
         }
 
-        if let Some(range) = self.range {
-            match (range.start, node.start_position()) {
-                (Some(start_bound), Some(node_start)) if node_start.bytes() < start_bound => {
-                    return FormatNode::NotInRange
-                }
-                _ => (),
-            };
-
-            match (range.end, node.end_position()) {
-                (Some(end_bound), Some(node_end)) if node_end.bytes() > end_bound => {
-                    return FormatNode::NotInRange
-                }
-                _ => (),
-            }
+        if !self.ranges.is_empty()
+            && !self
+                .ranges
+                .iter()
+                .any(|range| range_contains_node(range, node))
+        {
+            return FormatNode::NotInRange;
         }
 
         FormatNode::Normal
@@ -164,10 +172,25 @@ impl Context {
     }
 }
 
-/// Returns the relevant line ending string from the [`LineEndings`] enum
+/// Determines the dominant line endings used in the given code.
+/// If the code contains no line breaks, or an equal amount of each, then Unix line endings are used.
+pub fn detect_line_endings(code: &str) -> LineEndings {
+    let total_newlines = code.matches('\n').count();
+    let windows_newlines = code.matches("\r\n").count();
+
+    if windows_newlines * 2 > total_newlines {
+        LineEndings::Windows
+    } else {
+        LineEndings::Unix
+    }
+}
+
+/// Returns the relevant line ending string from the [`LineEndings`] enum.
+/// [`LineEndings::Auto`] should be resolved using [`detect_line_endings`] before formatting, but falls back to
+/// Unix line endings if it is not.
 pub fn line_ending_character(line_endings: LineEndings) -> String {
     match line_endings {
-        LineEndings::Unix => String::from("\n"),
+        LineEndings::Auto | LineEndings::Unix => String::from("\n"),
         LineEndings::Windows => String::from("\r\n"),
     }
 }
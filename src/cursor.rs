@@ -0,0 +1,211 @@
+use full_moon::{
+    tokenizer::{Lexer, LexerResult, TokenType},
+    LuaVersion,
+};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+/// A significant (non-whitespace) token within some code, given by its byte boundaries and contents
+struct SignificantToken {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// Tokenizes the code, keeping only the tokens which are not whitespace
+fn significant_tokens(code: &str, lua_version: LuaVersion) -> Vec<SignificantToken> {
+    let tokens = match Lexer::new(code, lua_version).collect() {
+        LexerResult::Ok(tokens) | LexerResult::Recovered(tokens, _) => tokens,
+        LexerResult::Fatal(_) => return Vec::new(),
+    };
+
+    tokens
+        .into_iter()
+        .filter(|token| {
+            !matches!(
+                token.token_type(),
+                TokenType::Whitespace { .. } | TokenType::Eof
+            )
+        })
+        .map(|token| SignificantToken {
+            start: token.start_position().bytes(),
+            end: token.end_position().bytes(),
+            text: token.to_string(),
+        })
+        .collect()
+}
+
+/// Where an input token ended up in the output
+enum MappedToken {
+    /// The token is present in the output at the given index, although its contents may have changed
+    Present(usize),
+    /// The token was removed, and would have been positioned before the output token at the given index
+    Removed(usize),
+}
+
+fn map_token(operations: &[DiffOp], index: usize) -> MappedToken {
+    for operation in operations {
+        match *operation {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } if index < old_index + len => {
+                return MappedToken::Present(new_index + index - old_index)
+            }
+            DiffOp::Replace {
+                old_index,
+                old_len,
+                new_index,
+                new_len,
+            } if index < old_index + old_len => {
+                return MappedToken::Present(new_index + (index - old_index).min(new_len - 1))
+            }
+            DiffOp::Delete {
+                old_index,
+                old_len,
+                new_index,
+            } if index < old_index + old_len => return MappedToken::Removed(new_index),
+            _ => (),
+        }
+    }
+
+    unreachable!("token {} is not covered by the diff", index)
+}
+
+/// Which part of a token the cursor is anchored to
+enum Anchor {
+    /// The cursor is inside the token, at the given byte offset from its start
+    Inside(usize),
+    /// The cursor is in the whitespace directly after the token
+    After,
+    /// The cursor is in the whitespace directly before the token
+    Before,
+}
+
+/// Maps a byte offset within the input code to the equivalent offset within the formatted output.
+///
+/// Both the input and output are split into their significant tokens, which are then aligned.
+/// A cursor inside a token keeps its position within that token.
+/// A cursor in whitespace sticks to the end of the previous token, unless a newline comes first,
+/// in which case it sticks to the start of the next token.
+pub fn map_cursor_offset(
+    input: &str,
+    output: &str,
+    lua_version: LuaVersion,
+    cursor_offset: usize,
+) -> usize {
+    let cursor_offset = cursor_offset.min(input.len());
+
+    let input_tokens = significant_tokens(input, lua_version);
+    let output_tokens = significant_tokens(output, lua_version);
+    if input_tokens.is_empty() || output_tokens.is_empty() {
+        return cursor_offset.min(output.len());
+    }
+
+    // The first token finishing after the cursor, which contains the cursor if it starts at or before it
+    let next_index = input_tokens.partition_point(|token| token.end <= cursor_offset);
+    let (index, anchor) = match input_tokens.get(next_index) {
+        Some(token) if token.start <= cursor_offset => {
+            (next_index, Anchor::Inside(cursor_offset - token.start))
+        }
+        next_token => {
+            let previous_on_same_line = next_index > 0
+                && !input[input_tokens[next_index - 1].end..cursor_offset].contains('\n');
+            match next_token {
+                Some(_) if !previous_on_same_line => (next_index, Anchor::Before),
+                _ if next_index > 0 => (next_index - 1, Anchor::After),
+                _ => (next_index, Anchor::Before),
+            }
+        }
+    };
+
+    let input_texts = input_tokens
+        .iter()
+        .map(|token| token.text.as_str())
+        .collect::<Vec<_>>();
+    let output_texts = output_tokens
+        .iter()
+        .map(|token| token.text.as_str())
+        .collect::<Vec<_>>();
+    let operations = capture_diff_slices(Algorithm::Myers, &input_texts, &output_texts);
+
+    let mapped_offset = match map_token(&operations, index) {
+        MappedToken::Present(output_index) => {
+            let token = &output_tokens[output_index];
+            match anchor {
+                Anchor::Inside(offset) => token.start + offset.min(token.end - token.start),
+                Anchor::After => token.end,
+                Anchor::Before => token.start,
+            }
+        }
+        // Keep the cursor after whatever came before the removed token
+        MappedToken::Removed(0) => output_tokens[0].start,
+        MappedToken::Removed(output_index) => output_tokens[output_index - 1].end,
+    };
+
+    // The contents of the token may have changed, so make sure we don't end up in the middle of a character
+    (0..=mapped_offset)
+        .rev()
+        .find(|&offset| output.is_char_boundary(offset))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(input: &str, output: &str, cursor: &str) -> usize {
+        map_cursor_offset(
+            input,
+            output,
+            LuaVersion::lua51(),
+            input.find(cursor).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cursor_inside_token() {
+        let input = "local   foo   =   bar";
+        let output = "local foo = bar\n";
+        assert_eq!(map(input, output, "oo "), 7);
+        assert_eq!(map(input, output, "ar"), 13);
+    }
+
+    #[test]
+    fn test_cursor_in_whitespace() {
+        let input = "local x = 1   \n\n    local y = 2";
+        let output = "local x = 1\n\nlocal y = 2\n";
+        // Trailing whitespace sticks to the previous token
+        assert_eq!(map(input, output, "   \n"), 11);
+        // Indentation sticks to the next token
+        assert_eq!(map(input, output, "  local y"), 13);
+    }
+
+    #[test]
+    fn test_cursor_in_changed_token() {
+        let input = "local x = 'hello'";
+        let output = "local x = \"hello\"\n";
+        assert_eq!(map(input, output, "llo"), 13);
+    }
+
+    #[test]
+    fn test_cursor_in_removed_token() {
+        let input = "local x = (1)";
+        let output = "local x = 1\n";
+        assert_eq!(map(input, output, ")"), 11);
+    }
+
+    #[test]
+    fn test_cursor_at_end_of_input() {
+        let input = "local x = 1";
+        let output = "local x = 1\n";
+        assert_eq!(
+            map_cursor_offset(input, output, LuaVersion::lua51(), 11),
+            11
+        );
+        assert_eq!(
+            map_cursor_offset(input, output, LuaVersion::lua51(), 100),
+            11
+        );
+    }
+}
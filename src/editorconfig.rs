@@ -108,11 +108,18 @@ property_choice! {
 
 // Override StyLua config with EditorConfig properties
 fn load(mut config: Config, properties: &Properties) -> Config {
-    if let Ok(end_of_line) = properties.get::<EndOfLine>() {
-        config.line_endings = match end_of_line {
-            EndOfLine::Cr | EndOfLine::Lf => LineEndings::Unix,
-            EndOfLine::CrLf => LineEndings::Windows,
-        };
+    match properties.get::<EndOfLine>() {
+        Ok(end_of_line) => {
+            config.line_endings = match end_of_line {
+                EndOfLine::Cr | EndOfLine::Lf => LineEndings::Unix,
+                EndOfLine::CrLf => LineEndings::Windows,
+            };
+        }
+        // `auto` is not part of the EditorConfig specification, but we support it to preserve the file's line endings
+        Err(raw) if raw.into_str().eq_ignore_ascii_case("auto") => {
+            config.line_endings = LineEndings::Auto;
+        }
+        Err(_) => (),
     }
     if let Ok(indent_size) = properties.get::<IndentSize>() {
         config.indent_width = match indent_size {
@@ -254,6 +261,14 @@ mod tests {
         assert_eq!(config.line_endings, LineEndings::Windows);
     }
 
+    #[test]
+    fn test_end_of_line_auto() {
+        let mut properties = Properties::new();
+        properties.insert_raw_for_key("end_of_line", "Auto");
+        let config = Config::from(&properties);
+        assert_eq!(config.line_endings, LineEndings::Auto);
+    }
+
     #[test]
     fn test_indent_size() {
         let mut properties = Properties::new();
@@ -1,4 +1,4 @@
-use context::Context;
+use context::{detect_line_endings, Context};
 use full_moon::ast::Ast;
 #[cfg(all(feature = "luau", any(feature = "lua52", feature = "lua53")))]
 use full_moon::tokenizer::{Symbol, TokenType};
@@ -9,17 +9,20 @@ use wasm_bindgen::prelude::*;
 
 #[macro_use]
 mod context;
+mod cursor;
 #[cfg(feature = "editorconfig")]
 pub mod editorconfig;
 mod formatters;
 mod shape;
 mod sort_requires;
+mod transformations;
 mod verify_ast;
 
 /// The Lua syntax version to use
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum LuaVersion {
     /// Parse all syntax versions at the same time. This allows most general usage.
@@ -77,6 +80,7 @@ impl From<LuaVersion> for full_moon::LuaVersion {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum IndentType {
     /// Indent using tabs (`\t`)
@@ -90,20 +94,25 @@ pub enum IndentType {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum LineEndings {
-    // Auto,
     /// Unix Line Endings (LF) - `\n`
     #[default]
     Unix,
     /// Windows Line Endings (CRLF) - `\r\n`
     Windows,
+    /// Detect the dominant line endings used in the input, and preserve them in the output.
+    /// Falls back to Unix line endings if the input contains no line breaks.
+    // Added last, as wasm-bindgen exposes each variant by its discriminant
+    Auto,
 }
 
 /// The style of quotes to use within string literals
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum QuoteStyle {
     /// Use double quotes where possible, but change to single quotes if it produces less escapes
@@ -121,6 +130,7 @@ pub enum QuoteStyle {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum CallParenType {
     /// Use call parentheses all the time
@@ -140,6 +150,7 @@ pub enum CallParenType {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum CollapseSimpleStatement {
     /// Never collapse
@@ -157,6 +168,7 @@ pub enum CollapseSimpleStatement {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum BlockNewlineGaps {
     /// Never allow leading or trailing newline gaps
@@ -183,6 +195,66 @@ impl Range {
     pub fn from_values(start: Option<usize>, end: Option<usize>) -> Self {
         Self { start, end }
     }
+
+    /// Creates a new formatting range from the given start and end lines, resolved to byte offsets within the code.
+    /// Lines and columns are 1-indexed, and columns are counted in characters.
+    /// If no start column is given, the range begins at the start of the start line.
+    /// If no end column is given, the range finishes at the end of the end line.
+    /// Both boundaries are inclusive and optional, and lines and columns past the end of the code are clamped.
+    /// A line or column of 0 is treated as 1.
+    pub fn from_lines(
+        code: &str,
+        start_line: Option<usize>,
+        start_column: Option<usize>,
+        end_line: Option<usize>,
+        end_column: Option<usize>,
+    ) -> Self {
+        let start = start_line.map(|line| {
+            let line_offset = line_offset(code, line);
+            line_offset
+                + column_offset(
+                    &code[line_offset..],
+                    start_column.unwrap_or(1).saturating_sub(1),
+                )
+        });
+        let end = end_line.map(|line| {
+            let line_offset = line_offset(code, line);
+            line_offset
+                + match end_column {
+                    Some(column) => column_offset(&code[line_offset..], column),
+                    None => line_contents(&code[line_offset..]).len(),
+                }
+        });
+
+        Self { start, end }
+    }
+}
+
+/// The byte offset of the start of the given 1-indexed line, or the end of the code if there are not enough lines
+fn line_offset(code: &str, line: usize) -> usize {
+    if line <= 1 {
+        return 0;
+    }
+
+    code.match_indices('\n')
+        .nth(line - 2)
+        .map(|(index, _)| index + 1)
+        .unwrap_or(code.len())
+}
+
+/// The contents of the first line of the code, excluding its line ending
+fn line_contents(code: &str) -> &str {
+    let line = code.split('\n').next().unwrap_or_default();
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// The byte offset after the given number of characters in the first line of the code, clamped to the end of the line
+fn column_offset(code: &str, characters: usize) -> usize {
+    let line = line_contents(code);
+    line.char_indices()
+        .nth(characters)
+        .map(|(index, _)| index)
+        .unwrap_or(line.len())
 }
 
 /// Configuration for the Sort Requires codemod
@@ -190,6 +262,7 @@ impl Range {
 #[serde(default, deny_unknown_fields)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SortRequiresConfig {
     /// Whether the sort requires codemod is enabled
     pub enabled: bool,
@@ -215,6 +288,7 @@ impl SortRequiresConfig {
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
 pub enum SpaceAfterFunctionNames {
     /// Never use spaces after function names.
@@ -233,6 +307,7 @@ pub enum SpaceAfterFunctionNames {
 #[serde(default, deny_unknown_fields)]
 #[cfg_attr(all(target_arch = "wasm32", feature = "wasm-bindgen"), wasm_bindgen)]
 #[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Config {
     /// The type of Lua syntax to parse.
     pub syntax: LuaVersion,
@@ -408,6 +483,19 @@ pub fn format_ast(
     config: Config,
     range: Option<Range>,
     verify_output: OutputVerification,
+) -> Result<Ast, Error> {
+    format_ast_with_ranges(input_ast, config, range.as_slice(), verify_output)
+}
+
+/// Formats given [`Ast`], only formatting content within the given ranges.
+/// All ranges are byte offsets into the original input, and are formatted together in a single pass.
+/// If no ranges are given, the whole [`Ast`] is formatted.
+#[allow(clippy::result_large_err)]
+pub fn format_ast_with_ranges(
+    input_ast: Ast,
+    config: Config,
+    ranges: &[Range],
+    verify_output: OutputVerification,
 ) -> Result<Ast, Error> {
     // Clone the input AST only if we are verifying, to later use for checking
     let input_ast_for_verification = if let OutputVerification::Full = verify_output {
@@ -416,7 +504,13 @@ pub fn format_ast(
         None
     };
 
-    let ctx = Context::new(config, range);
+    // Resolve automatic line endings against the input before we start formatting
+    let mut config = config;
+    if config.line_endings == LineEndings::Auto {
+        config.line_endings = detect_line_endings(&input_ast.to_string());
+    }
+
+    let ctx = Context::new(config, ranges);
 
     // Perform require sorting beforehand if necessary
     let input_ast = match config.sort_requires.enabled {
@@ -454,6 +548,19 @@ pub fn format_code(
     config: Config,
     range: Option<Range>,
     verify_output: OutputVerification,
+) -> Result<String, Error> {
+    format_code_with_ranges(code, config, range.as_slice(), verify_output)
+}
+
+/// Formats given Lua code, only formatting content within the given ranges.
+/// All ranges are byte offsets into the original code, and are formatted together in a single pass.
+/// If no ranges are given, the whole code is formatted.
+#[allow(clippy::result_large_err)]
+pub fn format_code_with_ranges(
+    code: &str,
+    config: Config,
+    ranges: &[Range],
+    verify_output: OutputVerification,
 ) -> Result<String, Error> {
     let input_ast = match full_moon::parse_fallible(code, config.syntax.into()).into_result() {
         Ok(ast) => ast,
@@ -462,12 +569,108 @@ pub fn format_code(
         }
     };
 
-    let ast = format_ast(input_ast, config, range, verify_output)?;
+    // We already have the input code, so resolve automatic line endings here rather than
+    // leaving `format_ast` to print the AST back out
+    let mut config = config;
+    if config.line_endings == LineEndings::Auto {
+        config.line_endings = detect_line_endings(code);
+    }
+
+    let ast = format_ast_with_ranges(input_ast, config, ranges, verify_output)?;
     let output = ast.to_string();
 
     Ok(output)
 }
 
+/// A family of changes made by formatting, which can be applied on its own
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "fromstr", derive(strum::EnumString))]
+pub enum Transformation {
+    /// Changing the quotes used for strings, following `quote_style`
+    Quotes,
+    /// Adding or removing parentheses around call arguments, following `call_parentheses`
+    CallParentheses,
+    /// Changing the indentation at the start of each line
+    Indentation,
+    /// Changing the whitespace between tokens on the same line
+    Spacing,
+    /// Removing whitespace at the end of each line
+    TrailingWhitespace,
+    /// Changing the line endings, following `line_endings`
+    LineEndings,
+}
+
+/// Formats given Lua code, but only applies the changes made by the given transformations.
+/// All other code, including the whitespace around unchanged tokens, is left as it was in the input.
+/// This is useful to adopt formatting gradually, one family of changes at a time.
+/// When verifying, the code with only the given transformations applied is compared against the input.
+#[allow(clippy::result_large_err)]
+pub fn format_code_with_transformations(
+    code: &str,
+    config: Config,
+    ranges: &[Range],
+    transformations: &[Transformation],
+    verify_output: OutputVerification,
+) -> Result<String, Error> {
+    // Only the merged output is written, so that is what we verify rather than the full format
+    let formatted_code = format_code_with_ranges(code, config, ranges, OutputVerification::None)?;
+    let output = transformations::apply_transformations(
+        code,
+        &formatted_code,
+        config.syntax.into(),
+        transformations,
+    )?;
+
+    // Only some of the formatting was applied, so make sure the result still parses
+    let reparsed_output =
+        match full_moon::parse_fallible(&output, config.syntax.into()).into_result() {
+            Ok(ast) => ast,
+            Err(error) => return Err(Error::VerificationAstError(error)),
+        };
+
+    if let OutputVerification::Full = verify_output {
+        let input_ast = full_moon::parse_fallible(code, config.syntax.into())
+            .into_result()
+            .map_err(Error::ParseError)?;
+
+        let mut ast_verifier = verify_ast::AstVerifier::new();
+        if !ast_verifier.compare(input_ast, reparsed_output) {
+            return Err(Error::VerificationAstDifference);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Lua code which has been formatted, alongside the new position of a cursor within it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedCode {
+    /// The formatted code
+    pub code: String,
+    /// The byte offset of the cursor within the formatted code
+    pub cursor_offset: usize,
+}
+
+/// Formats given Lua code, tracking where the cursor at the given byte offset moves to.
+/// The cursor is mapped through the tokens of the code, so it stays next to the same token after formatting.
+#[allow(clippy::result_large_err)]
+pub fn format_code_with_cursor(
+    code: &str,
+    config: Config,
+    range: Option<Range>,
+    cursor_offset: usize,
+    verify_output: OutputVerification,
+) -> Result<FormattedCode, Error> {
+    let formatted_code = format_code(code, config, range, verify_output)?;
+    let cursor_offset =
+        cursor::map_cursor_offset(code, &formatted_code, config.syntax.into(), cursor_offset);
+
+    Ok(FormattedCode {
+        code: formatted_code,
+        cursor_offset,
+    })
+}
+
 #[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
 #[wasm_bindgen(js_name = formatCode)]
 pub fn format_code_wasm(
@@ -479,6 +682,17 @@ pub fn format_code_wasm(
     format_code(code, config, range, verify_output).map_err(|err| err.to_string())
 }
 
+#[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
+#[wasm_bindgen(js_name = formatCodeWithRanges)]
+pub fn format_code_with_ranges_wasm(
+    code: &str,
+    config: Config,
+    ranges: Vec<Range>,
+    verify_output: OutputVerification,
+) -> Result<String, String> {
+    format_code_with_ranges(code, config, &ranges, verify_output).map_err(|err| err.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -517,4 +731,140 @@ mod tests {
         .unwrap();
         assert_eq!(output, "local x = 1\n");
     }
+
+    #[test]
+    fn test_format_code_with_cursor() {
+        let output = format_code_with_cursor(
+            "local   x   =    foo( 1,2 )",
+            Config::default(),
+            None,
+            21,
+            OutputVerification::None,
+        )
+        .unwrap();
+        assert_eq!(output.code, "local x = foo(1, 2)\n");
+        assert_eq!(&output.code[output.cursor_offset..], "1, 2)\n");
+    }
+
+    #[test]
+    fn test_range_from_lines() {
+        let code = "local x = 1\nlocal y = 2\nlocal z = 3\n";
+
+        let range = Range::from_lines(code, Some(2), None, Some(2), None);
+        assert_eq!((range.start, range.end), (Some(12), Some(23)));
+
+        let range = Range::from_lines(code, Some(1), Some(7), Some(3), Some(5));
+        assert_eq!((range.start, range.end), (Some(6), Some(29)));
+
+        let range = Range::from_lines(code, None, None, Some(10), None);
+        assert_eq!((range.start, range.end), (None, Some(code.len())));
+
+        let range = Range::from_lines(code, Some(1), Some(0), Some(0), Some(0));
+        assert_eq!((range.start, range.end), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn test_range_from_lines_with_crlf() {
+        let code = "local x = 1\r\nlocal y = 2\r\n";
+
+        let range = Range::from_lines(code, Some(2), None, Some(2), None);
+        assert_eq!((range.start, range.end), (Some(13), Some(24)));
+
+        let range = Range::from_lines(code, Some(1), Some(100), Some(1), Some(100));
+        assert_eq!((range.start, range.end), (Some(11), Some(11)));
+    }
+
+    #[test]
+    fn test_range_from_lines_with_multibyte_characters() {
+        let code = "local s = \"héllo\"\nlocal t = 1\n";
+
+        let range = Range::from_lines(code, Some(1), Some(13), Some(1), Some(15));
+        assert_eq!((range.start, range.end), (Some(12), Some(16)));
+        assert_eq!(&code[range.start.unwrap()..range.end.unwrap()], "éll");
+
+        let range = Range::from_lines(code, Some(2), None, Some(2), None);
+        assert_eq!((range.start, range.end), (Some(19), Some(30)));
+    }
+
+    #[test]
+    fn test_line_endings_discriminants() {
+        // wasm-bindgen exposes enums by their discriminants, so existing variants must keep their values
+        assert_eq!(LineEndings::Unix as u32, 0);
+        assert_eq!(LineEndings::Windows as u32, 1);
+        assert_eq!(LineEndings::Auto as u32, 2);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_line_endings_serde_round_trip() {
+        for line_endings in [LineEndings::Unix, LineEndings::Windows, LineEndings::Auto] {
+            let serialized = serde_json::to_value(line_endings).unwrap();
+            assert_eq!(
+                serde_json::from_value::<LineEndings>(serialized).unwrap(),
+                line_endings
+            );
+        }
+        assert_eq!(
+            serde_json::to_value(LineEndings::Auto).unwrap(),
+            serde_json::json!("Auto")
+        );
+    }
+
+    #[test]
+    fn test_format_code_with_transformations_verifies_output() {
+        let output = format_code_with_transformations(
+            "local   x = foo 'hello'   \n",
+            Config::default(),
+            &[],
+            &[Transformation::Quotes, Transformation::CallParentheses],
+            OutputVerification::Full,
+        )
+        .unwrap();
+        assert_eq!(output, "local   x = foo(\"hello\")   \n");
+    }
+
+    #[test]
+    fn test_auto_line_endings_preserves_windows() {
+        let output = format_code(
+            "local   x   =    1\r\nlocal y = 2\r\n",
+            Config {
+                line_endings: LineEndings::Auto,
+                ..Config::default()
+            },
+            None,
+            OutputVerification::None,
+        )
+        .unwrap();
+        assert_eq!(output, "local x = 1\r\nlocal y = 2\r\n");
+    }
+
+    #[test]
+    fn test_auto_line_endings_uses_dominant_style() {
+        let output = format_code(
+            "local x = 1\nlocal y = 2\r\nlocal z = 3\n",
+            Config {
+                line_endings: LineEndings::Auto,
+                ..Config::default()
+            },
+            None,
+            OutputVerification::None,
+        )
+        .unwrap();
+        assert_eq!(output, "local x = 1\nlocal y = 2\nlocal z = 3\n");
+    }
+
+    #[test]
+    fn test_auto_line_endings_without_newlines() {
+        let output = format_code(
+            "local   x   =    1",
+            Config {
+                line_endings: LineEndings::Auto,
+                ..Config::default()
+            },
+            None,
+            OutputVerification::None,
+        )
+        .unwrap();
+        assert_eq!(output, "local x = 1\n");
+    }
 }
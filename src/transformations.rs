@@ -0,0 +1,519 @@
+use crate::{Error, Transformation};
+use full_moon::{
+    tokenizer::{Lexer, LexerResult, StringLiteralQuoteType, TokenType},
+    LuaVersion,
+};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::HashSet;
+
+/// The kind of a significant token, as far as the transformations are concerned
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    String,
+    Identifier,
+    SingleLineComment,
+    Other,
+}
+
+/// A significant (non-whitespace) token within some code, given by its byte boundaries and contents
+struct SignificantToken {
+    start: usize,
+    end: usize,
+    text: String,
+    kind: TokenKind,
+    /// The key used to align the tokens of the input and output.
+    /// Quoted strings are keyed by their value, so that strings whose quotes have changed are still aligned,
+    /// but strings with different contents are not.
+    key: String,
+}
+
+/// The value of a quoted string, ignoring any escaping of quotes (which changes alongside the quotes themselves)
+fn unescape_quotes(literal: &str) -> String {
+    let mut value = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            value.push(char);
+            continue;
+        }
+
+        match chars.next() {
+            Some(quote @ ('\'' | '"')) => value.push(quote),
+            Some(escaped) => {
+                value.push(char);
+                value.push(escaped);
+            }
+            None => value.push(char),
+        }
+    }
+    value
+}
+
+/// Tokenizes the code, keeping only the tokens which are not whitespace
+fn significant_tokens(
+    code: &str,
+    lua_version: LuaVersion,
+) -> Result<Vec<SignificantToken>, Vec<full_moon::Error>> {
+    let tokens = match Lexer::new(code, lua_version).collect() {
+        LexerResult::Ok(tokens) => tokens,
+        LexerResult::Recovered(_, errors) | LexerResult::Fatal(errors) => {
+            return Err(errors
+                .into_iter()
+                .map(full_moon::Error::TokenizerError)
+                .collect())
+        }
+    };
+
+    Ok(tokens
+        .into_iter()
+        .filter(|token| {
+            !matches!(
+                token.token_type(),
+                TokenType::Whitespace { .. } | TokenType::Eof
+            )
+        })
+        .map(|token| {
+            let text = token.to_string();
+            let (kind, key) = match token.token_type() {
+                TokenType::StringLiteral {
+                    literal,
+                    quote_type: StringLiteralQuoteType::Single | StringLiteralQuoteType::Double,
+                    ..
+                } => (TokenKind::String, format!("\"{}", unescape_quotes(literal))),
+                TokenType::StringLiteral { .. } => (TokenKind::String, text.clone()),
+                TokenType::Identifier { .. } => (TokenKind::Identifier, text.clone()),
+                TokenType::SingleLineComment { .. } => {
+                    (TokenKind::SingleLineComment, text.trim_end().to_string())
+                }
+                _ => (TokenKind::Other, text.clone()),
+            };
+
+            SignificantToken {
+                start: token.start_position().bytes(),
+                end: token.end_position().bytes(),
+                text,
+                kind,
+                key,
+            }
+        })
+        .collect())
+}
+
+/// Finds the parentheses around call arguments which were changed, given the indices of the changed tokens.
+/// Both parentheses of a pair must have changed, and the opening parenthesis must follow something which can be called,
+/// so that parentheses around expressions are left alone.
+fn changed_call_parentheses(
+    tokens: &[SignificantToken],
+    changed: &HashSet<usize>,
+) -> HashSet<usize> {
+    let mut call_parentheses = HashSet::new();
+    let mut open_parentheses = Vec::new();
+
+    for (index, token) in tokens.iter().enumerate() {
+        match token.text.as_str() {
+            "(" => open_parentheses.push(index),
+            ")" => {
+                let Some(open) = open_parentheses.pop() else {
+                    continue;
+                };
+                let follows_callable = open.checked_sub(1).is_some_and(|previous| {
+                    let previous = &tokens[previous];
+                    previous.kind == TokenKind::Identifier
+                        || previous.text == ")"
+                        || previous.text == "]"
+                });
+                if follows_callable && changed.contains(&open) && changed.contains(&index) {
+                    call_parentheses.insert(open);
+                    call_parentheses.insert(index);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    call_parentheses
+}
+
+/// Builds the result by walking through the input, taking changes from the output only if their transformation is enabled
+struct Merger<'a> {
+    input: &'a str,
+    output: &'a str,
+    input_tokens: &'a [SignificantToken],
+    output_tokens: &'a [SignificantToken],
+    transformations: &'a [Transformation],
+    line_ending: &'a str,
+    result: String,
+    /// The end of the last input token which was emitted or skipped
+    input_position: usize,
+    /// The output token equivalent to the last token emitted, if there is one
+    previous_output_index: Option<usize>,
+    /// Whether an opening parenthesis was just added or removed, so the whitespace before the call arguments
+    /// should follow the output
+    structure_changed: bool,
+}
+
+impl<'a> Merger<'a> {
+    fn is_enabled(&self, transformation: Transformation) -> bool {
+        self.transformations.contains(&transformation)
+    }
+
+    /// The whitespace in the output before the output token at the given index
+    fn output_gap(&self, index: usize) -> &'a str {
+        let start = match index {
+            0 => 0,
+            _ => self.output_tokens[index - 1].end,
+        };
+        let output = self.output;
+        &output[start..self.output_tokens[index].start]
+    }
+
+    /// Merges the whitespace between two input tokens with the equivalent whitespace in the output
+    fn merge_gap(
+        &self,
+        input_gap: &str,
+        output_gap: Option<&str>,
+        is_adjacent: bool,
+        at_end: bool,
+    ) -> String {
+        if !input_gap.contains('\n') {
+            return match output_gap {
+                Some(output_gap)
+                    if is_adjacent
+                        && !output_gap.contains('\n')
+                        && (self.structure_changed || self.is_enabled(Transformation::Spacing)) =>
+                {
+                    output_gap.to_string()
+                }
+                _ if at_end && self.is_enabled(Transformation::TrailingWhitespace) => String::new(),
+                _ => input_gap.to_string(),
+            };
+        }
+
+        let mut lines = input_gap.split('\n').collect::<Vec<_>>();
+        let last_line = lines.pop().unwrap_or_default();
+
+        let mut gap = String::with_capacity(input_gap.len());
+        for line in lines {
+            let (whitespace, line_ending) = match line.strip_suffix('\r') {
+                Some(whitespace) => (whitespace, "\r\n"),
+                None => (line, "\n"),
+            };
+            if !self.is_enabled(Transformation::TrailingWhitespace) {
+                gap.push_str(whitespace);
+            }
+            if self.is_enabled(Transformation::LineEndings) {
+                gap.push_str(self.line_ending);
+            } else {
+                gap.push_str(line_ending);
+            }
+        }
+
+        // The last line is either the indentation of the next token, or whitespace at the end of the file
+        match output_gap {
+            _ if at_end && self.is_enabled(Transformation::TrailingWhitespace) => (),
+            Some(output_gap)
+                if self.is_enabled(Transformation::Indentation) && output_gap.contains('\n') =>
+            {
+                gap.push_str(output_gap.rsplit('\n').next().unwrap_or_default())
+            }
+            _ => gap.push_str(last_line),
+        }
+
+        gap
+    }
+
+    /// Emits the input token at the given index, alongside the whitespace before it.
+    /// The equivalent output token is given if the token is unchanged, aside from its contents.
+    fn emit_input(&mut self, index: usize, output_index: Option<usize>) {
+        let (input, input_tokens) = (self.input, self.input_tokens);
+        let token = &input_tokens[index];
+        let input_gap = &input[self.input_position..token.start];
+        let output_gap = output_index.map(|output_index| self.output_gap(output_index));
+        let is_adjacent = match (self.previous_output_index, output_index) {
+            (Some(previous), Some(output_index)) => previous + 1 == output_index,
+            (None, Some(output_index)) => index == 0 && output_index == 0,
+            (_, None) => false,
+        };
+
+        let gap = self.merge_gap(input_gap, output_gap, is_adjacent, false);
+        self.result.push_str(&gap);
+
+        match (token.kind, output_index) {
+            (TokenKind::String, Some(output_index)) if self.is_enabled(Transformation::Quotes) => {
+                self.result.push_str(&self.output_tokens[output_index].text)
+            }
+            (TokenKind::SingleLineComment, _)
+                if self.is_enabled(Transformation::TrailingWhitespace) =>
+            {
+                // Comments run until the end of the line, so may include a carriage return
+                match token.text.strip_suffix('\r') {
+                    Some(comment) => {
+                        self.result.push_str(comment.trim_end());
+                        self.result.push('\r');
+                    }
+                    None => self.result.push_str(token.text.trim_end()),
+                }
+            }
+            _ => self.result.push_str(&token.text),
+        }
+
+        self.input_position = token.end;
+        self.previous_output_index = output_index;
+        self.structure_changed = false;
+    }
+
+    /// Skips over the input token at the given index, as it was removed by an enabled transformation
+    fn skip_input(&mut self, index: usize) {
+        let token = &self.input_tokens[index];
+        self.input_position = token.end;
+        self.structure_changed = token.text == "(";
+    }
+
+    /// Emits the output token at the given index, as it was added by an enabled transformation
+    fn emit_output(&mut self, index: usize) {
+        let output_gap = self.output_gap(index);
+        if !output_gap.contains('\n') {
+            self.result.push_str(output_gap);
+        }
+        let token = &self.output_tokens[index];
+        self.result.push_str(&token.text);
+
+        self.previous_output_index = Some(index);
+        self.structure_changed = token.text == "(";
+    }
+
+    fn finish(mut self) -> String {
+        let input_gap = &self.input[self.input_position..];
+        let gap = self.merge_gap(input_gap, None, false, true);
+        self.result.push_str(&gap);
+        self.result
+    }
+}
+
+/// Takes the formatted output of the input code, and returns the input with only the changes made by the given
+/// transformations applied. Every other change made by formatting is discarded, leaving the input untouched.
+/// Errors if either the input or output cannot be tokenized, rather than silently applying nothing.
+#[allow(clippy::result_large_err)]
+pub fn apply_transformations(
+    input: &str,
+    output: &str,
+    lua_version: LuaVersion,
+    transformations: &[Transformation],
+) -> Result<String, Error> {
+    let input_tokens = significant_tokens(input, lua_version).map_err(Error::ParseError)?;
+    let output_tokens =
+        significant_tokens(output, lua_version).map_err(Error::VerificationAstError)?;
+
+    let input_keys = input_tokens
+        .iter()
+        .map(|token| token.key.as_str())
+        .collect::<Vec<_>>();
+    let output_keys = output_tokens
+        .iter()
+        .map(|token| token.key.as_str())
+        .collect::<Vec<_>>();
+    let operations = capture_diff_slices(Algorithm::Myers, &input_keys, &output_keys);
+
+    let (removed_parentheses, added_parentheses) =
+        if transformations.contains(&Transformation::CallParentheses) {
+            let (mut removed, mut added) = (HashSet::new(), HashSet::new());
+            for operation in &operations {
+                if let DiffOp::Delete { .. } | DiffOp::Insert { .. } = operation {
+                    removed.extend(operation.old_range());
+                    added.extend(operation.new_range());
+                }
+            }
+            (
+                changed_call_parentheses(&input_tokens, &removed),
+                changed_call_parentheses(&output_tokens, &added),
+            )
+        } else {
+            (HashSet::new(), HashSet::new())
+        };
+
+    let mut merger = Merger {
+        input,
+        output,
+        input_tokens: &input_tokens,
+        output_tokens: &output_tokens,
+        transformations,
+        line_ending: if output.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        },
+        result: String::with_capacity(input.len()),
+        input_position: 0,
+        previous_output_index: None,
+        structure_changed: false,
+    };
+
+    for operation in operations {
+        match operation {
+            DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } => {
+                for offset in 0..len {
+                    merger.emit_input(old_index + offset, Some(new_index + offset));
+                }
+            }
+            // Any other change is not made by a transformation we can apply separately, so the input is kept
+            DiffOp::Replace { .. } => {
+                for index in operation.old_range() {
+                    merger.emit_input(index, None);
+                }
+            }
+            DiffOp::Delete { .. } => {
+                for index in operation.old_range() {
+                    if removed_parentheses.contains(&index) {
+                        merger.skip_input(index);
+                    } else {
+                        merger.emit_input(index, None);
+                    }
+                }
+            }
+            DiffOp::Insert { .. } => {
+                for index in operation.new_range() {
+                    if added_parentheses.contains(&index) {
+                        merger.emit_output(index);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(merger.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format_code, CallParenType, Config, OutputVerification};
+
+    fn apply(input: &str, config: Config, transformations: &[Transformation]) -> String {
+        let output = format_code(input, config, None, OutputVerification::None).unwrap();
+        apply_transformations(input, &output, LuaVersion::lua51(), transformations).unwrap()
+    }
+
+    const INPUT: &str = "local x   =  'hello'  \nif x then\n      print 'foo'   -- comment   \nend\nlocal y = foo({ 1 })\n";
+
+    #[test]
+    fn test_no_transformations() {
+        assert_eq!(apply(INPUT, Config::default(), &[]), INPUT);
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(
+            apply(INPUT, Config::default(), &[Transformation::Quotes]),
+            "local x   =  \"hello\"  \nif x then\n      print \"foo\"   -- comment   \nend\nlocal y = foo({ 1 })\n"
+        );
+    }
+
+    #[test]
+    fn test_quotes_only_replace_strings_with_the_same_value() {
+        // Sorting requires reorders the strings, which must not be swapped when only changing quotes.
+        // The strings which moved cannot be aligned with the output, so are left as they are
+        let config = Config {
+            sort_requires: crate::SortRequiresConfig { enabled: true },
+            ..Config::default()
+        };
+        assert_eq!(
+            apply(
+                "local b = require('b')\nlocal a = require('a')\nprint('c')\n",
+                config,
+                &[Transformation::Quotes]
+            ),
+            "local b = require('b')\nlocal a = require('a')\nprint(\"c\")\n"
+        );
+
+        // Escaped quotes are part of changing the quotes, so the strings are still aligned
+        assert_eq!(
+            apply(
+                "local x = 'it\\'s'\n",
+                Config::default(),
+                &[Transformation::Quotes]
+            ),
+            "local x = \"it's\"\n"
+        );
+    }
+
+    #[test]
+    fn test_call_parentheses() {
+        assert_eq!(
+            apply(INPUT, Config::default(), &[Transformation::CallParentheses]),
+            "local x   =  'hello'  \nif x then\n      print('foo')   -- comment   \nend\nlocal y = foo({ 1 })\n"
+        );
+
+        let config = Config {
+            call_parentheses: CallParenType::None,
+            ..Config::default()
+        };
+        assert_eq!(
+            apply(
+                "local y = foo({ 1 })\nlocal z = (1)\n",
+                config,
+                &[Transformation::CallParentheses]
+            ),
+            "local y = foo { 1 }\nlocal z = (1)\n"
+        );
+    }
+
+    #[test]
+    fn test_whitespace() {
+        assert_eq!(
+            apply(INPUT, Config::default(), &[Transformation::TrailingWhitespace]),
+            "local x   =  'hello'\nif x then\n      print 'foo'   -- comment\nend\nlocal y = foo({ 1 })\n"
+        );
+        assert_eq!(
+            apply(INPUT, Config::default(), &[Transformation::Indentation]),
+            "local x   =  'hello'  \nif x then\n\tprint 'foo'   -- comment   \nend\nlocal y = foo({ 1 })\n"
+        );
+        // The spacing before the comment is kept, as the output adds call parentheses in between
+        assert_eq!(
+            apply(INPUT, Config::default(), &[Transformation::Spacing]),
+            "local x = 'hello'  \nif x then\n      print 'foo'   -- comment   \nend\nlocal y = foo({ 1 })\n"
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let input = "local x = 1\r\nlocal  y = 2  \r\n";
+        assert_eq!(
+            apply(
+                input,
+                Config::default(),
+                &[Transformation::TrailingWhitespace]
+            ),
+            "local x = 1\r\nlocal  y = 2\r\n"
+        );
+        assert_eq!(
+            apply(input, Config::default(), &[Transformation::LineEndings]),
+            "local x = 1\nlocal  y = 2  \n"
+        );
+    }
+
+    #[test]
+    fn test_untokenizable_code_is_an_error() {
+        assert!(matches!(
+            apply_transformations(
+                "local x = 'hello",
+                "local x = 'hello'",
+                LuaVersion::lua51(),
+                &[Transformation::Quotes]
+            ),
+            Err(Error::ParseError(_))
+        ));
+        assert!(matches!(
+            apply_transformations(
+                "local x = 'hello'",
+                "local x = \"hello",
+                LuaVersion::lua51(),
+                &[Transformation::Quotes]
+            ),
+            Err(Error::VerificationAstError(_))
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use stylua_lib::{format_code, Config, OutputVerification, Range};
+use stylua_lib::{format_code, format_code_with_ranges, Config, OutputVerification, Range};
 
 fn format_range(input: &str, range: Range) -> String {
     format_code(
@@ -10,6 +10,10 @@ fn format_range(input: &str, range: Range) -> String {
     .unwrap()
 }
 
+fn format_ranges(input: &str, ranges: &[Range]) -> String {
+    format_code_with_ranges(input, Config::default(), ranges, OutputVerification::None).unwrap()
+}
+
 fn format(input: &str) -> String {
     let start_point = input.find("||");
     let end_point = input.rfind("||");
@@ -411,3 +415,58 @@ local   e    = 5
     local e = 5
     "###);
 }
+
+#[test]
+fn test_multiple_ranges() {
+    insta::assert_snapshot!(
+        format_ranges(
+            r###"local  a  =  1
+local  b  =  2
+local  c  =  3
+"###,
+            &[
+                Range::from_values(Some(0), Some(14)),
+                Range::from_values(Some(30), Some(44)),
+            ]
+        ),
+    @r###"
+    local a = 1
+    local  b  =  2
+    local c = 3
+    "###);
+}
+
+#[test]
+fn test_multiple_ranges_out_of_order() {
+    insta::assert_snapshot!(
+        format_ranges(
+            r###"local  a  =  1
+local  b  =  2
+local  c  =  3
+"###,
+            &[
+                Range::from_values(Some(15), Some(29)),
+                Range::from_values(Some(0), Some(14)),
+            ]
+        ),
+    @r###"
+    local a = 1
+    local b = 2
+    local  c  =  3
+    "###);
+}
+
+#[test]
+fn test_no_ranges_formats_everything() {
+    insta::assert_snapshot!(
+        format_ranges(
+            r###"local  a  =  1
+local  b  =  2
+"###,
+            &[]
+        ),
+    @r###"
+    local a = 1
+    local b = 2
+    "###);
+}
@@ -15,6 +15,10 @@ describe("StyLua WASM Smoke Tests (ESM)", () => {
       assert.strictEqual(typeof stylua.formatCode, "function");
     });
 
+    it("should export formatCodeWithRanges function", () => {
+      assert.strictEqual(typeof stylua.formatCodeWithRanges, "function");
+    });
+
     it("should export Config class", () => {
       assert.strictEqual(typeof stylua.Config, "function");
     });
@@ -144,6 +148,18 @@ describe("StyLua WASM Smoke Tests (ESM)", () => {
       assert.strictEqual(range.start, undefined);
       assert.strictEqual(range.end, 50);
     });
+
+    it("should create a range from lines", () => {
+      const range = stylua.Range.from_lines(
+        "local x = 1\nlocal y = 2\n",
+        2,
+        undefined,
+        2,
+        undefined
+      );
+      assert.strictEqual(range.start, 12);
+      assert.strictEqual(range.end, 23);
+    });
   });
 
   describe("SortRequiresConfig", () => {
@@ -235,6 +251,17 @@ describe("StyLua WASM Smoke Tests (ESM)", () => {
       assert.ok(result !== undefined);
     });
 
+    it("should format multiple ranges of code", () => {
+      const config = stylua.Config.new();
+      const result = stylua.formatCodeWithRanges(
+        "local  a  =  1\nlocal  b  =  2\nlocal  c  =  3\n",
+        config,
+        [stylua.Range.from_values(0, 14), stylua.Range.from_values(30, 44)],
+        stylua.OutputVerification.None
+      );
+      assert.strictEqual(result, "local a = 1\nlocal  b  =  2\nlocal c = 3\n");
+    });
+
     it("should format Lua tables", () => {
       const config = stylua.Config.new();
       const code = "local t = {a=1,b=2,c=3}";